@@ -0,0 +1,77 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct ThreadPool {
+    sender: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    pub fn new(size: usize) -> ThreadPool {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..size)
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                thread::spawn(move || loop {
+                    // The lock is held while waiting, every other idle
+                    // worker queues up behind it.
+                    let job = receiver
+                        .lock()
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Run `f` on a worker. Panics are caught like in the real pool.
+    pub fn execute<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        let job = Box::new(move || {
+            if let Ok(value) = panic::catch_unwind(AssertUnwindSafe(f)) {
+                let _ = result_tx.send(value);
+            }
+        });
+        self.sender.as_ref().unwrap().send(job).unwrap();
+        JobHandle {
+            receiver: result_rx,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+pub struct JobHandle<T> {
+    receiver: Receiver<T>,
+}
+
+impl<T> JobHandle<T> {
+    /// Wait for the result, `Err` if the job panicked.
+    pub fn join(self) -> Result<T, mpsc::RecvError> {
+        self.receiver.recv()
+    }
+}
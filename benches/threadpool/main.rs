@@ -0,0 +1,153 @@
+//! Throughput of the work-stealing `ThreadPool` against the mutex guarded
+//! channel it replaced, with many small file-chunk jobs queued at once.
+//!
+//! Run with `cargo bench --bench threadpool -- [jobs] [chunk_size] [threads]`.
+//!
+//! The bench does not show that work stealing is faster. On a single core
+//! the ratio between the pools varied from 0.8x to 1.1x between runs, for
+//! 1 B to 64 KiB chunks and 1 to 8 threads. The stealing pool was adopted
+//! for priorities, resizing and scoped jobs; a gain from less contention on
+//! the shared queue could only show with many cores and tiny jobs, and has
+//! not been measured.
+
+#[allow(dead_code, unused_imports)]
+#[path = "../../src/threadpool.rs"]
+mod threadpool;
+
+/// The previous scheduler reduced to its queue, kept as the baseline: one
+/// `mpsc` channel whose receiver is shared by every worker behind a
+/// `Mutex`.
+mod channel_pool;
+
+use std::env;
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SRC_SIZE: u64 = 16 * 1024 * 1024;
+const ROUNDS: usize = 5;
+
+/// Read `len` bytes at `offset` and checksum them.
+fn chunk_job(src: &File, offset: u64, len: usize) -> u32 {
+    let mut buffer = vec![0u8; len];
+    src.read_exact_at(&mut buffer, offset).unwrap();
+    crc32c::crc32c(&buffer)
+}
+
+fn chunk_offset(i: usize, chunk_size: usize) -> u64 {
+    (i as u64 * chunk_size as u64) % (SRC_SIZE - chunk_size as u64 + 1)
+}
+
+/// Submit `jobs` chunk jobs, wait for all of them and return the elapsed
+/// time with the combined checksum, so both pools can be compared.
+fn run_stealing(
+    pool: &threadpool::ThreadPool,
+    src: &Arc<File>,
+    jobs: usize,
+    chunk_size: usize,
+) -> (Duration, u32) {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..jobs)
+        .map(|i| {
+            let src = Arc::clone(src);
+            pool.execute(move || chunk_job(&src, chunk_offset(i, chunk_size), chunk_size))
+        })
+        .collect();
+    let sum = handles
+        .into_iter()
+        .fold(0u32, |sum, handle| sum.wrapping_add(handle.join().unwrap()));
+    (start.elapsed(), sum)
+}
+
+fn run_channel(
+    pool: &channel_pool::ThreadPool,
+    src: &Arc<File>,
+    jobs: usize,
+    chunk_size: usize,
+) -> (Duration, u32) {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..jobs)
+        .map(|i| {
+            let src = Arc::clone(src);
+            pool.execute(move || chunk_job(&src, chunk_offset(i, chunk_size), chunk_size))
+        })
+        .collect();
+    let sum = handles
+        .into_iter()
+        .fold(0u32, |sum, handle| sum.wrapping_add(handle.join().unwrap()));
+    (start.elapsed(), sum)
+}
+
+/// Best of `ROUNDS` runs on one pool, in jobs per second. The pool is
+/// created and dropped outside of the timed rounds.
+fn measure<P>(
+    run: fn(&P, &Arc<File>, usize, usize) -> (Duration, u32),
+    pool: &P,
+    src: &Arc<File>,
+    jobs: usize,
+    chunk_size: usize,
+) -> (f64, u32) {
+    let mut best = Duration::MAX;
+    let mut checksum = 0;
+    for _ in 0..ROUNDS {
+        let (elapsed, sum) = run(pool, src, jobs, chunk_size);
+        best = best.min(elapsed);
+        checksum = sum;
+    }
+    (jobs as f64 / best.as_secs_f64(), checksum)
+}
+
+fn arg(args: &[String], i: usize, default: usize) -> usize {
+    args.get(i).map_or(default, |arg| {
+        arg.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid argument {:?}: {}", arg, e);
+            std::process::exit(2);
+        })
+    })
+}
+
+fn main() {
+    // `cargo bench` appends `--bench`.
+    let args: Vec<String> = env::args()
+        .skip(1)
+        .filter(|arg| !arg.starts_with("--"))
+        .collect();
+    let jobs = arg(&args, 0, 100_000);
+    let chunk_size = arg(&args, 1, 4096).clamp(1, SRC_SIZE as usize);
+    let threads = arg(
+        &args,
+        2,
+        thread::available_parallelism().map_or(4, |n| n.get()),
+    );
+
+    let src_path: PathBuf = env::temp_dir().join(format!("xfs-bench-{}", std::process::id()));
+    fs::write(
+        &src_path,
+        (0..SRC_SIZE).map(|i| i as u8).collect::<Vec<u8>>(),
+    )
+    .unwrap();
+    let src = Arc::new(File::open(&src_path).unwrap());
+
+    println!(
+        "{} jobs of {} B chunks on {} threads, best of {} rounds",
+        jobs, chunk_size, threads, ROUNDS
+    );
+    let channel_pool = channel_pool::ThreadPool::new(threads);
+    let (channel, channel_sum) = measure(run_channel, &channel_pool, &src, jobs, chunk_size);
+    drop(channel_pool);
+    let stealing_pool = threadpool::ThreadPool::new(threads);
+    let (stealing, stealing_sum) = measure(run_stealing, &stealing_pool, &src, jobs, chunk_size);
+    drop(stealing_pool);
+    println!("  mutex channel : {:>12.0} jobs/s", channel);
+    println!("  work stealing : {:>12.0} jobs/s", stealing);
+    println!("  ratio         : {:>12.2}x", stealing / channel);
+    assert_eq!(
+        channel_sum, stealing_sum,
+        "pools computed different checksums"
+    );
+
+    fs::remove_file(&src_path).unwrap();
+}
@@ -1,7 +1,21 @@
+#[path = "../config.rs"]
+mod config;
+#[path = "../connect/mod.rs"]
+mod connect;
+#[path = "../device/mod.rs"]
+mod device;
+// Only `hash_range` is used here, the copies serve the server's benchmark.
+#[allow(dead_code)]
 #[path = "../file/file_io.rs"]
 mod file_io;
+#[path = "../hash.rs"]
+mod hash;
 #[path = "../packet.rs"]
 mod packet;
+// Only the message format is used here, the registry itself runs in the server.
+#[allow(dead_code)]
+#[path = "../registry/mod.rs"]
+mod registry;
 #[path = "../threadpool.rs"]
 mod threadpool;
 #[path = "../utils.rs"]
@@ -9,63 +23,85 @@ mod utils;
 
 use ::serde::de::{DeserializeOwned, DeserializeSeed};
 use ::serde::{Deserialize, Serialize};
-use packet::MsgPacket;
+use config::Config;
+use connect::connect::{list_devices, spawn_heartbeat};
+use device::identity::NodeIdentity;
+use file_io::hash_range;
+use hash::{Digest, HashAlgorithm, Hasher};
+use packet::{
+    data_chunk, ErrorReply, FileStat, FileType, Frame, FrameDecoder, FrameEncoder, GetReply,
+    GetRequest, HandshakeProof, HandshakeReply, HandshakeRequest, ListReply, MkdirRequest,
+    MsgOpcode, MsgPacket, PathRequest, PutRequest, RenameRequest, RmdirRequest, StatusReply,
+    TransferComplete, TransferProgress, TruncateRequest, CHUNK_CHECKSUM_VERSION, DATA_CHUNK_LEN,
+    PROTOCOL_VERSION,
+};
 use std::any::{type_name, type_name_of_val};
+use std::collections::HashMap;
 use std::fmt::Display;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::prelude::*;
-use std::io::{self};
+use std::io::{self, SeekFrom};
 use std::net::TcpStream;
-use std::sync::mpsc::Sender;
-use std::sync::{mpsc, Arc};
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{string, thread};
 use threadpool::ThreadPool;
 use utils::register_sig_handler;
 use uuid::serde;
 
 fn main() -> () {
-    const ADDRESS: &str = "127.0.0.1:8080";
+    let config = Config::load_or_exit();
+    let address = config.server_address();
+
+    let identity = NodeIdentity::load_or_create(&config.state_dir, config.identity_key)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to load node identity from {}: {}",
+                config.state_dir.display(),
+                e
+            );
+            std::process::exit(1);
+        });
+    println!("Node id: {}", identity.id);
 
     let pool = ThreadPool::new(2);
-    let stream = TcpStream::connect(ADDRESS).unwrap_or_else(|e| {
+    let mut stream = TcpStream::connect(&address).unwrap_or_else(|e| {
         eprintln!("Failed to connect to server: {}", e);
         std::process::exit(1);
     });
-    let local_addr = stream
-        .local_addr()
-        .expect("Failed to get local address")
-        .to_string();
+
+    println!("Connected to server: {}", address);
+    print!("Enter client id (empty for the node id): ");
+    io::stdout().flush().unwrap_or_else(|e| {
+        eprintln!("Failed to flush stdout: {}", e);
+    });
+    let mut requested_id = String::new();
+    io::stdin()
+        .read_line(&mut requested_id)
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid id: {}", e);
+            0
+        });
+
+    let mut decoder = FrameDecoder::new();
+    let client_id = handshake(
+        &mut stream,
+        &mut decoder,
+        requested_id.trim(),
+        Some(&identity),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Handshake failed: {}", e);
+        std::process::exit(1);
+    });
+    println!("Logined as {}!", client_id);
+    spawn_heartbeat(&config, &identity);
+
     let stream = Arc::new(stream);
     let stream_clone = Arc::clone(&stream);
-
-    println!("Connected to server: {}", ADDRESS);
-    println!("Chat Type [1 for msg_packet  / 2 for string]: ");
-    let msg_type: bool;
-    let mut client_id = String::new();
-    {
-        let mut tmp_buf = String::new();
-        io::stdin().read_line(&mut tmp_buf).unwrap();
-        let tmp_input = &tmp_buf[..tmp_buf.len() - 1];
-        msg_type = match tmp_input {
-            "1" => {
-                print!("Enter client id: ");
-                io::stdout().flush().unwrap_or_else(|e| {
-                    eprintln!("Failed to flush stdout: {}", e);
-                });
-                let _ = io::stdin().read_line(&mut client_id).unwrap_or_else(|e| {
-                    eprintln!("Invalid id: {}", e);
-                    client_id = String::from(local_addr);
-                    0
-                });
-                client_id.pop();
-                println!("Logined as {}!", client_id);
-                true
-            }
-            _ => false,
-        }
-    }
-    println!("msg type: {}", msg_type);
     register_sig_handler(move || {
         println!("Exiting....");
         stream_clone
@@ -76,94 +112,1221 @@ fn main() -> () {
         std::process::exit(0);
     });
 
-    if msg_type {
-        let (tx, rx) = mpsc::channel();
-        pool.execute(move || {
-            handle_connection2::<MsgPacket>(&stream, rx, MsgPacket::dummy());
-        });
-        let mut packet_base = MsgPacket::dummy();
-        packet_base.id = client_id;
-        send_loop(tx, pool, packet_base, MsgPacket::parse);
-    } else {
-        let (tx, rx) = mpsc::channel();
-        pool.execute(move || {
-            handle_connection2::<String>(&stream, rx, "".to_string());
-        });
-        send_loop(tx, pool, "".to_string(), |_, x| x)
+    let stream_reader = Arc::clone(&stream);
+    let terminating = Arc::new(AtomicBool::new(false));
+    let terminating_clone = Arc::clone(&terminating);
+    let (get_tx, get_rx) = mpsc::channel();
+    pool.execute(move || {
+        handle_connection2(&stream_reader, decoder, get_rx, &terminating_clone);
+    });
+    send_loop(&stream, pool, &config, &client_id, get_tx, &terminating);
+}
+
+/// Negotiate protocol version and client id with the server.
+///
+/// # Arguments
+///
+/// * `stream` - Freshly connected TcpStream
+/// * `decoder` - Decoder that keeps any bytes received after the reply
+/// * `client_id` - Requested client id, empty to use the node id
+/// * `node` - Identity of this machine, `None` for the extra sessions of
+///   parallel transfers
+///
+/// # Returns
+/// The client id confirmed by the server.
+///
+fn handshake(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    client_id: &str,
+    node: Option<&NodeIdentity>,
+) -> io::Result<String> {
+    // Every `Data` frame this client sends or reads carries a chunk digest.
+    let request = HandshakeRequest {
+        min_version: CHUNK_CHECKSUM_VERSION,
+        max_version: PROTOCOL_VERSION,
+        client_id: client_id.to_string(),
+        node_id: node.map(|node| node.id.clone()).unwrap_or_default(),
+        public_key: node.and_then(|node| node.public_key.clone()),
+    };
+    FrameEncoder::new().write_packet(
+        stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Handshake, &request),
+    )?;
+
+    let reply = recv_handshake_reply(stream, decoder)?;
+    let Some(challenge) = reply.challenge else {
+        return Ok(reply.client_id);
+    };
+    // The server asks to prove that the node holds its secret key.
+    let node = node.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "unexpected handshake challenge")
+    })?;
+    let proof = HandshakeProof {
+        signature: node.sign_challenge(&challenge)?,
+    };
+    FrameEncoder::new().write_packet(
+        stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Handshake, &proof),
+    )?;
+    let reply = recv_handshake_reply(stream, decoder)?;
+    match reply.challenge {
+        None => Ok(reply.client_id),
+        Some(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "second handshake challenge",
+        )),
     }
 }
 
-fn send_loop<T, F>(tx: Sender<T>, mut pool: ThreadPool, parse_base: T, parse: F) -> ()
-where
-    T: std::fmt::Debug + DeserializeOwned + Serialize + Clone,
-    F: Fn(T, String) -> T,
-{
+fn recv_handshake_reply(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+) -> io::Result<HandshakeReply> {
+    let packet = recv_packet(stream, decoder)?;
+    match packet.opcode {
+        MsgOpcode::Handshake => Ok(packet.body::<HandshakeReply>()?),
+        MsgOpcode::Error => Err(error_of(&packet)),
+        opcode => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {:?} reply to handshake", opcode),
+        )),
+    }
+}
+
+/// Block until the next frame arrives.
+fn recv_frame(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> io::Result<Frame> {
+    loop {
+        if let Some(frame) = decoder.next_frame()? {
+            return Ok(frame);
+        }
+        if decoder.read_from(stream)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Block until the next packet arrives.
+fn recv_packet(stream: &mut TcpStream, decoder: &mut FrameDecoder) -> io::Result<MsgPacket> {
+    let frame = recv_frame(stream, decoder)?;
+    Ok(serde_json::from_slice::<MsgPacket>(&frame.payload)?)
+}
+
+/// Turn an `Error` packet into an `io::Error`.
+fn error_of(packet: &MsgPacket) -> io::Error {
+    match packet.body::<ErrorReply>() {
+        Ok(reply) => io::Error::other(reply.to_string()),
+        Err(_) => io::Error::other(packet.data.clone()),
+    }
+}
+
+fn send_loop(
+    mut stream: &TcpStream,
+    pool: ThreadPool,
+    config: &Config,
+    client_id: &str,
+    get_tx: Sender<PendingGet>,
+    terminating: &AtomicBool,
+) -> () {
+    let address = &config.server_address();
+    let hash = config.hash;
     println!("\"q\" : for exit");
+    println!("\"put <local> [remote]\" : upload a file");
+    println!("\"get <remote> [local]\" : download a file, resuming a partial one");
+    println!("\"pput <local> [remote] [connections]\" : upload over parallel connections");
+    println!("\"pget <remote> [local] [connections]\" : download over parallel connections");
+    println!("\"ls [path]\" : list a directory on the server");
+    println!("\"stat <path>\" : show metadata of a file on the server");
+    println!("\"rm <path>\", \"mv <from> <to>\", \"mkdir [-p] <path>\", \"rmdir [-r] <path>\",");
+    println!("\"truncate <path> <size>\" : modify files on the server");
+    println!("\"status\" : show the server's thread pool load");
+    println!("\"devices\" : list the devices known to the registry");
     println!("Enter message to send: ");
+    let mut encoder = FrameEncoder::new();
     loop {
-        {
-            // let mut stdin = io::stdin();
-            let mut stdin = io::stdin().lock();
-            let mut input: String = String::new();
-            let _ = stdin.read_line(&mut input);
-            let msg = &input[..input.len() - 1];
-            match msg {
-                "q" => {
-                    println!("Exiting....");
-                    pool.join();
-                    std::process::exit(1);
+        let mut input: String = String::new();
+        let read_len = io::stdin().lock().read_line(&mut input).unwrap_or(0);
+        let msg = input.trim_end_matches('\n');
+        let mut args = msg.split_whitespace();
+        let result = match args.next() {
+            _ if read_len == 0 => exit_session(stream, &mut encoder, pool, client_id, terminating),
+            Some("q") => exit_session(stream, &mut encoder, pool, client_id, terminating),
+            Some("put") => match (args.next(), args.next()) {
+                (Some(local), remote) => {
+                    let remote = remote
+                        .map(str::to_string)
+                        .unwrap_or_else(|| file_name_of(local));
+                    put_file(stream, &mut encoder, client_id, local, &remote, hash)
                 }
                 _ => {
-                    tx.send(parse(parse_base.clone(), msg.to_string()))
-                        .unwrap_or_else(|e| {
-                            eprintln!("Failed to send: {}", e);
-                        });
+                    eprintln!("usage: put <local> [remote]");
+                    Ok(())
+                }
+            },
+            Some("get") => match (args.next(), args.next()) {
+                (Some(remote), local) => {
+                    let local = local
+                        .map(str::to_string)
+                        .unwrap_or_else(|| file_name_of(remote));
+                    get_file(
+                        stream,
+                        &mut encoder,
+                        config,
+                        client_id,
+                        remote,
+                        &local,
+                        &get_tx,
+                    )
                 }
+                _ => {
+                    eprintln!("usage: get <remote> [local]");
+                    Ok(())
+                }
+            },
+            Some("ls") => {
+                let request = PathRequest {
+                    path: args.next().unwrap_or("").to_string(),
+                };
+                encoder.write_packet(
+                    &mut stream,
+                    &MsgPacket::with_body(client_id, MsgOpcode::List, &request),
+                )
             }
-        }
+            Some("stat") => match args.next() {
+                Some(path) => {
+                    let request = PathRequest {
+                        path: path.to_string(),
+                    };
+                    encoder.write_packet(
+                        &mut stream,
+                        &MsgPacket::with_body(client_id, MsgOpcode::Stat, &request),
+                    )
+                }
+                None => {
+                    eprintln!("usage: stat <path>");
+                    Ok(())
+                }
+            },
+            Some("devices") => show_devices(&config.registry),
+            Some("status") => encoder.write_packet(
+                &mut stream,
+                &MsgPacket::with_opcode(client_id, MsgOpcode::Status, ""),
+            ),
+            Some(cmd @ ("rm" | "mv" | "mkdir" | "rmdir" | "truncate")) => {
+                let args: Vec<&str> = args.collect();
+                let flag = |name: &str| args.contains(&name);
+                let operands: Vec<&str> = args
+                    .iter()
+                    .copied()
+                    .filter(|arg| !arg.starts_with('-'))
+                    .collect();
+                let packet = match (cmd, operands.as_slice()) {
+                    ("rm", [path]) => Some(MsgPacket::with_body(
+                        client_id,
+                        MsgOpcode::Delete,
+                        &PathRequest {
+                            path: path.to_string(),
+                        },
+                    )),
+                    ("mv", [from, to]) => Some(MsgPacket::with_body(
+                        client_id,
+                        MsgOpcode::Rename,
+                        &RenameRequest {
+                            from: from.to_string(),
+                            to: to.to_string(),
+                        },
+                    )),
+                    ("mkdir", [path]) => Some(MsgPacket::with_body(
+                        client_id,
+                        MsgOpcode::Mkdir,
+                        &MkdirRequest {
+                            path: path.to_string(),
+                            parents: flag("-p"),
+                        },
+                    )),
+                    ("rmdir", [path]) => Some(MsgPacket::with_body(
+                        client_id,
+                        MsgOpcode::Rmdir,
+                        &RmdirRequest {
+                            path: path.to_string(),
+                            recursive: flag("-r"),
+                        },
+                    )),
+                    ("truncate", [path, size]) => size.parse().ok().map(|size| {
+                        MsgPacket::with_body(
+                            client_id,
+                            MsgOpcode::Truncate,
+                            &TruncateRequest {
+                                path: path.to_string(),
+                                size,
+                            },
+                        )
+                    }),
+                    _ => None,
+                };
+                match packet {
+                    Some(packet) => encoder.write_packet(&mut stream, &packet),
+                    None => {
+                        eprintln!("invalid arguments for {}", cmd);
+                        Ok(())
+                    }
+                }
+            }
+            Some(cmd @ ("pput" | "pget")) => match (args.next(), args.next(), args.next()) {
+                (Some(src), dest, connections) => {
+                    let dest = dest
+                        .map(str::to_string)
+                        .unwrap_or_else(|| file_name_of(src));
+                    let connections = connections
+                        .and_then(|n| n.parse().ok())
+                        .unwrap_or(PARALLEL_CONNECTIONS)
+                        .max(1);
+                    if cmd == "pput" {
+                        parallel_put(address, client_id, src, &dest, connections, hash)
+                    } else {
+                        parallel_get(address, client_id, src, &dest, connections, hash)
+                    }
+                }
+                _ => {
+                    eprintln!("usage: {} <src> [dest] [connections]", cmd);
+                    Ok(())
+                }
+            },
+            _ => encoder.write_packet(&mut stream, &MsgPacket::new(client_id, msg)),
+        };
+        result.unwrap_or_else(|e| {
+            eprintln!("Failed to send: {}", e);
+        });
         thread::sleep(Duration::from_millis(20));
     }
 }
 
-fn handle_connection2<T>(mut stream: &TcpStream, send_rx: mpsc::Receiver<T>, dummy: T) -> ()
-where
-    T: std::fmt::Debug + DeserializeOwned + Serialize + Clone,
-{
-    stream
-        .set_read_timeout(Some(Duration::from_millis(100)))
+/// Send `Terminate`, wait for the server to acknowledge it and exit.
+fn exit_session(
+    mut stream: &TcpStream,
+    encoder: &mut FrameEncoder,
+    mut pool: ThreadPool,
+    client_id: &str,
+    terminating: &AtomicBool,
+) -> ! {
+    println!("Exiting....");
+    terminating.store(true, Ordering::SeqCst);
+    encoder
+        .write_packet(
+            &mut stream,
+            &MsgPacket::with_opcode(client_id, MsgOpcode::Terminate, ""),
+        )
         .unwrap_or_else(|e| {
-            eprintln!("Failed to set read timeout: {}", e);
+            eprintln!("Failed to send: {}", e);
             std::process::exit(1);
         });
+    pool.join();
+    std::process::exit(0);
+}
+
+fn file_name_of(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string())
+}
+
+/// Upload a local file to the server. Progress and the final
+/// acknowledgement are printed by `handle_connection2`.
+///
+/// # Arguments
+///
+/// * `stream` - Connected TcpStream
+/// * `encoder` - Encoder used for every outgoing frame
+/// * `client_id` - Id confirmed by the handshake
+/// * `local` - Path of the file to upload
+/// * `remote` - Destination path relative to the server storage root
+/// * `hash` - Algorithm of the chunk and transfer checksums
+///
+fn put_file(
+    mut stream: &TcpStream,
+    encoder: &mut FrameEncoder,
+    client_id: &str,
+    local: &str,
+    remote: &str,
+    hash: HashAlgorithm,
+) -> io::Result<()> {
+    let file = File::open(local)?;
+    let size = file.metadata()?.len();
+    let request = PutRequest {
+        path: remote.to_string(),
+        size,
+        offset: 0,
+        file_size: None,
+        hash,
+    };
+    encoder.write_packet(
+        &mut stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Put, &request),
+    )?;
+
+    let mut reader = file.take(size);
+    let mut buf = vec![0u8; DATA_CHUNK_LEN];
+    let mut sent = 0u64;
     loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        encoder.write_data(&mut stream, Some(hash), &buf[..len])?;
+        sent += len as u64;
+    }
+    if sent != size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!(
+                "{} shrank during upload ({}B of {}B sent)",
+                local, sent, size
+            ),
+        ));
+    }
+    println!("Sent {} ({}B), waiting for acknowledgement...", local, sent);
+    Ok(())
+}
+
+/// Local target of a `get`, handed to `handle_connection2` before the request is sent.
+struct PendingGet {
+    remote: String,
+    local: PathBuf,
+    /// Size of the remote file when the request was sent.
+    file_size: u64,
+}
+
+/// Remote file a `<local>.part` was downloaded from, kept in
+/// `<local>.part.origin` so a resume can tell whether it changed since.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct PartOrigin {
+    size: u64,
+    mtime: u64,
+}
+
+impl PartOrigin {
+    fn read(local: &Path) -> Option<PartOrigin> {
+        let data = fs::read(origin_path_of(local)).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    fn write(&self, local: &Path) -> io::Result<()> {
+        fs::write(origin_path_of(local), serde_json::to_vec(self)?)
+    }
+
+    fn remove(local: &Path) {
+        let _ = fs::remove_file(origin_path_of(local));
+    }
+}
+
+/// A download being written to `<local>.part`, renamed once verified.
+struct LocalDownload {
+    remote: String,
+    local: PathBuf,
+    part: PathBuf,
+    file: File,
+    /// Size of the whole file, verified as a whole if the part was resumed.
+    file_size: u64,
+    resumed: bool,
+    expected: u64,
+    received: u64,
+    hash: HashAlgorithm,
+    hasher: Hasher,
+    next_report: u64,
+}
+
+impl LocalDownload {
+    const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+    fn open(pending: PendingGet, reply: &GetReply) -> io::Result<LocalDownload> {
+        if reply.file_size != pending.file_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} changed from {}B to {}B during the request",
+                    pending.remote, pending.file_size, reply.file_size
+                ),
+            ));
+        }
+        let part = part_path_of(&pending.local);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&part)?;
+        file.set_len(reply.offset)?;
+        file.seek(SeekFrom::Start(reply.offset))?;
+        Ok(LocalDownload {
+            remote: pending.remote,
+            local: pending.local,
+            part,
+            file,
+            file_size: reply.file_size,
+            resumed: reply.offset > 0,
+            expected: reply.length,
+            received: 0,
+            hash: reply.hash,
+            hasher: reply.hash.hasher(),
+            next_report: Self::PROGRESS_INTERVAL,
+        })
+    }
+
+    /// Verify the digest of one `Data` payload and append its chunk.
+    fn write_chunk(&mut self, payload: &[u8]) -> io::Result<()> {
+        let chunk = data_chunk(Some(self.hash), payload)?;
+        let received = self.received.checked_add(chunk.len() as u64);
+        if received.is_none_or(|received| received > self.expected) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: more than the announced {}B",
+                    self.remote, self.expected
+                ),
+            ));
+        }
+        self.file.write_all(chunk)?;
+        self.hasher.update(chunk);
+        self.received += chunk.len() as u64;
+        if self.received >= self.next_report {
+            self.next_report = self.received + Self::PROGRESS_INTERVAL;
+            println!(
+                ".. {}: {}/{}B ({}%)",
+                self.remote,
+                self.received,
+                self.expected,
+                self.received * 100 / self.expected.max(1)
+            );
+        }
+        Ok(())
+    }
+
+    /// Verify the server's acknowledgement and move the file into place.
+    fn finish(self, complete: &TransferComplete) -> io::Result<()> {
+        let checksum = self.hasher.finish().to_string();
+        if self.received != self.expected
+            || complete.hash != self.hash
+            || checksum != complete.checksum
         {
-            let mut buf = vec![0; 1024];
-            let recv_len = stream.read(&mut buf).unwrap_or(0);
-            if recv_len > 0 {
-                let recv_packet =
-                    serde_json::from_slice::<T>(&buf[..recv_len]).unwrap_or_else(|e| {
-                        // println!("Bytes: {:?}", &buf[..recv_len]);
-                        eprintln!("Error decoding message len: {} Err: {}", recv_len, e);
-                        dummy.clone()
-                    });
-                println!(">> {:?}", recv_packet);
+            drop(self.file);
+            fs::remove_file(&self.part)?;
+            PartOrigin::remove(&self.local);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{}: received {}B {} {}, server sent {}B {} {}",
+                    self.remote,
+                    self.received,
+                    self.hash,
+                    checksum,
+                    complete.bytes,
+                    complete.hash,
+                    complete.checksum
+                ),
+            ));
+        }
+        self.file.sync_all()?;
+        if self.resumed {
+            // The part may predate a change the size and mtime missed.
+            let whole = hash_range(&self.file, 0, self.file_size, self.hash)?.to_string();
+            if complete.file_checksum.as_ref() != Some(&whole) {
+                drop(self.file);
+                fs::remove_file(&self.part)?;
+                PartOrigin::remove(&self.local);
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{}: resumed file is {} {}, server has {}, download it again",
+                        self.remote,
+                        self.hash,
+                        whole,
+                        complete.file_checksum.as_deref().unwrap_or("no checksum")
+                    ),
+                ));
             }
         }
-        match send_rx.try_recv() {
-            Ok(msg) => {
-                let buf = serde_json::to_vec(&msg).unwrap();
-                let send_len = stream.write(&buf).unwrap_or_else(|e| {
-                    eprintln!("Failed to send: {:#?} Err: {}", msg, e);
-                    0
-                });
-                if send_len > 0 {
-                    stream.flush().unwrap_or_else(|e| {
-                        eprintln!("Failed to flush buffer for : {:#?} Err: {}", msg, e);
-                    });
-                }
-                ()
+        fs::rename(&self.part, &self.local)?;
+        PartOrigin::remove(&self.local);
+        println!(
+            "Downloaded {} to {} ({}B, {} {})",
+            self.remote,
+            self.local.display(),
+            self.received,
+            self.hash,
+            checksum
+        );
+        Ok(())
+    }
+}
+
+fn part_path_of(local: &Path) -> PathBuf {
+    let mut part = local.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+fn origin_path_of(local: &Path) -> PathBuf {
+    let mut origin = local.as_os_str().to_owned();
+    origin.push(".part.origin");
+    PathBuf::from(origin)
+}
+
+/// Request a file from the server. If `<local>.part` exists and the remote
+/// file kept the size and mtime it had when the part was started, only the
+/// missing range is requested and the whole file is verified at the end.
+/// Otherwise it is downloaded again. The data is written by
+/// `handle_connection2`.
+///
+/// # Arguments
+///
+/// * `stream` - Connected TcpStream
+/// * `encoder` - Encoder used for every outgoing frame
+/// * `config` - Server address for the `Stat` session and the checksum algorithm
+/// * `client_id` - Id confirmed by the handshake
+/// * `remote` - Source path relative to the server storage root
+/// * `local` - Where to save the file
+/// * `get_tx` - Hands the local target to `handle_connection2`
+///
+fn get_file(
+    mut stream: &TcpStream,
+    encoder: &mut FrameEncoder,
+    config: &Config,
+    client_id: &str,
+    remote: &str,
+    local: &str,
+    get_tx: &Sender<PendingGet>,
+) -> io::Result<()> {
+    let local = PathBuf::from(local);
+    let stat = remote_stat(&config.server_address(), client_id, remote)?;
+    let origin = PartOrigin {
+        size: stat.size,
+        mtime: stat.mtime,
+    };
+    let mut offset = fs::metadata(part_path_of(&local))
+        .map(|metadata| metadata.len())
+        .unwrap_or(0);
+    if offset > 0 {
+        if offset <= stat.size && PartOrigin::read(&local).as_ref() == Some(&origin) {
+            println!("Resuming {} from {}B", local.display(), offset);
+        } else {
+            println!("{} changed on the server, downloading it again", remote);
+            offset = 0;
+        }
+    }
+    origin.write(&local)?;
+    get_tx
+        .send(PendingGet {
+            remote: remote.to_string(),
+            local,
+            file_size: stat.size,
+        })
+        .map_err(|e| io::Error::new(io::ErrorKind::BrokenPipe, e.to_string()))?;
+
+    let request = GetRequest {
+        path: remote.to_string(),
+        offset,
+        length: None,
+        hash: config.hash,
+        file_checksum: offset > 0,
+    };
+    encoder.write_packet(
+        &mut stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Get, &request),
+    )
+}
+
+/// Connections used by `pput`/`pget` when none are given. Each one is a
+/// separate session on the server's event loop, next to the interactive one.
+const PARALLEL_CONNECTIONS: usize = 3;
+
+/// One range of a parallel transfer, carried over its own connection.
+struct RangeJob {
+    address: String,
+    client_id: String,
+    remote: String,
+    local: PathBuf,
+    offset: u64,
+    length: u64,
+    file_size: u64,
+    hash: HashAlgorithm,
+}
+
+/// Split `file_size` bytes into at most `connections` ranges, the last
+/// range takes the remainder.
+fn split_ranges(file_size: u64, connections: usize) -> Vec<(u64, u64)> {
+    let count = (connections as u64).min(file_size).max(1);
+    let step = file_size / count;
+    (0..count)
+        .map(|i| {
+            let start = i * step;
+            let length = if i == count - 1 {
+                file_size - start
+            } else {
+                step
+            };
+            (start, length)
+        })
+        .collect()
+}
+
+/// Connect and handshake a dedicated session.
+fn open_session(address: &str, client_id: &str) -> io::Result<(TcpStream, FrameDecoder)> {
+    let mut stream = TcpStream::connect(address)?;
+    let mut decoder = FrameDecoder::new();
+    handshake(&mut stream, &mut decoder, client_id, None)?;
+    Ok((stream, decoder))
+}
+
+/// Send `Terminate` and wait for the server to acknowledge it.
+fn close_session(
+    mut stream: TcpStream,
+    mut decoder: FrameDecoder,
+    client_id: &str,
+) -> io::Result<()> {
+    FrameEncoder::new().write_packet(
+        &mut stream,
+        &MsgPacket::with_opcode(client_id, MsgOpcode::Terminate, ""),
+    )?;
+    while recv_packet(&mut stream, &mut decoder)?.opcode != MsgOpcode::Terminate {}
+    Ok(())
+}
+
+fn verify_range(
+    received: u64,
+    digest: Digest,
+    complete: &TransferComplete,
+    length: u64,
+) -> io::Result<()> {
+    let checksum = digest.to_string();
+    if received != length
+        || complete.bytes != length
+        || complete.hash != digest.algorithm()
+        || checksum != complete.checksum
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{}B {} {} locally, {}B {} {} on the server",
+                received,
+                digest.algorithm(),
+                checksum,
+                complete.bytes,
+                complete.hash,
+                complete.checksum
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Ask the server for the size of `remote` with an empty ranged `Get`.
+fn remote_size(address: &str, client_id: &str, remote: &str) -> io::Result<u64> {
+    let (mut stream, mut decoder) = open_session(address, client_id)?;
+    let request = GetRequest {
+        path: remote.to_string(),
+        offset: 0,
+        length: Some(0),
+        hash: HashAlgorithm::default(),
+        file_checksum: false,
+    };
+    FrameEncoder::new().write_packet(
+        &mut stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Get, &request),
+    )?;
+    let mut file_size = None;
+    loop {
+        let packet = recv_packet(&mut stream, &mut decoder)?;
+        match packet.opcode {
+            MsgOpcode::Get => file_size = Some(packet.body::<GetReply>()?.file_size),
+            MsgOpcode::Complete => break,
+            MsgOpcode::Error => return Err(error_of(&packet)),
+            _ => (),
+        }
+    }
+    close_session(stream, decoder, client_id)?;
+    file_size.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Get reply"))
+}
+
+/// Ask the server for the metadata of `remote` over a dedicated session.
+fn remote_stat(address: &str, client_id: &str, remote: &str) -> io::Result<FileStat> {
+    let (mut stream, mut decoder) = open_session(address, client_id)?;
+    let request = PathRequest {
+        path: remote.to_string(),
+    };
+    FrameEncoder::new().write_packet(
+        &mut stream,
+        &MsgPacket::with_body(client_id, MsgOpcode::Stat, &request),
+    )?;
+    let stat = loop {
+        let packet = recv_packet(&mut stream, &mut decoder)?;
+        match packet.opcode {
+            MsgOpcode::Stat => break packet.body::<FileStat>()?,
+            MsgOpcode::Error => return Err(error_of(&packet)),
+            _ => (),
+        }
+    };
+    close_session(stream, decoder, client_id)?;
+    Ok(stat)
+}
+
+/// Download one range into its place in `job.local`.
+fn get_range(job: &RangeJob) -> io::Result<()> {
+    let (mut stream, mut decoder) = open_session(&job.address, &job.client_id)?;
+    let request = GetRequest {
+        path: job.remote.clone(),
+        offset: job.offset,
+        length: Some(job.length),
+        hash: job.hash,
+        file_checksum: false,
+    };
+    FrameEncoder::new().write_packet(
+        &mut stream,
+        &MsgPacket::with_body(&job.client_id, MsgOpcode::Get, &request),
+    )?;
+
+    let mut file = OpenOptions::new().write(true).open(&job.local)?;
+    file.seek(SeekFrom::Start(job.offset))?;
+    let mut received = 0u64;
+    let mut hasher = job.hash.hasher();
+    loop {
+        let frame = recv_frame(&mut stream, &mut decoder)?;
+        if frame.opcode() == MsgOpcode::Data as u8 {
+            let chunk = data_chunk(Some(job.hash), &frame.payload)?;
+            file.write_all(chunk)?;
+            hasher.update(chunk);
+            received += chunk.len() as u64;
+            continue;
+        }
+        let packet = serde_json::from_slice::<MsgPacket>(&frame.payload)?;
+        match packet.opcode {
+            MsgOpcode::Complete => {
+                verify_range(received, hasher.finish(), &packet.body()?, job.length)?;
+                break;
+            }
+            MsgOpcode::Error => return Err(error_of(&packet)),
+            _ => (),
+        }
+    }
+    file.sync_all()?;
+    close_session(stream, decoder, &job.client_id)
+}
+
+/// Upload one range of `job.local` into its place on the server.
+fn put_range(job: &RangeJob) -> io::Result<()> {
+    let (stream, mut decoder) = open_session(&job.address, &job.client_id)?;
+    let mut encoder = FrameEncoder::new();
+    let request = PutRequest {
+        path: job.remote.clone(),
+        size: job.length,
+        offset: job.offset,
+        file_size: Some(job.file_size),
+        hash: job.hash,
+    };
+    encoder.write_packet(
+        &mut &stream,
+        &MsgPacket::with_body(&job.client_id, MsgOpcode::Put, &request),
+    )?;
+
+    let mut file = File::open(&job.local)?;
+    file.seek(SeekFrom::Start(job.offset))?;
+    let mut reader = file.take(job.length);
+    let mut buf = vec![0u8; DATA_CHUNK_LEN];
+    let mut sent = 0u64;
+    let mut hasher = job.hash.hasher();
+    loop {
+        let len = reader.read(&mut buf)?;
+        if len == 0 {
+            break;
+        }
+        encoder.write_data(&mut &stream, Some(job.hash), &buf[..len])?;
+        hasher.update(&buf[..len]);
+        sent += len as u64;
+    }
+
+    let mut stream = stream;
+    loop {
+        let packet = recv_packet(&mut stream, &mut decoder)?;
+        match packet.opcode {
+            MsgOpcode::Complete => {
+                verify_range(sent, hasher.finish(), &packet.body()?, job.length)?;
+                break;
+            }
+            MsgOpcode::Error => return Err(error_of(&packet)),
+            _ => (),
+        }
+    }
+    close_session(stream, decoder, &job.client_id)
+}
+
+/// Run every range on its own pool worker and connection, then report
+/// the ranges that failed.
+fn run_parallel(jobs: Vec<RangeJob>, transfer: fn(&RangeJob) -> io::Result<()>) -> io::Result<()> {
+    let count = jobs.len();
+    let pool = ThreadPool::new(count);
+    let handles: Vec<_> = jobs
+        .into_iter()
+        .map(|job| {
+            let range = (job.offset, job.length);
+            (range, pool.execute(move || transfer(&job)))
+        })
+        .collect();
+
+    let mut failed = 0;
+    for ((offset, length), handle) in handles {
+        let result = handle
+            .join()
+            .unwrap_or_else(|e| Err(io::Error::other(e.to_string())));
+        if let Err(e) = result {
+            eprintln!("Range {}+{}B failed: {}", offset, length, e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{} of {} ranges failed",
+            failed, count
+        )));
+    }
+    Ok(())
+}
+
+fn range_jobs(
+    address: &str,
+    client_id: &str,
+    remote: &str,
+    local: &Path,
+    file_size: u64,
+    connections: usize,
+    hash: HashAlgorithm,
+) -> Vec<RangeJob> {
+    split_ranges(file_size, connections)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (offset, length))| RangeJob {
+            address: address.to_string(),
+            client_id: format!("{}#{}", client_id, i),
+            remote: remote.to_string(),
+            local: local.to_path_buf(),
+            offset,
+            length,
+            file_size,
+            hash,
+        })
+        .collect()
+}
+
+/// Download `remote` over `connections` parallel sessions, each writing
+/// its range at the right offset of one preallocated file.
+fn parallel_get(
+    address: &str,
+    client_id: &str,
+    remote: &str,
+    local: &str,
+    connections: usize,
+    hash: HashAlgorithm,
+) -> io::Result<()> {
+    let file_size = remote_size(address, client_id, remote)?;
+    let local = PathBuf::from(local);
+    let mut part = local.as_os_str().to_owned();
+    part.push(".pget");
+    let part = PathBuf::from(part);
+    File::create(&part)?.set_len(file_size)?;
+
+    let jobs = range_jobs(
+        address,
+        client_id,
+        remote,
+        &part,
+        file_size,
+        connections,
+        hash,
+    );
+    let count = jobs.len();
+    let current_time = Instant::now();
+    if let Err(e) = run_parallel(jobs, get_range) {
+        fs::remove_file(&part)?;
+        return Err(e);
+    }
+    fs::rename(&part, &local)?;
+    println!(
+        "Downloaded {} to {} ({}B over {} connections) in {} msec",
+        remote,
+        local.display(),
+        file_size,
+        count,
+        current_time.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Upload `local` over `connections` parallel sessions, each writing
+/// its range at the right offset of the destination file.
+fn parallel_put(
+    address: &str,
+    client_id: &str,
+    local: &str,
+    remote: &str,
+    connections: usize,
+    hash: HashAlgorithm,
+) -> io::Result<()> {
+    let file_size = fs::metadata(local)?.len();
+    let jobs = range_jobs(
+        address,
+        client_id,
+        remote,
+        Path::new(local),
+        file_size,
+        connections,
+        hash,
+    );
+    let count = jobs.len();
+    let current_time = Instant::now();
+    run_parallel(jobs, put_range)?;
+    println!(
+        "Uploaded {} to {} ({}B over {} connections) in {} msec",
+        local,
+        remote,
+        file_size,
+        count,
+        current_time.elapsed().as_millis()
+    );
+    Ok(())
+}
+
+/// Print every device known to the registry at `registry`.
+fn show_devices(registry: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(registry)?;
+    let devices = list_devices(&mut stream)?;
+    println!("{} devices at {}", devices.len(), registry);
+    for device in &devices {
+        println!(
+            "{} {:<8} {}:{} {} {} ({} cores) updated {}",
+            device.id,
+            device.status,
+            device.ip_addr,
+            device.port,
+            device.os,
+            device.os_version,
+            device.core_num,
+            device.updated_at
+        );
+        // Older devices report schema version 1 without the details below.
+        if device.schema_version < 2 {
+            continue;
+        }
+        println!(
+            "    host {}, memory {} / {} MB free, load {:.2} {:.2} {:.2}",
+            device.hostname,
+            device.memory.available_bytes >> 20,
+            device.memory.total_bytes >> 20,
+            device.load_average.one,
+            device.load_average.five,
+            device.load_average.fifteen
+        );
+        for disk in &device.disks {
+            println!(
+                "    disk {} ({}) {} / {} MB free",
+                disk.mount_point,
+                disk.file_system,
+                disk.available_bytes >> 20,
+                disk.total_bytes >> 20
+            );
+        }
+        for iface in &device.interfaces {
+            println!("    if {} {}", iface.name, iface.addresses.join(" "));
+        }
+        if let Some(public_key) = &device.public_key {
+            println!("    key {}", public_key);
+        }
+    }
+    Ok(())
+}
+
+/// `ls -l` style mode column, e.g. `drwxr-xr-x`.
+fn mode_string(stat: &FileStat) -> String {
+    let mut mode = String::with_capacity(10);
+    mode.push(match stat.file_type {
+        FileType::Dir => 'd',
+        FileType::Symlink => 'l',
+        FileType::File => '-',
+        FileType::Other => '?',
+    });
+    for shift in [6, 3, 0] {
+        let bits = stat.permissions >> shift;
+        mode.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        mode.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        mode.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+    mode
+}
+
+/// Format seconds since the Unix epoch as a UTC `YYYY-MM-DD HH:MM` string.
+fn format_mtime(secs: u64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    let rem = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+fn handle_connection2(
+    mut stream: &TcpStream,
+    mut decoder: FrameDecoder,
+    get_rx: Receiver<PendingGet>,
+    terminating: &AtomicBool,
+) -> () {
+    let mut pending_gets = HashMap::<String, PendingGet>::new();
+    let mut download: Option<LocalDownload> = None;
+    loop {
+        loop {
+            let frame = match decoder.next_frame() {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e) => {
+                    eprintln!("Malformed frame from server: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            if frame.opcode() == MsgOpcode::Data as u8 {
+                match download.as_mut() {
+                    Some(active) => {
+                        if let Err(e) = active.write_chunk(&frame.payload) {
+                            eprintln!("Download of {} failed: {}", active.remote, e);
+                            download = None;
+                        }
+                    }
+                    None => eprintln!("Unexpected data frame ({}B)", frame.payload.len()),
+                }
+                continue;
+            }
+            let recv_packet = match serde_json::from_slice::<MsgPacket>(&frame.payload) {
+                Ok(packet) => packet,
+                Err(e) => {
+                    eprintln!(
+                        "Error decoding message len: {} Err: {}",
+                        frame.payload.len(),
+                        e
+                    );
+                    continue;
+                }
+            };
+            match recv_packet.opcode {
+                MsgOpcode::Terminate => {
+                    println!("Session terminated by server.");
+                    if terminating.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    std::process::exit(0);
+                }
+                MsgOpcode::Error => match recv_packet.body::<ErrorReply>() {
+                    Ok(reply) => eprintln!("!! {}", reply),
+                    Err(_) => eprintln!("!! {}", recv_packet.data),
+                },
+                MsgOpcode::Progress => {
+                    if let Ok(progress) = recv_packet.body::<TransferProgress>() {
+                        println!(
+                            ".. {}: {}/{}B ({}%)",
+                            progress.path,
+                            progress.bytes,
+                            progress.total,
+                            progress.bytes * 100 / progress.total.max(1)
+                        );
+                    }
+                }
+                MsgOpcode::Delete
+                | MsgOpcode::Rename
+                | MsgOpcode::Mkdir
+                | MsgOpcode::Rmdir
+                | MsgOpcode::Truncate => {
+                    println!("ok: {:?} {}", recv_packet.opcode, recv_packet.data)
+                }
+                MsgOpcode::Status => match recv_packet.body::<StatusReply>() {
+                    Ok(reply) => println!("{}", reply.pool),
+                    Err(e) => eprintln!("Malformed Status reply: {}", e),
+                },
+                MsgOpcode::List => match recv_packet.body::<ListReply>() {
+                    Ok(reply) => {
+                        println!("/{} ({} entries)", reply.path, reply.entries.len());
+                        for entry in &reply.entries {
+                            println!(
+                                "{} {:>12} {} {}",
+                                mode_string(entry),
+                                entry.size,
+                                format_mtime(entry.mtime),
+                                entry.name
+                            );
+                        }
+                    }
+                    Err(e) => eprintln!("Malformed List reply: {}", e),
+                },
+                MsgOpcode::Stat => match recv_packet.body::<FileStat>() {
+                    Ok(stat) => {
+                        println!("  name: {}", stat.name);
+                        println!("  type: {:?}", stat.file_type);
+                        println!("  size: {}B", stat.size);
+                        println!("  mode: {} ({:04o})", mode_string(&stat), stat.permissions);
+                        println!(" mtime: {}", format_mtime(stat.mtime));
+                    }
+                    Err(e) => eprintln!("Malformed Stat reply: {}", e),
+                },
+                MsgOpcode::Get => {
+                    let Ok(reply) = recv_packet.body::<GetReply>() else {
+                        continue;
+                    };
+                    pending_gets.extend(get_rx.try_iter().map(|get| (get.remote.clone(), get)));
+                    let Some(pending) = pending_gets.remove(&reply.path) else {
+                        eprintln!("Unexpected download of {}", reply.path);
+                        continue;
+                    };
+                    download = LocalDownload::open(pending, &reply)
+                        .map_err(|e| eprintln!("Failed to open download target: {}", e))
+                        .ok();
+                }
+                MsgOpcode::Complete => {
+                    let Ok(complete) = recv_packet.body::<TransferComplete>() else {
+                        continue;
+                    };
+                    match download.take_if(|active| active.remote == complete.path) {
+                        Some(active) => active.finish(&complete).unwrap_or_else(|e| {
+                            eprintln!("Download failed: {}", e);
+                        }),
+                        None => println!(
+                            "Transfer complete: {} ({}B, {} {})",
+                            complete.path, complete.bytes, complete.hash, complete.checksum
+                        ),
+                    }
+                }
+                _ => println!(">> {:?}", recv_packet),
+            }
+        }
+        match decoder.read_from(&mut stream) {
+            Ok(0) => {
+                if terminating.load(Ordering::SeqCst) {
+                    return;
+                }
+                println!("Connection closed by server.");
+                std::process::exit(0);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                eprintln!("Failed to read from server: {}", e);
+                std::process::exit(1);
             }
-            Err(_) => (),
         }
     }
 }
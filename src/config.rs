@@ -0,0 +1,705 @@
+use crate::hash::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable naming the config file, like `--config`.
+pub const CONFIG_ENV: &str = "XFS_CONFIG";
+
+/// Largest accepted `pool_size`.
+pub const MAX_POOL_SIZE: usize = 256;
+
+/// Settings shared by the server, the client and the device `connect`.
+///
+/// Sources are applied in order, later ones win: built in defaults, the
+/// JSON config file (`--config` or `XFS_CONFIG`), `XFS_*` environment
+/// variables, command line flags.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Address the server binds to, and the client connects to.
+    pub host: String,
+    pub port: u16,
+    /// Most worker threads of the server's `ThreadPool`.
+    pub pool_size: usize,
+    /// Worker threads kept alive when the server is idle.
+    pub min_pool_size: usize,
+    /// Seconds an idle worker above `min_pool_size` waits before it exits.
+    pub pool_idle_timeout: u64,
+    /// Jobs the server's `ThreadPool` queues before submitters wait.
+    pub queue_capacity: usize,
+    /// Directory exported by the server.
+    pub storage_root: PathBuf,
+    /// `host:port` of the device registry used by `connect`.
+    pub registry: String,
+    /// File the server's device registry is saved to.
+    pub registry_store: PathBuf,
+    /// Seconds between two heartbeats a device sends to the registry.
+    pub heartbeat_interval: u64,
+    /// Seconds between two reports of a device's refreshed spec, sent in
+    /// place of a heartbeat. At least `heartbeat_interval`.
+    pub spec_refresh_interval: u64,
+    /// Seconds without a heartbeat before the registry suspects a device.
+    pub suspect_timeout: u64,
+    /// Seconds without a heartbeat before the registry marks a device offline.
+    pub offline_timeout: u64,
+    /// Checksum algorithm of the client's transfers.
+    pub hash: HashAlgorithm,
+    /// Directory holding the persistent node identity.
+    pub state_dir: PathBuf,
+    /// Generate a keypair with a new node identity.
+    pub identity_key: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 8080,
+            pool_size: 4,
+            min_pool_size: 1,
+            pool_idle_timeout: 60,
+            queue_capacity: 1024,
+            storage_root: PathBuf::from("xfs_storage"),
+            registry: "127.0.0.1:7878".to_string(),
+            registry_store: PathBuf::from("xfs_registry.json"),
+            heartbeat_interval: 5,
+            spec_refresh_interval: 60,
+            suspect_timeout: 15,
+            offline_timeout: 60,
+            hash: HashAlgorithm::Crc32c,
+            state_dir: PathBuf::from("xfs_state"),
+            identity_key: false,
+        }
+    }
+}
+
+/// One settable key: config file field, environment variable and flag.
+struct Key {
+    name: &'static str,
+    env: &'static str,
+    flag: &'static str,
+    help: &'static str,
+}
+
+const KEYS: [Key; 16] = [
+    Key {
+        name: "host",
+        env: "XFS_HOST",
+        flag: "--host",
+        help: "server address to bind or connect to",
+    },
+    Key {
+        name: "port",
+        env: "XFS_PORT",
+        flag: "--port",
+        help: "server port",
+    },
+    Key {
+        name: "pool_size",
+        env: "XFS_POOL_SIZE",
+        flag: "--pool-size",
+        help: "most server worker threads",
+    },
+    Key {
+        name: "min_pool_size",
+        env: "XFS_MIN_POOL_SIZE",
+        flag: "--min-pool-size",
+        help: "server worker threads kept when idle",
+    },
+    Key {
+        name: "pool_idle_timeout",
+        env: "XFS_POOL_IDLE_TIMEOUT",
+        flag: "--pool-idle-timeout",
+        help: "seconds before an idle extra worker exits",
+    },
+    Key {
+        name: "queue_capacity",
+        env: "XFS_QUEUE_CAPACITY",
+        flag: "--queue-capacity",
+        help: "server jobs queued before backpressure",
+    },
+    Key {
+        name: "storage_root",
+        env: "XFS_STORAGE_ROOT",
+        flag: "--storage-root",
+        help: "directory exported by the server",
+    },
+    Key {
+        name: "registry",
+        env: "XFS_REGISTRY",
+        flag: "--registry",
+        help: "device registry host:port",
+    },
+    Key {
+        name: "registry_store",
+        env: "XFS_REGISTRY_STORE",
+        flag: "--registry-store",
+        help: "file the device registry is saved to",
+    },
+    Key {
+        name: "heartbeat_interval",
+        env: "XFS_HEARTBEAT_INTERVAL",
+        flag: "--heartbeat-interval",
+        help: "seconds between device heartbeats",
+    },
+    Key {
+        name: "spec_refresh_interval",
+        env: "XFS_SPEC_REFRESH_INTERVAL",
+        flag: "--spec-refresh-interval",
+        help: "seconds between device spec reports",
+    },
+    Key {
+        name: "suspect_timeout",
+        env: "XFS_SUSPECT_TIMEOUT",
+        flag: "--suspect-timeout",
+        help: "seconds without heartbeat until Suspect",
+    },
+    Key {
+        name: "offline_timeout",
+        env: "XFS_OFFLINE_TIMEOUT",
+        flag: "--offline-timeout",
+        help: "seconds without heartbeat until Offline",
+    },
+    Key {
+        name: "hash",
+        env: "XFS_HASH",
+        flag: "--hash",
+        help: "client transfer checksum: crc32c, xxh64, sha256",
+    },
+    Key {
+        name: "state_dir",
+        env: "XFS_STATE_DIR",
+        flag: "--state-dir",
+        help: "directory of the node identity",
+    },
+    Key {
+        name: "identity_key",
+        env: "XFS_IDENTITY_KEY",
+        flag: "--identity-key",
+        help: "true to give a new identity a keypair",
+    },
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was given.
+    Help,
+    UnknownFlag(String),
+    MissingValue(String),
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, serde_json::Error),
+    /// A value from any source failed to parse or validate.
+    Invalid {
+        key: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "help requested"),
+            ConfigError::UnknownFlag(flag) => write!(f, "unknown flag: {}", flag),
+            ConfigError::MissingValue(flag) => write!(f, "missing value for {}", flag),
+            ConfigError::Read(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "invalid config {}: {}", path.display(), e),
+            ConfigError::Invalid { key, value, reason } => {
+                write!(f, "invalid {} {:?}: {}", key, value, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the configuration from the process arguments and environment.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ConfigError` for unknown flags, unreadable config files
+    /// and values that fail validation.
+    ///
+    pub fn load() -> Result<Config, ConfigError> {
+        let args: Vec<String> = env::args().skip(1).collect();
+        Config::load_from(&args, |name| env::var(name).ok())
+    }
+
+    /// `load` with the arguments and environment given.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Command line arguments without the program name.
+    /// * `var` - Value of an environment variable, if set.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ConfigError` like `load`.
+    ///
+    fn load_from(
+        args: &[String],
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, ConfigError> {
+        let flags = parse_flags(args)?;
+
+        let config_path = flags
+            .iter()
+            .find(|(flag, _)| flag == "--config")
+            .map(|(_, value)| PathBuf::from(value))
+            .or_else(|| var(CONFIG_ENV).map(PathBuf::from));
+        let mut config = match config_path {
+            Some(path) => Config::from_file(&path)?,
+            None => Config::default(),
+        };
+
+        for key in KEYS.iter() {
+            if let Some(value) = var(key.env) {
+                config.set(key.name, &value)?;
+            }
+        }
+        for (flag, value) in flags.iter() {
+            if let Some(key) = KEYS.iter().find(|key| key.flag == flag) {
+                config.set(key.name, value)?;
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Like `load`, but prints the problem or the usage and exits.
+    pub fn load_or_exit() -> Config {
+        match Config::load() {
+            Ok(config) => config,
+            Err(ConfigError::Help) => {
+                println!("{}", usage());
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!("{}", usage());
+                std::process::exit(2);
+            }
+        }
+    }
+
+    /// Read a JSON config file. Missing fields keep their defaults.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the config file.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `ConfigError` if the file cannot be read or parsed.
+    ///
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let data =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        serde_json::from_str(&data).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+    }
+
+    /// `host:port` of the server.
+    pub fn server_address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    fn set(&mut self, name: &'static str, value: &str) -> Result<(), ConfigError> {
+        let invalid = |reason: String| ConfigError::Invalid {
+            key: name,
+            value: value.to_string(),
+            reason,
+        };
+        match name {
+            "host" => self.host = value.to_string(),
+            "port" => self.port = value.parse().map_err(|e| invalid(format!("{}", e)))?,
+            "pool_size" => self.pool_size = value.parse().map_err(|e| invalid(format!("{}", e)))?,
+            "min_pool_size" => {
+                self.min_pool_size = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "pool_idle_timeout" => {
+                self.pool_idle_timeout = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "queue_capacity" => {
+                self.queue_capacity = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "storage_root" => self.storage_root = PathBuf::from(value),
+            "registry" => self.registry = value.to_string(),
+            "registry_store" => self.registry_store = PathBuf::from(value),
+            "heartbeat_interval" => {
+                self.heartbeat_interval = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "spec_refresh_interval" => {
+                self.spec_refresh_interval = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "suspect_timeout" => {
+                self.suspect_timeout = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "offline_timeout" => {
+                self.offline_timeout = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            "hash" => self.hash = value.parse().map_err(invalid)?,
+            "state_dir" => self.state_dir = PathBuf::from(value),
+            "identity_key" => {
+                self.identity_key = value.parse().map_err(|e| invalid(format!("{}", e)))?
+            }
+            _ => unreachable!("no config key {}", name),
+        }
+        Ok(())
+    }
+
+    /// Check ranges and that both addresses resolve.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ConfigError::Invalid` for the first offending value.
+    ///
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |key: &'static str, value: String, reason: &str| {
+            Err(ConfigError::Invalid {
+                key,
+                value,
+                reason: reason.to_string(),
+            })
+        };
+        if self.port == 0 {
+            return invalid("port", self.port.to_string(), "must not be 0");
+        }
+        if self.pool_size == 0 || self.pool_size > MAX_POOL_SIZE {
+            return invalid(
+                "pool_size",
+                self.pool_size.to_string(),
+                &format!("must be between 1 and {}", MAX_POOL_SIZE),
+            );
+        }
+        if self.min_pool_size > self.pool_size {
+            return invalid(
+                "min_pool_size",
+                self.min_pool_size.to_string(),
+                "must not exceed pool_size",
+            );
+        }
+        if self.queue_capacity == 0 {
+            return invalid(
+                "queue_capacity",
+                self.queue_capacity.to_string(),
+                "must not be 0",
+            );
+        }
+        if self.storage_root.as_os_str().is_empty() {
+            return invalid("storage_root", String::new(), "must not be empty");
+        }
+        if self.registry_store.as_os_str().is_empty() {
+            return invalid("registry_store", String::new(), "must not be empty");
+        }
+        if self.state_dir.as_os_str().is_empty() {
+            return invalid("state_dir", String::new(), "must not be empty");
+        }
+        if self.heartbeat_interval == 0 {
+            return invalid(
+                "heartbeat_interval",
+                self.heartbeat_interval.to_string(),
+                "must not be 0",
+            );
+        }
+        // Refreshed specs take the place of heartbeats, so a shorter
+        // interval would never leave one to send.
+        if self.spec_refresh_interval < self.heartbeat_interval {
+            return invalid(
+                "spec_refresh_interval",
+                self.spec_refresh_interval.to_string(),
+                "must not be below heartbeat_interval",
+            );
+        }
+        if self.suspect_timeout == 0 {
+            return invalid(
+                "suspect_timeout",
+                self.suspect_timeout.to_string(),
+                "must not be 0",
+            );
+        }
+        if self.offline_timeout <= self.suspect_timeout {
+            return invalid(
+                "offline_timeout",
+                self.offline_timeout.to_string(),
+                "must exceed suspect_timeout",
+            );
+        }
+        if let Err(e) = (self.host.as_str(), self.port).to_socket_addrs() {
+            return invalid("host", self.host.clone(), &e.to_string());
+        }
+        match self.registry.to_socket_addrs() {
+            Ok(_) => Ok(()),
+            Err(e) => invalid("registry", self.registry.clone(), &e.to_string()),
+        }
+    }
+}
+
+/// Split `--flag value` and `--flag=value` pairs.
+fn parse_flags(args: &[String]) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg.clone(), None),
+        };
+        if flag != "--config" && !KEYS.iter().any(|key| key.flag == flag) {
+            return Err(ConfigError::UnknownFlag(flag));
+        }
+        let value = match value.or_else(|| args.next().cloned()) {
+            Some(value) => value,
+            None => return Err(ConfigError::MissingValue(flag)),
+        };
+        flags.push((flag, value));
+    }
+    Ok(flags)
+}
+
+pub fn usage() -> String {
+    let mut usage = format!(
+        "Options:\n  {:<20} {:<18} {}\n",
+        "--config <path>", CONFIG_ENV, "JSON config file"
+    );
+    for key in KEYS.iter() {
+        usage.push_str(&format!(
+            "  {:<20} {:<18} {}\n",
+            format!("{} <v>", key.flag),
+            key.env,
+            key.help
+        ));
+    }
+    usage.push_str("  -h, --help");
+    usage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    /// A config file in a fresh temporary directory, removed on drop.
+    struct ConfigFile(PathBuf);
+
+    impl ConfigFile {
+        fn new(json: &str) -> ConfigFile {
+            let path =
+                std::env::temp_dir().join(format!("xfs-config-{}.json", uuid::Uuid::new_v4()));
+            fs::write(&path, json).unwrap();
+            ConfigFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn load(flags: &[&str], vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        Config::load_from(&args(flags), |name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn parse_flags_splits_both_forms() {
+        let flags = parse_flags(&args(&["--port", "9000", "--host=::1", "--config=a=b"])).unwrap();
+        assert_eq!(
+            flags,
+            [
+                ("--port".to_string(), "9000".to_string()),
+                ("--host".to_string(), "::1".to_string()),
+                ("--config".to_string(), "a=b".to_string()),
+            ]
+        );
+        assert!(parse_flags(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parse_flags_rejects_bad_arguments() {
+        assert!(matches!(
+            parse_flags(&args(&["--port", "1", "-h"])),
+            Err(ConfigError::Help)
+        ));
+        assert!(matches!(
+            parse_flags(&args(&["--help"])),
+            Err(ConfigError::Help)
+        ));
+        assert!(matches!(
+            parse_flags(&args(&["--prot", "1"])),
+            Err(ConfigError::UnknownFlag(flag)) if flag == "--prot"
+        ));
+        assert!(matches!(
+            parse_flags(&args(&["9000"])),
+            Err(ConfigError::UnknownFlag(flag)) if flag == "9000"
+        ));
+        assert!(matches!(
+            parse_flags(&args(&["--port"])),
+            Err(ConfigError::MissingValue(flag)) if flag == "--port"
+        ));
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let file = ConfigFile::new(r#"{"port": 1000, "pool_size": 8, "queue_capacity": 16}"#);
+        let config = load(
+            &["--config", file.path(), "--port", "3000"],
+            &[("XFS_PORT", "2000"), ("XFS_POOL_SIZE", "6")],
+        )
+        .unwrap();
+        assert_eq!(config.port, 3000);
+        assert_eq!(config.pool_size, 6);
+        assert_eq!(config.queue_capacity, 16);
+        assert_eq!(config.host, Config::default().host);
+
+        let config = load(&[], &[(CONFIG_ENV, file.path()), ("XFS_PORT", "2000")]).unwrap();
+        assert_eq!((config.port, config.pool_size), (2000, 8));
+        assert_eq!(load(&[], &[]).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn config_flag_overrides_config_env() {
+        let env_file = ConfigFile::new(r#"{"port": 1000}"#);
+        let flag_file = ConfigFile::new(r#"{"port": 2000}"#);
+        let config = load(
+            &["--config", flag_file.path()],
+            &[(CONFIG_ENV, env_file.path())],
+        )
+        .unwrap();
+        assert_eq!(config.port, 2000);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected_in_files_only() {
+        let file = ConfigFile::new(r#"{"prot": 1000}"#);
+        assert!(matches!(
+            load(&["--config", file.path()], &[]),
+            Err(ConfigError::Parse(..))
+        ));
+        assert!(matches!(
+            load(&["--config", "/nonexistent/xfs.json"], &[]),
+            Err(ConfigError::Read(..))
+        ));
+        // Unrelated variables are none of the config's business.
+        assert_eq!(
+            load(&[], &[("XFS_PROT", "1000")]).unwrap(),
+            Config::default()
+        );
+    }
+
+    #[test]
+    fn unparsable_values_name_their_key() {
+        assert!(matches!(
+            load(&[], &[("XFS_PORT", "http")]),
+            Err(ConfigError::Invalid { key: "port", value, .. }) if value == "http"
+        ));
+        assert!(matches!(
+            load(&["--hash", "md5"], &[]),
+            Err(ConfigError::Invalid { key: "hash", .. })
+        ));
+        assert!(matches!(
+            load(&["--identity-key", "yes"], &[]),
+            Err(ConfigError::Invalid {
+                key: "identity_key",
+                ..
+            })
+        ));
+    }
+
+    /// The first value `validate` rejects after `change` to the defaults.
+    fn rejected(change: impl FnOnce(&mut Config)) -> Option<&'static str> {
+        let mut config = Config::default();
+        change(&mut config);
+        match config.validate() {
+            Ok(()) => None,
+            Err(ConfigError::Invalid { key, .. }) => Some(key),
+            Err(e) => panic!("expected Invalid, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_zero_intervals() {
+        assert_eq!(rejected(|_| ()), None);
+        assert_eq!(rejected(|config| config.port = 0), Some("port"));
+        assert_eq!(rejected(|config| config.pool_size = 0), Some("pool_size"));
+        assert_eq!(
+            rejected(|config| config.queue_capacity = 0),
+            Some("queue_capacity")
+        );
+        assert_eq!(
+            rejected(|config| config.heartbeat_interval = 0),
+            Some("heartbeat_interval")
+        );
+        assert_eq!(
+            rejected(|config| config.spec_refresh_interval = 0),
+            Some("spec_refresh_interval")
+        );
+        assert_eq!(
+            rejected(|config| config.suspect_timeout = 0),
+            Some("suspect_timeout")
+        );
+    }
+
+    #[test]
+    fn validate_checks_related_values() {
+        assert_eq!(
+            rejected(|config| config.pool_size = MAX_POOL_SIZE + 1),
+            Some("pool_size")
+        );
+        assert_eq!(
+            rejected(|config| config.min_pool_size = config.pool_size + 1),
+            Some("min_pool_size")
+        );
+        assert_eq!(
+            rejected(|config| config.min_pool_size = config.pool_size),
+            None
+        );
+        assert_eq!(
+            rejected(|config| config.spec_refresh_interval = config.heartbeat_interval - 1),
+            Some("spec_refresh_interval")
+        );
+        assert_eq!(
+            rejected(|config| config.spec_refresh_interval = config.heartbeat_interval),
+            None
+        );
+        assert_eq!(
+            rejected(|config| config.offline_timeout = config.suspect_timeout),
+            Some("offline_timeout")
+        );
+        assert_eq!(
+            rejected(|config| config.offline_timeout = config.suspect_timeout - 1),
+            Some("offline_timeout")
+        );
+        assert_eq!(
+            rejected(|config| config.offline_timeout = config.suspect_timeout + 1),
+            None
+        );
+        assert_eq!(
+            rejected(|config| config.storage_root = PathBuf::new()),
+            Some("storage_root")
+        );
+        assert_eq!(
+            rejected(|config| config.registry = "no port".to_string()),
+            Some("registry")
+        );
+    }
+}
@@ -1,15 +1,20 @@
-use crate::device::spec;
-use serde_json::to_vec;
-use std::io::{self, Write};
+use crate::config::Config;
+use crate::device::identity::NodeIdentity;
+use crate::device::spec::{self, DeviceSpec};
+use crate::registry::message::{body, read_message, write_message, Heartbeat, RegistryOpcode};
+use std::io;
 use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
 
 struct IPv4 {
     ip: String,
     port: u16,
 }
 
-pub fn connect() -> TcpStream {
-    let stream = match TcpStream::connect("127.0.0.1:7878") {
+/// Connect to the device registry at `config.registry`, exit on failure.
+pub fn connect(config: &Config) -> TcpStream {
+    let stream = match TcpStream::connect(&config.registry) {
         Ok(stream) => stream,
         Err(e) => {
             eprintln!("Failed to connect: {}", e);
@@ -20,12 +25,15 @@ pub fn connect() -> TcpStream {
     stream
 }
 
-pub fn send_device_spec(stream: &mut TcpStream) -> io::Result<()> {
+pub fn send_device_spec(stream: &mut TcpStream, identity: &NodeIdentity) -> io::Result<()> {
     /*
      * Send own device spec to server
      */
-    let mut device_info = spec::get_system_info();
+    report_device(stream, spec::get_system_info(identity))
+}
 
+/// Send `device_info` with the local address of `stream` filled in.
+fn report_device(stream: &mut TcpStream, mut device_info: DeviceSpec) -> io::Result<()> {
     let local_l3_info = IPv4 {
         ip: stream.local_addr().unwrap().ip().to_string(),
         port: stream.local_addr().unwrap().port(),
@@ -34,14 +42,82 @@ pub fn send_device_spec(stream: &mut TcpStream) -> io::Result<()> {
     device_info.ip_addr = local_l3_info.ip;
     device_info.port = local_l3_info.port;
 
-    let serialized_data = to_vec(&device_info).unwrap();
+    write_message(stream, RegistryOpcode::Report, &device_info)
+}
+
+/// Tell the registry that the device `id` is still alive.
+pub fn send_heartbeat(stream: &mut TcpStream, id: &str) -> io::Result<()> {
+    let heartbeat = Heartbeat { id: id.to_string() };
+    write_message(stream, RegistryOpcode::Heartbeat, &heartbeat)
+}
 
-    let data_length = serialized_data.len() as u32;
-    let length_bytes = data_length.to_be_bytes();
+/// Keep this device registered from a background thread: report the spec,
+/// then send a heartbeat every `config.heartbeat_interval` seconds over the
+/// same connection. Every `config.spec_refresh_interval` seconds the spec is
+/// read again and reported instead, so disks, memory, load and interfaces
+/// stay current. A lost registry is retried at the heartbeat interval.
+pub fn spawn_heartbeat(config: &Config, identity: &NodeIdentity) {
+    let registry = config.registry.clone();
+    let interval = Duration::from_secs(config.heartbeat_interval);
+    let refresh = Duration::from_secs(config.spec_refresh_interval);
+    let identity = identity.clone();
+    thread::spawn(move || {
+        let mut connected = false;
+        loop {
+            if let Err(e) = keep_alive(&registry, &identity, interval, refresh, &mut connected) {
+                if connected {
+                    eprintln!("Lost device registry {}: {}", registry, e);
+                    connected = false;
+                }
+            }
+            thread::sleep(interval);
+        }
+    });
+}
 
-    stream.write_all(&[0u8])?;
-    stream.write_all(&length_bytes)?;
-    stream.write_all(&serialized_data)?;
+/// Report a fresh spec, then send heartbeats and refreshed specs until the
+/// connection fails.
+fn keep_alive(
+    registry: &str,
+    identity: &NodeIdentity,
+    interval: Duration,
+    refresh: Duration,
+    connected: &mut bool,
+) -> io::Result<()> {
+    let mut stream = TcpStream::connect(registry)?;
+    report_device(&mut stream, spec::get_system_info(identity))?;
+    let mut reported = Instant::now();
+    if !*connected {
+        println!("Registered with device registry {}", registry);
+        *connected = true;
+    }
+    loop {
+        thread::sleep(interval);
+        if reported.elapsed() >= refresh {
+            // A report also counts as a heartbeat on the registry.
+            report_device(&mut stream, spec::get_system_info(identity))?;
+            reported = Instant::now();
+        } else {
+            send_heartbeat(&mut stream, &identity.id)?;
+        }
+    }
+}
 
-    Ok(())
+/// Ask the registry for every device it knows.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` if the query fails or the registry closes
+/// the connection without answering.
+///
+pub fn list_devices(stream: &mut TcpStream) -> io::Result<Vec<DeviceSpec>> {
+    write_message(stream, RegistryOpcode::List, &())?;
+    match read_message(stream)? {
+        Some((RegistryOpcode::List, payload)) => body(&payload),
+        Some((opcode, _)) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {:?} reply to List", opcode),
+        )),
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
 }
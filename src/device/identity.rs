@@ -0,0 +1,387 @@
+use crate::hash::HashAlgorithm;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// File in the state directory holding the identity.
+pub const IDENTITY_FILE: &str = "identity.json";
+/// File in the state directory holding the secret key, readable by the
+/// owner only.
+pub const KEY_FILE: &str = "identity.key";
+/// Prefix of every signed handshake challenge, so a signature is never
+/// valid for anything but a handshake.
+const CHALLENGE_CONTEXT: &[u8] = b"xfs handshake challenge:";
+/// Bytes of a handshake challenge.
+const CHALLENGE_LEN: usize = 32;
+
+/// Identity of this node, created once in the state directory and loaded on
+/// every start, so a machine stays the same device across restarts.
+///
+/// With a keypair the id is the fingerprint of the public key, which peers
+/// check with `is_bound`, and the node proves it holds the secret key by
+/// signing a challenge, see `sign_challenge` and `verify_challenge`. The
+/// keypair is only generated together with a new identity, an existing id
+/// never changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NodeIdentity {
+    pub id: String,
+    /// Hex encoded Ed25519 public key, if the node has a keypair.
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Loaded from `KEY_FILE`, never part of `IDENTITY_FILE`.
+    #[serde(skip)]
+    signing_key: Option<SigningKey>,
+}
+
+impl NodeIdentity {
+    /// Load the identity from `state_dir`, or create and save a new one.
+    ///
+    /// # Arguments
+    ///
+    /// * `state_dir` - Directory holding `IDENTITY_FILE` and `KEY_FILE`,
+    ///   created if missing.
+    /// * `with_key` - Generate a keypair if a new identity is created.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if the state directory cannot be read
+    /// or written, or holds a corrupt identity.
+    ///
+    pub fn load_or_create(state_dir: &Path, with_key: bool) -> io::Result<NodeIdentity> {
+        fs::create_dir_all(state_dir)?;
+        let path = state_dir.join(IDENTITY_FILE);
+        let mut identity = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<NodeIdentity>(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = if with_key {
+                    // A key without an identity file is left by a crash
+                    // between the two writes, it is reused.
+                    let signing_key = match read_key(state_dir)? {
+                        Some(signing_key) => signing_key,
+                        None => generate_key(state_dir)?,
+                    };
+                    let public_key = signing_key.verifying_key().to_bytes();
+                    NodeIdentity {
+                        id: fingerprint(&public_key),
+                        public_key: Some(hex(&public_key)),
+                        signing_key: Some(signing_key),
+                    }
+                } else {
+                    NodeIdentity {
+                        id: uuid::Uuid::new_v4().to_string(),
+                        public_key: None,
+                        signing_key: None,
+                    }
+                };
+                let data = serde_json::to_vec_pretty(&identity)?;
+                write_atomic(&path, &data, 0o644)?;
+                return Ok(identity);
+            }
+            Err(e) => return Err(e),
+        };
+        if identity.id.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} has an empty id", path.display()),
+            ));
+        }
+        if let Some(public_key) = &identity.public_key {
+            let key_path = state_dir.join(KEY_FILE);
+            let signing_key = read_key(state_dir)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{} is missing", key_path.display()),
+                )
+            })?;
+            if hex(signing_key.verifying_key().as_bytes()) != *public_key {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "{} does not belong to {}",
+                        key_path.display(),
+                        path.display()
+                    ),
+                ));
+            }
+            identity.signing_key = Some(signing_key);
+        } else if with_key {
+            eprintln!(
+                "Identity {} has no keypair, remove {} to create a new one with a key",
+                identity.id,
+                path.display()
+            );
+        }
+        Ok(identity)
+    }
+
+    /// Sign a handshake challenge with the secret key.
+    ///
+    /// # Arguments
+    ///
+    /// * `challenge` - Hex encoded challenge sent by the server.
+    ///
+    /// # Returns
+    /// The hex encoded signature.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if the node has no keypair or the challenge
+    /// is malformed.
+    ///
+    pub fn sign_challenge(&self, challenge: &str) -> io::Result<String> {
+        let Some(signing_key) = &self.signing_key else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("identity {} has no keypair", self.id),
+            ));
+        };
+        let message = challenge_message(challenge)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "malformed challenge"))?;
+        Ok(hex(&signing_key.sign(&message).to_bytes()))
+    }
+}
+
+/// Create a random, hex encoded handshake challenge.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` if the system has no randomness.
+///
+pub fn new_challenge() -> io::Result<String> {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    getrandom::getrandom(&mut challenge).map_err(|e| io::Error::other(e.to_string()))?;
+    Ok(hex(&challenge))
+}
+
+/// Whether `signature` is the signature of `challenge` by the secret key of
+/// `public_key`, all three hex encoded.
+pub fn verify_challenge(public_key: &str, challenge: &str, signature: &str) -> bool {
+    let Some(public_key) = unhex(public_key).and_then(|bytes| bytes.try_into().ok()) else {
+        return false;
+    };
+    let Ok(public_key) = VerifyingKey::from_bytes(&public_key) else {
+        return false;
+    };
+    let Some(signature) = unhex(signature).and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    match challenge_message(challenge) {
+        Some(message) => public_key.verify(&message, &signature).is_ok(),
+        None => false,
+    }
+}
+
+/// The signed bytes: `CHALLENGE_CONTEXT` followed by the decoded challenge.
+fn challenge_message(challenge: &str) -> Option<Vec<u8>> {
+    let challenge = unhex(challenge).filter(|bytes| bytes.len() == CHALLENGE_LEN)?;
+    Some([CHALLENGE_CONTEXT, &challenge].concat())
+}
+
+/// Generate an Ed25519 keypair and save the secret key to `KEY_FILE`.
+fn generate_key(state_dir: &Path) -> io::Result<SigningKey> {
+    let mut secret = [0u8; 32];
+    getrandom::getrandom(&mut secret).map_err(|e| io::Error::other(e.to_string()))?;
+    write_atomic(&state_dir.join(KEY_FILE), hex(&secret).as_bytes(), 0o600)?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Read the secret key from `KEY_FILE`, `None` if there is none.
+fn read_key(state_dir: &Path) -> io::Result<Option<SigningKey>> {
+    let path = state_dir.join(KEY_FILE);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    match unhex(text.trim()).and_then(|bytes| bytes.try_into().ok()) {
+        Some(secret) => Ok(Some(SigningKey::from_bytes(&secret))),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is not a hex encoded key", path.display()),
+        )),
+    }
+}
+
+/// Write to a temporary file created with `mode` first and rename it onto
+/// `path`, so a crash never leaves a torn file.
+fn write_atomic(path: &Path, data: &[u8], mode: u32) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    // A leftover of a crash may have been created with another mode.
+    match fs::remove_file(&tmp) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Whether `id` is the fingerprint of the hex encoded `public_key`.
+pub fn is_bound(id: &str, public_key: &str) -> bool {
+    match unhex(public_key) {
+        Some(bytes) => fingerprint(&bytes) == id,
+        None => false,
+    }
+}
+
+/// UUID formatted id from the first 16 bytes of the public key's SHA-256.
+fn fingerprint(public_key: &[u8]) -> String {
+    let digest = HashAlgorithm::Sha256.digest(public_key);
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest.as_bytes()[..16]);
+    uuid::Uuid::from_bytes(bytes).to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` would take a sign, so "+f" would decode like "0f".
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A fresh state directory, removed on drop.
+    struct StateDir(PathBuf);
+
+    impl StateDir {
+        fn new() -> StateDir {
+            StateDir(std::env::temp_dir().join(format!("xfs-identity-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for StateDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn keyless_identity_is_kept_across_loads() {
+        let dir = StateDir::new();
+        let first = NodeIdentity::load_or_create(&dir.0, false).unwrap();
+        assert!(first.public_key.is_none());
+        assert_eq!(NodeIdentity::load_or_create(&dir.0, false).unwrap(), first);
+        assert!(!dir.0.join(KEY_FILE).exists());
+        assert!(first.sign_challenge(&new_challenge().unwrap()).is_err());
+    }
+
+    #[test]
+    fn keyed_identity_is_bound_and_reloads_its_key() {
+        let dir = StateDir::new();
+        let first = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        let public_key = first.public_key.clone().unwrap();
+        assert!(is_bound(&first.id, &public_key));
+        let mode = fs::metadata(dir.0.join(KEY_FILE))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let second = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        assert_eq!(second, first);
+        let challenge = new_challenge().unwrap();
+        let signature = second.sign_challenge(&challenge).unwrap();
+        assert!(verify_challenge(&public_key, &challenge, &signature));
+    }
+
+    #[test]
+    fn key_without_identity_file_is_reused() {
+        let dir = StateDir::new();
+        let first = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        // A crash between writing the key and the identity.
+        fs::remove_file(dir.0.join(IDENTITY_FILE)).unwrap();
+        let second = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        assert_eq!(second, first);
+    }
+
+    #[test]
+    fn missing_or_foreign_key_is_an_error() {
+        let dir = StateDir::new();
+        NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        let other = StateDir::new();
+        NodeIdentity::load_or_create(&other.0, true).unwrap();
+
+        fs::copy(other.0.join(KEY_FILE), dir.0.join(KEY_FILE)).unwrap();
+        let e = NodeIdentity::load_or_create(&dir.0, true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(dir.0.join(KEY_FILE)).unwrap();
+        let e = NodeIdentity::load_or_create(&dir.0, true).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn replayed_public_key_fails_the_challenge() {
+        let victim_dir = StateDir::new();
+        let victim = NodeIdentity::load_or_create(&victim_dir.0, true).unwrap();
+        let attacker_dir = StateDir::new();
+        let attacker = NodeIdentity::load_or_create(&attacker_dir.0, true).unwrap();
+        let public_key = victim.public_key.as_deref().unwrap();
+
+        let challenge = new_challenge().unwrap();
+        let forged = attacker.sign_challenge(&challenge).unwrap();
+        assert!(!verify_challenge(public_key, &challenge, &forged));
+
+        // An old signature of the victim does not answer a new challenge.
+        let old = victim.sign_challenge(&new_challenge().unwrap()).unwrap();
+        assert!(!verify_challenge(public_key, &challenge, &old));
+    }
+
+    #[test]
+    fn malformed_input_does_not_verify() {
+        let dir = StateDir::new();
+        let node = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        let public_key = node.public_key.as_deref().unwrap();
+        let challenge = new_challenge().unwrap();
+        let signature = node.sign_challenge(&challenge).unwrap();
+
+        assert!(node.sign_challenge("abc").is_err());
+        assert!(node.sign_challenge("00").is_err());
+        assert!(!verify_challenge(public_key, "00", &signature));
+        assert!(!verify_challenge(public_key, &challenge, &signature[2..]));
+        assert!(!verify_challenge(&public_key[2..], &challenge, &signature));
+        assert!(!verify_challenge("zz", &challenge, &signature));
+    }
+
+    #[test]
+    fn unhex_takes_hex_digits_only() {
+        assert_eq!(
+            unhex(&hex(&[0x00, 0xab, 0xff])),
+            Some(vec![0x00, 0xab, 0xff])
+        );
+        assert_eq!(unhex("0A"), Some(vec![0x0a]));
+        assert_eq!(unhex("+f"), None);
+        assert_eq!(unhex("-0"), None);
+        assert_eq!(unhex(" f"), None);
+        assert_eq!(unhex("abc"), None);
+
+        let dir = StateDir::new();
+        let node = NodeIdentity::load_or_create(&dir.0, true).unwrap();
+        let public_key = node.public_key.unwrap();
+        let signed = format!("+{}", &public_key[1..]);
+        assert!(!is_bound(&node.id, &signed));
+    }
+}
@@ -1,9 +1,24 @@
+use crate::device::identity::NodeIdentity;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::time::{SystemTime, UNIX_EPOCH};
-use sysinfo::System;
+use sysinfo::{Disks, System};
+
+/// Current `DeviceSpec::schema_version`.
+///
+/// Version 1 carries the fields up to `updated_at`, version 2 adds the
+/// host, disk, memory, load and interface fields, version 3 the public key
+/// of the node identity. Fields added later must
+/// default when missing, and unknown fields are ignored, so peers of any
+/// version read each other's specs.
+pub const SPEC_VERSION: u32 = 3;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceSpec {
+    /// 1 for specs sent before the field existed.
+    #[serde(default = "schema_v1")]
+    pub schema_version: u32,
+    /// `NodeIdentity::id`, stable across restarts.
     pub id: String,
     pub os: String,
     pub os_version: String,
@@ -12,28 +27,85 @@ pub struct DeviceSpec {
     pub port: u16,
     pub status: String,
     pub updated_at: String,
+    #[serde(default)]
+    pub hostname: String,
+    #[serde(default)]
+    pub disks: Vec<DiskSpec>,
+    #[serde(default)]
+    pub memory: MemorySpec,
+    #[serde(default)]
+    pub load_average: LoadAverage,
+    #[serde(default)]
+    pub interfaces: Vec<InterfaceSpec>,
+    /// `NodeIdentity::public_key`.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+/// Capacity of one mounted file system.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DiskSpec {
+    pub mount_point: String,
+    pub file_system: String,
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Memory in bytes.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MemorySpec {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+}
+
+/// Load average over 1, 5 and 15 minutes, zero where unsupported.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LoadAverage {
+    pub one: f64,
+    pub five: f64,
+    pub fifteen: f64,
 }
 
-pub fn get_system_info() -> DeviceSpec {
+/// A network interface with all of its IPv4 and IPv6 addresses.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InterfaceSpec {
+    pub name: String,
+    pub addresses: Vec<String>,
+}
+
+fn schema_v1() -> u32 {
+    1
+}
+
+/// Describe this machine as the node `identity`.
+pub fn get_system_info(identity: &NodeIdentity) -> DeviceSpec {
     let mut system = System::new_all();
     system.refresh_all();
 
     let os = System::name().unwrap_or_else(|| "Unknown".to_string());
     let os_version = System::os_version().unwrap_or_else(|| "Unknown version".to_string());
     let core_num = system.cpus().len() as u8;
+    let hostname = System::host_name().unwrap_or_default();
+    let memory = MemorySpec {
+        total_bytes: system.total_memory(),
+        available_bytes: system.available_memory(),
+    };
+    let load = System::load_average();
+    let load_average = LoadAverage {
+        one: load.one,
+        five: load.five,
+        fifteen: load.fifteen,
+    };
 
     let ip_addr = "".to_string();
     let port = 0;
 
     let status = "Active".to_string();
-    let start = SystemTime::now();
-    let since_the_epoch = start
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards");
-    let updated_at = format!("{:?}", since_the_epoch);
+    let updated_at = timestamp();
 
     DeviceSpec {
-        id: uuid::Uuid::new_v4().to_string(),
+        schema_version: SPEC_VERSION,
+        id: identity.id.clone(),
         os,
         os_version,
         core_num,
@@ -41,5 +113,105 @@ pub fn get_system_info() -> DeviceSpec {
         port,
         status,
         updated_at,
+        hostname,
+        disks: disks(),
+        memory,
+        load_average,
+        interfaces: interfaces(),
+        public_key: identity.public_key.clone(),
+    }
+}
+
+fn disks() -> Vec<DiskSpec> {
+    Disks::new_with_refreshed_list()
+        .iter()
+        .map(|disk| DiskSpec {
+            mount_point: disk.mount_point().display().to_string(),
+            file_system: disk.file_system().to_string_lossy().into_owned(),
+            total_bytes: disk.total_space(),
+            available_bytes: disk.available_space(),
+        })
+        .collect()
+}
+
+/// Addresses grouped by interface name, empty if they cannot be listed.
+fn interfaces() -> Vec<InterfaceSpec> {
+    let addrs = match if_addrs::get_if_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            eprintln!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut interfaces: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for iface in addrs {
+        let addr = iface.ip().to_string();
+        interfaces.entry(iface.name).or_default().push(addr);
+    }
+    interfaces
+        .into_iter()
+        .map(|(name, addresses)| InterfaceSpec { name, addresses })
+        .collect()
+}
+
+/// Current time in the format of `DeviceSpec::updated_at`.
+pub fn timestamp() -> String {
+    let since_the_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards");
+    format!("{:?}", since_the_epoch)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A spec as serialized by version 1 devices, field for field.
+    const V1_SPEC: &str = r#"{
+        "id": "4b0a6c1e-6f0a-4c1e-9a54-0d3f1a2b3c4d",
+        "os": "Linux",
+        "os_version": "6.1",
+        "core_num": 8,
+        "ip_addr": "10.0.0.7",
+        "port": 7000,
+        "status": "Active",
+        "updated_at": "1700000000.5s"
+    }"#;
+
+    #[test]
+    fn version_1_spec_deserializes_with_defaults() {
+        let spec: DeviceSpec = serde_json::from_str(V1_SPEC).unwrap();
+        assert_eq!(spec.schema_version, 1);
+        assert_eq!(spec.id, "4b0a6c1e-6f0a-4c1e-9a54-0d3f1a2b3c4d");
+        assert_eq!(
+            (spec.os.as_str(), spec.os_version.as_str()),
+            ("Linux", "6.1")
+        );
+        assert_eq!(spec.core_num, 8);
+        assert_eq!((spec.ip_addr.as_str(), spec.port), ("10.0.0.7", 7000));
+        assert_eq!(spec.updated_at, "1700000000.5s");
+        assert_eq!(spec.hostname, "");
+        assert!(spec.disks.is_empty());
+        assert_eq!(spec.memory.total_bytes, 0);
+        assert_eq!(spec.load_average.one, 0.0);
+        assert!(spec.interfaces.is_empty());
+        assert_eq!(spec.public_key, None);
+    }
+
+    #[test]
+    fn unknown_fields_of_newer_specs_are_ignored() {
+        let mut json: serde_json::Value = serde_json::from_str(V1_SPEC).unwrap();
+        json["schema_version"] = SPEC_VERSION.into();
+        json["public_key"] = "00ff".into();
+        json["gpu"] = "from a later version".into();
+        let spec: DeviceSpec = serde_json::from_value(json).unwrap();
+        assert_eq!(spec.schema_version, SPEC_VERSION);
+        assert_eq!(spec.public_key.as_deref(), Some("00ff"));
+
+        // And a current spec reads back as itself.
+        let again: DeviceSpec =
+            serde_json::from_str(&serde_json::to_string(&spec).unwrap()).unwrap();
+        assert_eq!(again.schema_version, SPEC_VERSION);
+        assert_eq!(again.public_key, spec.public_key);
     }
 }
@@ -1,13 +1,20 @@
+use crate::hash::{Digest, HashAlgorithm};
 use std::fs::File;
-use std::io::{Error, Read, Seek, SeekFrom, Write};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 
+/// Buffer size of `copy_part`, `copy_range` and `create_file`.
+pub const DEFAULT_BUFFER_SIZE: usize = 1024 * 1024;
+
 /// Copies a part of a file from the source path to the destination file.
 ///
+/// Streams through a `DEFAULT_BUFFER_SIZE` buffer, see `copy_part_with`.
+///
 /// # Arguments
 ///
 /// * `src_path` - The path to the source file.
-/// * `dest_file` - The destination file wrapped in an `Arc<Mutex<File>>`.
+/// * `dest_file` - The destination file, written at its current position.
 /// * `start` - The starting position in the source file bytes to copy from.
 /// * `length` - The length of the part in bytes to copy.
 ///
@@ -21,19 +28,212 @@ pub fn copy_part(
     src_start: u64,
     length: u64,
 ) -> std::io::Result<()> {
+    copy_part_with(
+        src_path,
+        dest_file,
+        src_start,
+        length,
+        DEFAULT_BUFFER_SIZE,
+        |_, _| (),
+    )
+}
+
+/// Copies a part of a file through a fixed size buffer, so memory use
+/// does not grow with `length`.
+///
+/// # Arguments
+///
+/// * `src_path` - The path to the source file.
+/// * `dest_file` - The destination file, written at its current position.
+/// * `src_start` - The starting position in the source file bytes to copy from.
+/// * `length` - The length of the part in bytes to copy.
+/// * `buffer_size` - Bytes read and written at a time.
+/// * `progress` - Called with the bytes copied so far and `length` after every write.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation. Fails with `InvalidInput` if `buffer_size` is zero and with
+/// `UnexpectedEof` if the source ends before the part does.
+///
+pub fn copy_part_with<F>(
+    src_path: &Path,
+    dest_file: &mut File,
+    src_start: u64,
+    length: u64,
+    buffer_size: usize,
+    mut progress: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    if buffer_size == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "buffer size must not be 0",
+        ));
+    }
     let mut src_file = File::open(src_path)?;
     src_file.seek(SeekFrom::Start(src_start))?;
 
-    let mut buffer = vec![0; length as usize];
-    src_file.read_exact(&mut buffer)?;
+    let mut buffer = vec![0; length.min(buffer_size as u64) as usize];
+    let mut copied = 0;
+    while copied < length {
+        let len = (length - copied).min(buffer.len() as u64) as usize;
+        src_file.read_exact(&mut buffer[..len])?;
+        dest_file.write_all(&buffer[..len])?;
+        copied += len as u64;
+        progress(copied, length);
+    }
+
+    Ok(())
+}
+
+/// Copies `length` bytes from `src_offset` of the source file to
+/// `dest_offset` of the destination file.
+///
+/// Uses positional reads and writes (`pread`/`pwrite`), so neither file's
+/// cursor is touched and several jobs can copy disjoint ranges into the same
+/// destination file at once. Streams through a `DEFAULT_BUFFER_SIZE` buffer,
+/// see `copy_range_with`.
+///
+/// # Arguments
+///
+/// * `src_file` - The source file.
+/// * `dest_file` - The destination file.
+/// * `src_offset` - The position in the source file to copy from.
+/// * `dest_offset` - The position in the destination file to copy to.
+/// * `length` - The length of the range in bytes to copy.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn copy_range(
+    src_file: &File,
+    dest_file: &File,
+    src_offset: u64,
+    dest_offset: u64,
+    length: u64,
+) -> std::io::Result<()> {
+    copy_range_with(
+        src_file,
+        dest_file,
+        src_offset,
+        dest_offset,
+        length,
+        DEFAULT_BUFFER_SIZE,
+        |_, _| (),
+    )
+}
+
+/// Copies a range between files with positional I/O through a fixed size
+/// buffer.
+///
+/// # Arguments
+///
+/// * `src_file` - The source file.
+/// * `dest_file` - The destination file.
+/// * `src_offset` - The position in the source file to copy from.
+/// * `dest_offset` - The position in the destination file to copy to.
+/// * `length` - The length of the range in bytes to copy.
+/// * `buffer_size` - Bytes read and written at a time.
+/// * `progress` - Called with the bytes copied so far and `length` after every write.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation. Fails with `InvalidInput` if `buffer_size` is zero and with
+/// `UnexpectedEof` if the source ends before the range does.
+///
+pub fn copy_range_with<F>(
+    src_file: &File,
+    dest_file: &File,
+    src_offset: u64,
+    dest_offset: u64,
+    length: u64,
+    buffer_size: usize,
+    mut progress: F,
+) -> std::io::Result<()>
+where
+    F: FnMut(u64, u64),
+{
+    if buffer_size == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "buffer size must not be 0",
+        ));
+    }
 
-    dest_file.write_all(&buffer)?;
+    let mut buffer = vec![0; length.min(buffer_size as u64) as usize];
+    let mut copied = 0;
+    while copied < length {
+        let len = (length - copied).min(buffer.len() as u64) as usize;
+        src_file.read_exact_at(&mut buffer[..len], src_offset + copied)?;
+        dest_file.write_all_at(&buffer[..len], dest_offset + copied)?;
+        copied += len as u64;
+        progress(copied, length);
+    }
 
     Ok(())
 }
 
+/// Hashes `length` bytes of a file from `offset` on.
+///
+/// Uses positional reads like `copy_range`, so ranges of one file can be
+/// hashed concurrently. Reads through a `DEFAULT_BUFFER_SIZE` buffer.
+///
+/// # Arguments
+///
+/// * `file` - The file to hash.
+/// * `offset` - The position of the range in the file.
+/// * `length` - The length of the range in bytes.
+/// * `algorithm` - The hash algorithm.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation. Fails with `UnexpectedEof` if the file ends before the range does.
+///
+pub fn hash_range(
+    file: &File,
+    offset: u64,
+    length: u64,
+    algorithm: HashAlgorithm,
+) -> std::io::Result<Digest> {
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0; length.min(DEFAULT_BUFFER_SIZE as u64) as usize];
+    let mut hashed = 0;
+    while hashed < length {
+        let len = (length - hashed).min(buffer.len() as u64) as usize;
+        file.read_exact_at(&mut buffer[..len], offset + hashed)?;
+        hasher.update(&buffer[..len]);
+        hashed += len as u64;
+    }
+    Ok(hasher.finish())
+}
+
+/// Reads `buffer.len()` bytes of an open file starting at `src_start`.
+///
+/// # Arguments
+///
+/// * `src_file` - The source file.
+/// * `src_start` - The starting position in the source file bytes to read from.
+/// * `buffer` - The buffer to fill, its length is the number of bytes read.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn read_part(src_file: &mut File, src_start: u64, buffer: &mut [u8]) -> std::io::Result<()> {
+    src_file.seek(SeekFrom::Start(src_start))?;
+    src_file.read_exact(buffer)
+}
+
 /// Creates a file with the specified name and size. written with 's'(1B)
 ///
+/// Streams through a `DEFAULT_BUFFER_SIZE` buffer, see `create_file_with`.
+///
 /// # Arguments
 ///
 /// * `filename` - The name of the file to create.
@@ -44,18 +244,49 @@ pub fn copy_part(
 /// Returns an `std::io::Result` indicating the success or failure of the operation.
 ///
 pub fn create_file(filename: &str, size: u64) -> Result<(), Error> {
-    let mut file = match File::create(filename) {
-        Ok(f) => f,
-        Err(e) => return Err(e),
-    };
+    create_file_with(filename, size, DEFAULT_BUFFER_SIZE, |_, _| ())
+}
 
-    let dummy = vec![0x73; size as usize];
+/// Creates a file of 's' bytes, writing a fixed size buffer at a time.
+///
+/// # Arguments
+///
+/// * `filename` - The name of the file to create.
+/// * `size` - The size of the file to create in bytes.
+/// * `buffer_size` - Bytes written at a time.
+/// * `progress` - Called with the bytes written so far and `size` after every write.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation. Fails with `InvalidInput` if `buffer_size` is zero.
+///
+pub fn create_file_with<F>(
+    filename: &str,
+    size: u64,
+    buffer_size: usize,
+    mut progress: F,
+) -> Result<(), Error>
+where
+    F: FnMut(u64, u64),
+{
+    if buffer_size == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "buffer size must not be 0",
+        ));
+    }
+    let mut file = File::create(filename)?;
 
-    let _ = match file.write_all(&dummy) {
-        Ok(_) => (),
-        Err(e) => return Err(e),
-    };
-    return Ok(());
+    let dummy = vec![0x73; size.min(buffer_size as u64) as usize];
+    let mut written = 0;
+    while written < size {
+        let len = (size - written).min(dummy.len() as u64) as usize;
+        file.write_all(&dummy[..len])?;
+        written += len as u64;
+        progress(written, size);
+    }
+    Ok(())
 }
 
 /// Reads the contents of a file into a vector of bytes.
@@ -82,3 +313,164 @@ pub fn read_file(filename: &str) -> Result<Vec<u8>, Error> {
     };
     return Ok(buffer);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, OpenOptions};
+    use std::path::PathBuf;
+
+    /// A fresh temporary directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            let dir = std::env::temp_dir().join(format!("xfs-file-io-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        fn file(&self, name: &str, data: &[u8]) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, data).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open_rw(path: &Path) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .unwrap()
+    }
+
+    #[test]
+    fn copy_part_streams_from_the_offset_to_the_cursor() {
+        let dir = TempDir::new();
+        let src = dir.file("src", b"0123456789");
+        let dest = dir.file("dest", b"ab");
+        let mut dest_file = open_rw(&dest);
+        dest_file.seek(SeekFrom::End(0)).unwrap();
+
+        let mut calls = Vec::new();
+        copy_part_with(&src, &mut dest_file, 3, 5, 2, |done, total| {
+            calls.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(calls, [(2, 5), (4, 5), (5, 5)]);
+        copy_part(&src, &mut dest_file, 0, 1).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"ab345670");
+    }
+
+    #[test]
+    fn copy_part_rejects_a_zero_buffer_and_a_short_source() {
+        let dir = TempDir::new();
+        let src = dir.file("src", b"0123456789");
+        let mut dest_file = open_rw(&dir.file("dest", b""));
+
+        let e = copy_part_with(&src, &mut dest_file, 0, 1, 0, |_, _| ()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let mut calls = 0;
+        let e = copy_part_with(&src, &mut dest_file, 8, 5, 2, |_, _| calls += 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        // The whole buffer before the end was copied and reported.
+        assert_eq!(calls, 1);
+        assert_eq!(fs::read(dir.0.join("dest")).unwrap(), b"89");
+    }
+
+    #[test]
+    fn create_file_writes_size_bytes_in_buffer_steps() {
+        let dir = TempDir::new();
+        let path = dir.0.join("created");
+        let name = path.to_str().unwrap();
+
+        let mut calls = Vec::new();
+        create_file_with(name, 10, 4, |done, total| calls.push((done, total))).unwrap();
+        assert_eq!(calls, [(4, 10), (8, 10), (10, 10)]);
+        assert_eq!(fs::read(&path).unwrap(), b"ssssssssss");
+
+        create_file(name, 0).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        let missing = dir.0.join("missing");
+        let e = create_file_with(missing.to_str().unwrap(), 1, 0, |_, _| ()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn copy_range_fills_disjoint_ranges_without_moving_cursors() {
+        let dir = TempDir::new();
+        let mut src_file = open_rw(&dir.file("src", b"0123456789"));
+        let dest = dir.file("dest", b"");
+        let mut dest_file = open_rw(&dest);
+        dest_file.set_len(10).unwrap();
+
+        // The upper half lands first, as parallel jobs may finish in any order.
+        let mut calls = Vec::new();
+        copy_range_with(&src_file, &dest_file, 6, 6, 4, 3, |done, total| {
+            calls.push((done, total))
+        })
+        .unwrap();
+        assert_eq!(calls, [(3, 4), (4, 4)]);
+        copy_range(&src_file, &dest_file, 0, 0, 6).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(src_file.stream_position().unwrap(), 0);
+        assert_eq!(dest_file.stream_position().unwrap(), 0);
+
+        copy_range(&src_file, &dest_file, 0, 12, 2).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789\0\001");
+        copy_range_with(&src_file, &dest_file, 0, 0, 0, 1, |_, _| {
+            panic!("nothing copied")
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn copy_range_rejects_a_zero_buffer_and_a_short_source() {
+        let dir = TempDir::new();
+        let src_file = open_rw(&dir.file("src", b"0123456789"));
+        let dest_file = open_rw(&dir.file("dest", b""));
+
+        let e = copy_range_with(&src_file, &dest_file, 0, 0, 1, 0, |_, _| ()).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidInput);
+        let e = copy_range(&src_file, &dest_file, 8, 0, 3).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        let e = copy_range(&src_file, &dest_file, 11, 0, 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn hash_range_matches_the_digest_of_the_bytes() {
+        let dir = TempDir::new();
+        // Spans several buffers.
+        let data: Vec<u8> = (0..DEFAULT_BUFFER_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let file = File::open(dir.file("src", &data)).unwrap();
+        let len = data.len() as u64;
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(
+                hash_range(&file, 0, len, algorithm).unwrap(),
+                algorithm.digest(&data)
+            );
+            assert_eq!(
+                hash_range(&file, 7, 1000, algorithm).unwrap(),
+                algorithm.digest(&data[7..1007])
+            );
+            assert_eq!(
+                hash_range(&file, len, 0, algorithm).unwrap(),
+                algorithm.digest(b"")
+            );
+            let e = hash_range(&file, len - 1, 2, algorithm).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as _, Sha256};
+use std::fmt;
+use std::str::FromStr;
+use xxhash_rust::xxh64::Xxh64;
+
+/// Checksum algorithms for transfers and file copies.
+///
+/// CRC32C is the cheapest and catches transmission errors, xxHash64 is as
+/// fast with fewer collisions, SHA-256 is slow but cryptographically strong.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum HashAlgorithm {
+    #[default]
+    #[serde(rename = "crc32c")]
+    Crc32c,
+    #[serde(rename = "xxh64")]
+    XxHash64,
+    #[serde(rename = "sha256")]
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub const ALL: [HashAlgorithm; 3] = [
+        HashAlgorithm::Crc32c,
+        HashAlgorithm::XxHash64,
+        HashAlgorithm::Sha256,
+    ];
+
+    /// Name used in config values and on the wire.
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Crc32c => "crc32c",
+            HashAlgorithm::XxHash64 => "xxh64",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// Length of a digest in bytes.
+    pub fn digest_len(self) -> usize {
+        match self {
+            HashAlgorithm::Crc32c => 4,
+            HashAlgorithm::XxHash64 => 8,
+            HashAlgorithm::Sha256 => 32,
+        }
+    }
+
+    /// Start an incremental hash.
+    pub fn hasher(self) -> Hasher {
+        let state = match self {
+            HashAlgorithm::Crc32c => State::Crc32c(0),
+            HashAlgorithm::XxHash64 => State::XxHash64(Xxh64::new(0)),
+            HashAlgorithm::Sha256 => State::Sha256(Sha256::new()),
+        };
+        Hasher { state }
+    }
+
+    /// Hash `bytes` in one go.
+    pub fn digest(self, bytes: &[u8]) -> Digest {
+        let mut hasher = self.hasher();
+        hasher.update(bytes);
+        hasher.finish()
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        HashAlgorithm::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| "expected crc32c, xxh64 or sha256".to_string())
+    }
+}
+
+enum State {
+    Crc32c(u32),
+    XxHash64(Xxh64),
+    Sha256(Sha256),
+}
+
+/// Incremental hash of a byte stream, fed piece by piece with `update`.
+pub struct Hasher {
+    state: State,
+}
+
+impl Hasher {
+    pub fn update(&mut self, bytes: &[u8]) {
+        match &mut self.state {
+            State::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, bytes),
+            State::XxHash64(xxh) => xxh.update(bytes),
+            State::Sha256(sha) => sha.update(bytes),
+        }
+    }
+
+    pub fn finish(self) -> Digest {
+        let (algorithm, bytes) = match self.state {
+            State::Crc32c(crc) => (HashAlgorithm::Crc32c, crc.to_be_bytes().to_vec()),
+            State::XxHash64(xxh) => (HashAlgorithm::XxHash64, xxh.digest().to_be_bytes().to_vec()),
+            State::Sha256(sha) => (HashAlgorithm::Sha256, sha.finalize().to_vec()),
+        };
+        Digest { algorithm, bytes }
+    }
+}
+
+/// A finished hash. Displays as lowercase hex, the format of
+/// `TransferComplete::checksum`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digest {
+    algorithm: HashAlgorithm,
+    bytes: Vec<u8>,
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    /// Big-endian digest bytes, `algorithm().digest_len()` of them.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.bytes {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{data_chunk, FrameDecoder, FrameEncoder, MsgOpcode};
+    use std::io;
+
+    fn hex(algorithm: HashAlgorithm, bytes: &[u8]) -> String {
+        algorithm.digest(bytes).to_string()
+    }
+
+    #[test]
+    fn crc32c_known_answers() {
+        // RFC 3720, B.4, and the usual "123456789" check value.
+        assert_eq!(hex(HashAlgorithm::Crc32c, b""), "00000000");
+        assert_eq!(hex(HashAlgorithm::Crc32c, b"123456789"), "e3069283");
+        assert_eq!(hex(HashAlgorithm::Crc32c, &[0u8; 32]), "8a9136aa");
+        assert_eq!(hex(HashAlgorithm::Crc32c, &[0xffu8; 32]), "62a8ab43");
+        let ascending: Vec<u8> = (0..32).collect();
+        assert_eq!(hex(HashAlgorithm::Crc32c, &ascending), "46dd794e");
+    }
+
+    #[test]
+    fn xxh64_known_answers() {
+        // Reference xxHash, seed 0.
+        assert_eq!(hex(HashAlgorithm::XxHash64, b""), "ef46db3751d8e999");
+        assert_eq!(hex(HashAlgorithm::XxHash64, b"a"), "d24ec4f1a98c6e5b");
+        assert_eq!(hex(HashAlgorithm::XxHash64, b"abc"), "44bc2cf5ad770999");
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        // FIPS 180-2 examples.
+        assert_eq!(
+            hex(HashAlgorithm::Sha256, b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex(HashAlgorithm::Sha256, b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            hex(
+                HashAlgorithm::Sha256,
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn incremental_hash_matches_one_shot() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i * 7) as u8).collect();
+        for algorithm in HashAlgorithm::ALL {
+            let mut hasher = algorithm.hasher();
+            for piece in data.chunks(4093) {
+                hasher.update(piece);
+            }
+            let digest = hasher.finish();
+            assert_eq!(digest, algorithm.digest(&data), "{}", algorithm);
+            assert_eq!(digest.as_bytes().len(), algorithm.digest_len());
+            assert_eq!(digest.algorithm(), algorithm);
+        }
+    }
+
+    #[test]
+    fn names_round_trip() {
+        for algorithm in HashAlgorithm::ALL {
+            assert_eq!(algorithm.to_string().parse(), Ok(algorithm));
+            assert_eq!(algorithm.name().to_uppercase().parse(), Ok(algorithm));
+            let json = serde_json::to_string(&algorithm).unwrap();
+            assert_eq!(json, format!("\"{}\"", algorithm.name()));
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn chunk_digest_round_trips_through_data_frame() {
+        let chunk: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        for algorithm in HashAlgorithm::ALL {
+            let mut encoder = FrameEncoder::new();
+            encoder.push_data(Some(algorithm), &chunk);
+            let mut wire = Vec::new();
+            encoder.flush_to(&mut wire).unwrap();
+
+            let mut decoder = FrameDecoder::new();
+            decoder.feed(&wire);
+            let frame = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(frame.opcode(), MsgOpcode::Data as u8);
+            assert_eq!(frame.payload.len(), algorithm.digest_len() + chunk.len());
+            assert_eq!(
+                &frame.payload[..algorithm.digest_len()],
+                algorithm.digest(&chunk).as_bytes()
+            );
+            assert_eq!(data_chunk(Some(algorithm), &frame.payload).unwrap(), chunk);
+
+            let mut corrupt = frame.payload.clone();
+            *corrupt.last_mut().unwrap() ^= 1;
+            let e = data_chunk(Some(algorithm), &corrupt).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{}", algorithm);
+            let short = &frame.payload[..algorithm.digest_len() - 1];
+            assert!(data_chunk(Some(algorithm), short).is_err(), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn v1_data_frame_carries_no_digest() {
+        let mut encoder = FrameEncoder::new();
+        encoder.push_data(None, b"raw");
+        let mut wire = Vec::new();
+        encoder.flush_to(&mut wire).unwrap();
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&wire);
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(frame.payload, b"raw");
+        assert_eq!(data_chunk(None, &frame.payload).unwrap(), b"raw");
+    }
+}
@@ -1,142 +1,133 @@
+mod config;
 mod connect;
 mod device;
 mod file;
+mod hash;
 mod packet;
+mod registry;
+mod server;
 mod threadpool;
 mod utils;
 
-use file::file_io::{copy_part, create_file, read_file};
-use threadpool::Message;
+use config::Config;
+use file::file_io::{copy_range_with, create_file_with, hash_range, DEFAULT_BUFFER_SIZE};
+use hash::HashAlgorithm;
 
-use packet::MsgPacket;
-use std::fs::{remove_file, File};
-use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
+use registry::service::RegistryServer;
+use registry::store::{DeviceStore, Liveness};
+use server::event_loop::EventLoop;
+use server::sandbox::Sandbox;
+use std::fs::{remove_file, File, OpenOptions};
+use std::net::TcpListener;
 use std::path::Path;
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{thread, time};
-use utils::register_sig_handler;
+use std::sync::Arc;
+use std::thread;
+use std::time::{self, Duration};
+use threadpool::{PoolOptions, ThreadPool};
+use utils::{register_reload_handler, register_sig_handler};
 
 fn main() {
-    run_loopback_server();
+    run_loopback_server(Config::load_or_exit());
 }
 
-/// Handle connection for both send and receive over TcpStream
-///
-/// # Arguments
-///
-/// * `stream` - TcpStream for connection
-/// * `rx` - Receiver for shutdown signal
-///
-fn handle_connection(mut stream: TcpStream, rx: Receiver<threadpool::Message>) {
-    stream
-        .set_read_timeout(Some(Duration::from_millis(100)))
-        .expect("Failed to set read timeout");
-    println!("Source Address: {}", (&stream).peer_addr().unwrap());
-    let client_id = (&stream).peer_addr().unwrap().port();
-    loop {
-        match rx.try_recv() {
-            Ok(msg) => match msg {
-                threadpool::Message::Terminate => {
-                    println!("Terminate received.");
-                    stream
-                        .shutdown(std::net::Shutdown::Both)
-                        .unwrap_or_else(|_| eprintln!("Failed to shutdown stream."));
-                    break;
-                }
-                _ => (),
-            },
-            Err(_) => {}
-        }
-        let mut buf: Vec<u8> = vec![0; 1024];
-        let recv_len = stream.read(&mut buf).unwrap_or_else(|_| 0);
-        if recv_len == 0 {
-            thread::sleep(Duration::from_millis(10));
-            continue;
-        }
-
-        let mut msg = String::new();
-        match serde_json::from_slice::<MsgPacket>(&buf[..recv_len]) {
-            Ok(packet) => {
-                println!("#{:>5}(msg): {}", packet.id, packet.data);
-                // msg = &packet.data;
-                let msg = serde_json::to_vec(&packet).unwrap();
-                stream.write(&msg).unwrap();
-                stream.flush().unwrap();
-            }
-            Err(_) => {
-                msg = String::from_utf8_lossy(&buf[..(recv_len)]).to_string();
-                println!("#{:>5}(str): {}", client_id, msg);
-                stream.write(msg.as_bytes()).unwrap();
-                stream.flush().unwrap();
-            }
+fn run_loopback_server(config: Config) {
+    let address = config.server_address();
+    let sandbox = Sandbox::new(&config.storage_root).expect("Failed to create storage root");
+    let listener = TcpListener::bind(&address).unwrap_or_else(|e| {
+        eprintln!("Failed to bind {}: {}", address, e);
+        std::process::exit(1);
+    });
+    println!("Server listening on {}", address);
+    println!("Storage root: {}", sandbox.root().display());
+    start_registry(&config);
+    let pool = ThreadPool::with_options(
+        config.pool_size,
+        PoolOptions {
+            capacity: Some(config.queue_capacity),
+            min_workers: Some(config.min_pool_size),
+            idle_timeout: Some(Duration::from_secs(config.pool_idle_timeout)),
+        },
+    );
+    let resize = pool.resize_handle();
+    register_reload_handler(move || match Config::load() {
+        Ok(config) => {
+            resize.resize(config.min_pool_size, config.pool_size);
+            println!(
+                "Threadpool resized to {}..={} workers",
+                config.min_pool_size, config.pool_size
+            );
         }
-
-        thread::sleep(Duration::from_millis(100));
-    }
-}
-
-fn run_loopback_server() {
-    const ADDRESS: &str = "127.0.0.1:8080";
-    let listener = TcpListener::bind(ADDRESS).unwrap();
-    println!("Server listening on {}", ADDRESS);
-    let pool = threadpool::ThreadPool::new(4);
-    let pool = Arc::new(Mutex::new(pool));
-    let pool_clone = Arc::clone(&pool);
-    let term_tx_list = Arc::new(Mutex::new(Vec::<Sender<Message>>::new()));
-    let term_tx_list_clone: Arc<Mutex<Vec<Sender<Message>>>> = Arc::clone(&term_tx_list);
+        Err(e) => eprintln!("Failed to reload config: {}", e),
+    });
+    let event_loop = EventLoop::new(listener, sandbox, pool).unwrap_or_else(|e| {
+        eprintln!("Failed to start event loop: {}", e);
+        std::process::exit(1);
+    });
+    let shutdown = event_loop.shutdown_handle();
     register_sig_handler(move || {
-        println!("Shutting down Threadpool on signal.");
-        for tx in term_tx_list_clone.lock().unwrap().iter() {
-            tx.send(threadpool::Message::Terminate).unwrap();
-        }
-        match pool_clone.try_lock() {
-            Ok(mut pool) => {
-                println!("Threadpool Joining....");
-                pool.join();
-                println!("Threadpool Joined!");
-            }
-            Err(_) => {
-                println!("Failed to aquire lock for threadpool. Unsafe Exiting..");
-            }
-        };
-        std::process::exit(0);
+        println!("Shutting down on signal.");
+        shutdown.shutdown();
     });
 
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let (tx, rx) = mpsc::channel();
-                term_tx_list.lock().unwrap().push(tx);
-                pool.lock().unwrap().execute(|| {
-                    handle_connection(stream, rx);
-                });
-            }
-            Err(e) => {
-                eprintln!("Connection failed: {}", e);
-            }
-        }
+    if let Err(e) = event_loop.run() {
+        eprintln!("Event loop failed: {}", e);
     }
 
     println!("Shutting down.");
 }
 
+/// Serve the device registry on `config.registry` from a background thread.
+/// The file server runs without it if it cannot start.
+fn start_registry(config: &Config) {
+    let store = match DeviceStore::open(&config.registry_store) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!(
+                "Failed to load device registry {}: {}",
+                config.registry_store.display(),
+                e
+            );
+            return;
+        }
+    };
+    let listener = match TcpListener::bind(&config.registry) {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind registry {}: {}", config.registry, e);
+            return;
+        }
+    };
+    println!(
+        "Device registry listening on {} ({} devices)",
+        config.registry,
+        store.list().len()
+    );
+    let store = Arc::new(store);
+    let events = store.subscribe();
+    thread::spawn(move || {
+        for event in events {
+            println!("Registry: {}", event);
+        }
+    });
+    let liveness = Liveness {
+        suspect_after: Duration::from_secs(config.suspect_timeout),
+        offline_after: Duration::from_secs(config.offline_timeout),
+    };
+    thread::spawn(move || RegistryServer::new(listener, store, liveness).run());
+}
+
 fn benchmark_file_io_perf() {
     const SRC_NAME: &str = "large_file_src.txt";
-    const DEST_NAME_PREFIX: &str = "large_file_destination";
+    const DEST_NAME: &str = "large_file_destination.txt";
     const SRC_SIZE: u64 = 1024 * 1024 * 1024 * 4; // 4GB (in bytes)
-    const THREAD_NUM: usize = 1;
+    const THREAD_NUM: usize = 4;
+    const PROGRESS_STEP: u64 = 1024 * 1024 * 1024; // report every 1GB
 
-    let threadpool = threadpool::ThreadPool::new(THREAD_NUM);
+    let threadpool = ThreadPool::new(THREAD_NUM);
 
-    for i in 0..THREAD_NUM {
-        let dest_name = format!("{DEST_NAME_PREFIX}_{i}.txt");
-        let dest_path = Path::new(dest_name.as_str());
-        if dest_path.exists() {
-            remove_file(dest_path).expect(format!("Failed to remove file: {dest_name}").as_str());
-        }
+    let dest_path = Path::new(DEST_NAME);
+    if dest_path.exists() {
+        remove_file(dest_path).expect("Failed to remove file: DEST_NAME");
     }
     println!(
         "Writing file of size {} MB with {} Threads...",
@@ -144,60 +135,128 @@ fn benchmark_file_io_perf() {
         THREAD_NUM
     );
 
-    let _ = create_file(SRC_NAME, SRC_SIZE);
+    let mut reported = 0;
+    let _ = create_file_with(SRC_NAME, SRC_SIZE, DEFAULT_BUFFER_SIZE, |done, total| {
+        if done - reported >= PROGRESS_STEP || done == total {
+            println!("Written {} / {} MB", done >> 20, total >> 20);
+            reported = done;
+        }
+    });
     let current_time = time::Instant::now();
 
     let src_path = Path::new(SRC_NAME);
+    let src_file = File::open(src_path).unwrap();
+    let file_size = src_file.metadata().unwrap().len();
 
-    let metadata = std::fs::metadata(&src_path).unwrap();
-    let file_size = metadata.len();
-
-    for i in 0..THREAD_NUM {
-        let offset = SRC_SIZE / THREAD_NUM as u64;
-        let dest_name = format!("{DEST_NAME_PREFIX}_{i}.txt");
-        let dest_path = Path::new(dest_name.as_str());
-        let mut dest_file = File::create(&dest_path).unwrap();
-        let start = i as u64 * offset;
-        let length = if i == THREAD_NUM - 1 {
-            file_size - start as u64
-        } else {
-            offset
-        };
-        // println!("Copying part from {} at length {}...", start, length);
-        threadpool.execute(move || {
-            copy_part(&src_path, &mut dest_file, start, length).expect("Failed to copy part");
-        });
-    }
-    drop(threadpool);
+    // Every part is written in place, so the destination is preallocated once
+    // and shared by all jobs.
+    let dest_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(dest_path)
+        .unwrap();
+    dest_file.set_len(file_size).unwrap();
+
+    let offset = file_size / THREAD_NUM as u64;
+    let parts: Vec<(u64, u64)> = (0..THREAD_NUM)
+        .map(|i| {
+            let start = i as u64 * offset;
+            let length = if i == THREAD_NUM - 1 {
+                file_size - start
+            } else {
+                offset
+            };
+            (start, length)
+        })
+        .collect();
+    // Scoped jobs borrow both files.
+    threadpool.scope(|scope| {
+        let handles: Vec<_> = parts
+            .iter()
+            .enumerate()
+            .map(|(i, &(start, length))| {
+                let (src_file, dest_file) = (&src_file, &dest_file);
+                scope.execute(move || {
+                    let mut reported = 0;
+                    copy_range_with(
+                        src_file,
+                        dest_file,
+                        start,
+                        start,
+                        length,
+                        DEFAULT_BUFFER_SIZE,
+                        |done, total| {
+                            if done - reported >= PROGRESS_STEP || done == total {
+                                println!("Part {}: copied {} / {} MB", i, done >> 20, total >> 20);
+                                reported = done;
+                            }
+                        },
+                    )
+                })
+            })
+            .collect();
+        for (i, handle) in handles.into_iter().enumerate() {
+            match handle.join() {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => eprintln!("Failed to copy part {}: {}", i, e),
+                Err(e) => eprintln!("Copy part {} did not finish: {}", i, e),
+            }
+        }
+    });
     println!("Elapsed time: {} msec", current_time.elapsed().as_millis());
 
-    let mut dest_len_sum = 0;
-    let mut dest_lens = Vec::<usize>::new();
-    let src = read_file(SRC_NAME).unwrap();
-    let src_len = src.len();
-    for i in 0..THREAD_NUM {
-        let dest_name = format!("{DEST_NAME_PREFIX}_{i}.txt");
-        let dest = read_file(dest_name.as_str()).unwrap();
-        dest_lens.push(dest.len());
-        dest_len_sum += dest.len();
+    // Every part is hashed in the source and the destination with each
+    // algorithm, which also measures their throughput.
+    let mut failed = 0;
+    for algorithm in HashAlgorithm::ALL {
+        let current_time = time::Instant::now();
+        let mismatches = threadpool.scope(|scope| {
+            let handles: Vec<_> = parts
+                .iter()
+                .map(|&(start, length)| {
+                    let (src_file, dest_file) = (&src_file, &dest_file);
+                    scope.execute(move || {
+                        let src = hash_range(src_file, start, length, algorithm)?;
+                        let dest = hash_range(dest_file, start, length, algorithm)?;
+                        Ok::<_, std::io::Error>((src, dest))
+                    })
+                })
+                .collect();
+            let mut mismatches = 0;
+            for (i, handle) in handles.into_iter().enumerate() {
+                match handle.join() {
+                    Ok(Ok((src, dest))) if src == dest => (),
+                    Ok(Ok((src, dest))) => {
+                        println!("Part {} differs: {} {} != {}", i, algorithm, src, dest);
+                        mismatches += 1;
+                    }
+                    Ok(Err(e)) => {
+                        println!("Failed to hash part {}: {}", i, e);
+                        mismatches += 1;
+                    }
+                    Err(e) => {
+                        println!("Hashing part {} did not finish: {}", i, e);
+                        mismatches += 1;
+                    }
+                }
+            }
+            mismatches
+        });
+        println!(
+            "Verified with {} in {} msec",
+            algorithm,
+            current_time.elapsed().as_millis()
+        );
+        failed += mismatches;
     }
-    if src_len == dest_len_sum {
+    if failed == 0 {
         println!("File copied successfully");
     } else {
-        println!(
-            "File copy failed : src_len: {}, dest_len_sum: {}",
-            src_len, dest_len_sum
-        );
-        for i in 0..THREAD_NUM {
-            println!("dest[{}]: {}", i, dest_lens[i]);
-        }
-        println!("File difference: {}B", src_len - dest_len_sum);
+        println!("File copy failed : {} mismatched parts", failed);
     }
 
     remove_file(src_path).expect("Failed to remove file: SRC_NAME");
-    for i in 0..THREAD_NUM {
-        let dest_name = format!("{DEST_NAME_PREFIX}_{i}.txt");
-        let dest_path = Path::new(dest_name.as_str());
-        remove_file(dest_path).expect(format!("Failed to remove file: {dest_name}").as_str());
-    }
+    remove_file(dest_path).expect("Failed to remove file: DEST_NAME");
 }
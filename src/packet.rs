@@ -1,34 +1,120 @@
+use crate::hash::HashAlgorithm;
+use crate::threadpool::PoolStats;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{self, Read, Write};
 
-#[derive(Serialize, Deserialize)]
+/// Magic bytes at the start of every frame ("XF").
+pub const FRAME_MAGIC: [u8; 2] = [0x58, 0x46];
+/// Highest wire protocol version this build speaks.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// Oldest wire protocol version this build still accepts.
+pub const MIN_PROTOCOL_VERSION: u8 = 1;
+/// First protocol version whose `Data` frames start with a digest of the chunk.
+pub const CHUNK_CHECKSUM_VERSION: u8 = 2;
+/// magic(2) + version(1) + opcode(1) + payload length(4, big-endian)
+pub const FRAME_HEADER_LEN: usize = 8;
+/// Upper bound for a single frame payload, protects against bogus length fields.
+pub const MAX_PAYLOAD_LEN: u32 = 16 * 1024 * 1024;
+/// Sender id used by the server in every reply.
+pub const SERVER_ID: &str = "server";
+/// Size of the raw file chunk carried by a single `Data` frame.
+pub const DATA_CHUNK_LEN: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MsgOpcode {
     Handshake = 0,
     PlainMsg = 1,
     Terminate = 2,
+    Error = 3,
+    /// Start of an upload, followed by `Data` frames.
+    Put = 4,
+    /// Raw file bytes, the frame payload is not a `MsgPacket`. From
+    /// `CHUNK_CHECKSUM_VERSION` on the bytes follow a digest of themselves.
+    Data = 5,
+    Progress = 6,
+    /// Final acknowledgement of a transfer.
+    Complete = 7,
+    /// Download request, answered with a `Get` reply, `Data` frames and `Complete`.
+    Get = 8,
+    List = 9,
+    Stat = 10,
+    /// Mutating operations, acknowledged by echoing the request body
+    /// with the same opcode.
+    Delete = 11,
+    Rename = 12,
+    Mkdir = 13,
+    Rmdir = 14,
+    Truncate = 15,
+    /// Server status request with an empty body, answered with a `StatusReply`.
+    Status = 16,
+}
+
+impl TryFrom<u8> for MsgOpcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        match value {
+            0 => Ok(MsgOpcode::Handshake),
+            1 => Ok(MsgOpcode::PlainMsg),
+            2 => Ok(MsgOpcode::Terminate),
+            3 => Ok(MsgOpcode::Error),
+            4 => Ok(MsgOpcode::Put),
+            5 => Ok(MsgOpcode::Data),
+            6 => Ok(MsgOpcode::Progress),
+            7 => Ok(MsgOpcode::Complete),
+            8 => Ok(MsgOpcode::Get),
+            9 => Ok(MsgOpcode::List),
+            10 => Ok(MsgOpcode::Stat),
+            11 => Ok(MsgOpcode::Delete),
+            12 => Ok(MsgOpcode::Rename),
+            13 => Ok(MsgOpcode::Mkdir),
+            14 => Ok(MsgOpcode::Rmdir),
+            15 => Ok(MsgOpcode::Truncate),
+            16 => Ok(MsgOpcode::Status),
+            unknown => Err(unknown),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MsgPacket {
     pub len: usize,
     pub id: String,
-    // opcode: MsgOpcode,
+    pub opcode: MsgOpcode,
     pub data: String,
 }
 impl MsgPacket {
     pub fn new(id: &str, data: &str) -> Self {
+        MsgPacket::with_opcode(id, MsgOpcode::PlainMsg, data)
+    }
+
+    pub fn with_opcode(id: &str, opcode: MsgOpcode, data: &str) -> Self {
         MsgPacket {
             len: id.len() + data.len(),
             id: id.to_string(),
-            // opcode: MsgOpcode::PlainMsg,
+            opcode,
             data: data.to_string(),
         }
     }
 
+    /// Build a packet whose `data` is the JSON encoding of `body`.
+    pub fn with_body<T: Serialize>(id: &str, opcode: MsgOpcode, body: &T) -> Self {
+        let data = serde_json::to_string(body).expect("Failed to serialize packet body");
+        MsgPacket::with_opcode(id, opcode, &data)
+    }
+
+    /// Decode the JSON body carried in `data`.
+    pub fn body<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_str(&self.data)
+    }
+
     pub fn dummy() -> Self {
         MsgPacket {
             len: 0,
             id: String::new(),
-            // opcode: MsgOpcode::PlainMsg,
+            opcode: MsgOpcode::PlainMsg,
             data: String::new(),
         }
     }
@@ -39,3 +125,751 @@ impl MsgPacket {
         base
     }
 }
+
+/// Body of a `Handshake` request sent by the client.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandshakeRequest {
+    pub min_version: u8,
+    pub max_version: u8,
+    /// Requested client id. When empty the server uses `node_id`, or
+    /// assigns one if that is empty too.
+    pub client_id: String,
+    /// Persistent `NodeIdentity::id` of the client machine, empty for
+    /// clients without one.
+    #[serde(default)]
+    pub node_id: String,
+    /// `NodeIdentity::public_key`, `node_id` must be its fingerprint.
+    #[serde(default)]
+    pub public_key: Option<String>,
+}
+
+/// Body of the server's `Handshake` reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandshakeReply {
+    pub version: u8,
+    /// Empty while a `challenge` is pending.
+    pub client_id: String,
+    /// Set when the request carried a `public_key`: hex encoded bytes the
+    /// client signs and returns in a second `Handshake` packet, a
+    /// `HandshakeProof`. The handshake completes with the next reply.
+    #[serde(default)]
+    pub challenge: Option<String>,
+}
+
+/// Body of the client's second `Handshake` packet, answering a challenge.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HandshakeProof {
+    /// Hex encoded Ed25519 signature of the challenge.
+    pub signature: String,
+}
+
+/// Body of a `Put` request. `size` bytes of `Data` frames follow it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PutRequest {
+    /// Destination path relative to the server storage root.
+    pub path: String,
+    pub size: u64,
+    /// Position of the range in the destination, only with `file_size`.
+    #[serde(default)]
+    pub offset: u64,
+    /// Set by parallel uploads: the full size of the destination file,
+    /// which is written in place instead of through a `.part` file.
+    #[serde(default)]
+    pub file_size: Option<u64>,
+    /// Algorithm of the chunk and transfer checksums.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+}
+
+/// Body of a `Get` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetRequest {
+    /// Source path relative to the server storage root.
+    pub path: String,
+    pub offset: u64,
+    /// Number of bytes to read, `None` reads to the end of the file.
+    pub length: Option<u64>,
+    /// Algorithm of the chunk and transfer checksums.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Ask for `TransferComplete::file_checksum`, so a resumed download
+    /// can verify the part it already had.
+    #[serde(default)]
+    pub file_checksum: bool,
+}
+
+/// Body of the server's `Get` reply, `length` bytes of `Data` frames follow it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GetReply {
+    pub path: String,
+    pub offset: u64,
+    pub length: u64,
+    pub file_size: u64,
+    /// The requested checksum algorithm, echoed back.
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+}
+
+/// Body of a `List`, `Stat` or `Delete` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PathRequest {
+    /// Path relative to the server storage root, empty for the root itself.
+    pub path: String,
+}
+
+/// Body of a `Rename` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RenameRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Body of a `Mkdir` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MkdirRequest {
+    pub path: String,
+    /// Create missing parent directories as well.
+    pub parents: bool,
+}
+
+/// Body of a `Rmdir` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RmdirRequest {
+    pub path: String,
+    /// Remove the directory together with its contents.
+    pub recursive: bool,
+}
+
+/// Body of a `Truncate` request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TruncateRequest {
+    pub path: String,
+    pub size: u64,
+}
+
+/// Body of a `Status` reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusReply {
+    pub pool: PoolStats,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Other,
+}
+
+/// Metadata of one file, the body of a `Stat` reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FileStat {
+    pub name: String,
+    pub size: u64,
+    pub file_type: FileType,
+    /// Unix permission bits, e.g. `0o644`.
+    pub permissions: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// Body of a `List` reply.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ListReply {
+    pub path: String,
+    pub entries: Vec<FileStat>,
+}
+
+/// Body of a `Progress` report.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferProgress {
+    pub path: String,
+    pub bytes: u64,
+    pub total: u64,
+}
+
+/// Body of a `Complete` acknowledgement.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransferComplete {
+    pub path: String,
+    pub bytes: u64,
+    /// Digest of the transferred bytes, lowercase hex.
+    pub checksum: String,
+    #[serde(default)]
+    pub hash: HashAlgorithm,
+    /// Digest of the whole source file, if `GetRequest::file_checksum`
+    /// asked for it.
+    #[serde(default)]
+    pub file_checksum: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    UnknownOpcode,
+    MalformedPacket,
+    HandshakeRequired,
+    VersionMismatch,
+    NoActiveTransfer,
+    NotFound,
+    PermissionDenied,
+    AlreadyExists,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    InvalidInput,
+    InvalidData,
+    StorageFull,
+    /// The path leaves the server's export root.
+    PathRejected,
+    /// Any other `std::io::ErrorKind`.
+    IoError,
+}
+
+impl From<io::ErrorKind> for ErrorCode {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => ErrorCode::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            io::ErrorKind::AlreadyExists => ErrorCode::AlreadyExists,
+            io::ErrorKind::NotADirectory => ErrorCode::NotADirectory,
+            io::ErrorKind::IsADirectory => ErrorCode::IsADirectory,
+            io::ErrorKind::DirectoryNotEmpty => ErrorCode::DirectoryNotEmpty,
+            io::ErrorKind::InvalidInput => ErrorCode::InvalidInput,
+            io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => ErrorCode::InvalidData,
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => ErrorCode::StorageFull,
+            _ => ErrorCode::IoError,
+        }
+    }
+}
+
+/// Body of an `Error` packet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorReply {
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn to_packet(&self) -> MsgPacket {
+        MsgPacket::with_body(SERVER_ID, MsgOpcode::Error, self)
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameError {
+    BadMagic([u8; 2]),
+    UnsupportedVersion(u8),
+    PayloadTooLarge(u32),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::BadMagic(magic) => write!(f, "bad frame magic: {:02x?}", magic),
+            FrameError::UnsupportedVersion(v) => write!(f, "unsupported protocol version: {}", v),
+            FrameError::PayloadTooLarge(len) => {
+                write!(f, "payload too large: {}B (max {}B)", len, MAX_PAYLOAD_LEN)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for io::Error {
+    fn from(e: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub opcode: u8,
+    pub payload_len: u32,
+}
+
+impl FrameHeader {
+    pub fn new(opcode: u8, payload_len: u32) -> Self {
+        FrameHeader {
+            version: PROTOCOL_VERSION,
+            opcode,
+            payload_len,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; FRAME_HEADER_LEN] {
+        let len = self.payload_len.to_be_bytes();
+        [
+            FRAME_MAGIC[0],
+            FRAME_MAGIC[1],
+            self.version,
+            self.opcode,
+            len[0],
+            len[1],
+            len[2],
+            len[3],
+        ]
+    }
+
+    /// Parse and validate a frame header.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Exactly `FRAME_HEADER_LEN` bytes from the wire.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FrameError` if the magic, version or payload length is invalid.
+    ///
+    pub fn from_bytes(buf: &[u8; FRAME_HEADER_LEN]) -> Result<Self, FrameError> {
+        let magic = [buf[0], buf[1]];
+        if magic != FRAME_MAGIC {
+            return Err(FrameError::BadMagic(magic));
+        }
+        let version = buf[2];
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let payload_len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if payload_len > MAX_PAYLOAD_LEN {
+            return Err(FrameError::PayloadTooLarge(payload_len));
+        }
+        Ok(FrameHeader {
+            version,
+            opcode: buf[3],
+            payload_len,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub header: FrameHeader,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn opcode(&self) -> u8 {
+        self.header.opcode
+    }
+}
+
+/// Serializes frames onto a byte stream.
+pub struct FrameEncoder {
+    buf: Vec<u8>,
+    /// Version written into queued frame headers.
+    version: u8,
+}
+
+impl Default for FrameEncoder {
+    fn default() -> Self {
+        FrameEncoder::new()
+    }
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        FrameEncoder {
+            buf: Vec::new(),
+            version: PROTOCOL_VERSION,
+        }
+    }
+
+    /// Write `version` into the headers of frames queued from now on, so a
+    /// peer that negotiated an older protocol accepts them.
+    pub fn set_version(&mut self, version: u8) {
+        self.version = version;
+    }
+
+    /// Queue a frame, it is sent on the next `flush_to`.
+    ///
+    /// # Arguments
+    ///
+    /// * `opcode` - Opcode written into the frame header.
+    /// * `payload` - Frame body, at most `MAX_PAYLOAD_LEN` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the payload is larger than `MAX_PAYLOAD_LEN`.
+    ///
+    pub fn push(&mut self, opcode: u8, payload: &[u8]) {
+        assert!(payload.len() <= MAX_PAYLOAD_LEN as usize);
+        self.push_header(opcode, payload.len());
+        self.buf.extend_from_slice(payload);
+    }
+
+    /// Queue a `Data` frame. With a `checksum` algorithm the digest of
+    /// `chunk` is written in front of it, see `data_chunk`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if digest and chunk are larger than `MAX_PAYLOAD_LEN`.
+    ///
+    pub fn push_data(&mut self, checksum: Option<HashAlgorithm>, chunk: &[u8]) {
+        let digest = checksum.map(|algorithm| algorithm.digest(chunk));
+        let digest = digest.as_ref().map_or(&[][..], |digest| digest.as_bytes());
+        let payload_len = digest.len() + chunk.len();
+        assert!(payload_len <= MAX_PAYLOAD_LEN as usize);
+        self.push_header(MsgOpcode::Data as u8, payload_len);
+        self.buf.extend_from_slice(digest);
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Queue a packet, the frame opcode mirrors `packet.opcode`.
+    pub fn push_packet(&mut self, packet: &MsgPacket) {
+        let payload = serde_json::to_vec(packet).expect("Failed to serialize packet");
+        self.push(packet.opcode as u8, &payload);
+    }
+
+    /// Bytes queued and not yet flushed.
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Write every queued frame to `writer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` indicating the success or failure of the write.
+    ///
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        writer.write_all(&self.buf)?;
+        writer.flush()?;
+        self.buf.clear();
+        Ok(())
+    }
+
+    fn push_header(&mut self, opcode: u8, payload_len: usize) {
+        let header = FrameHeader {
+            version: self.version,
+            ..FrameHeader::new(opcode, payload_len as u32)
+        };
+        self.buf.extend_from_slice(&header.to_bytes());
+    }
+
+    /// Encode and write a single frame to `writer` immediately.
+    pub fn write_frame<W: Write>(
+        &mut self,
+        writer: &mut W,
+        opcode: u8,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        self.push(opcode, payload);
+        self.flush_to(writer)
+    }
+
+    /// Encode and write a single `Data` frame to `writer` immediately.
+    pub fn write_data<W: Write>(
+        &mut self,
+        writer: &mut W,
+        checksum: Option<HashAlgorithm>,
+        chunk: &[u8],
+    ) -> io::Result<()> {
+        self.push_data(checksum, chunk);
+        self.flush_to(writer)
+    }
+
+    /// Encode and write a single packet to `writer` immediately.
+    pub fn write_packet<W: Write>(&mut self, writer: &mut W, packet: &MsgPacket) -> io::Result<()> {
+        self.push_packet(packet);
+        self.flush_to(writer)
+    }
+}
+
+/// Take the file bytes out of a `Data` payload written by `push_data`.
+///
+/// # Arguments
+///
+/// * `checksum` - Algorithm of the leading digest, `None` for a payload
+///   without one.
+/// * `payload` - Payload of the `Data` frame.
+///
+/// # Errors
+///
+/// Returns `InvalidData` if the payload is shorter than the digest or the
+/// digest does not match the chunk.
+///
+pub fn data_chunk(checksum: Option<HashAlgorithm>, payload: &[u8]) -> io::Result<&[u8]> {
+    let Some(algorithm) = checksum else {
+        return Ok(payload);
+    };
+    if payload.len() < algorithm.digest_len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Data frame shorter than a {} digest", algorithm),
+        ));
+    }
+    let (digest, chunk) = payload.split_at(algorithm.digest_len());
+    let actual = algorithm.digest(chunk);
+    if actual.as_bytes() != digest {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} mismatch in {}B chunk: got {}, computed {}",
+                algorithm,
+                chunk.len(),
+                digest
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect::<String>(),
+                actual
+            ),
+        ));
+    }
+    Ok(chunk)
+}
+
+/// Reassembles frames from a byte stream.
+///
+/// Bytes may arrive in arbitrary pieces: a frame split across several reads
+/// is buffered until complete, and several frames in one read are returned
+/// one at a time by `next_frame`.
+#[derive(Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+
+impl FrameDecoder {
+    const READ_CHUNK: usize = 8 * 1024;
+
+    pub fn new() -> Self {
+        FrameDecoder { buf: Vec::new() }
+    }
+
+    /// Append raw bytes received from the peer.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Read once from `reader` into the internal buffer.
+    ///
+    /// # Returns
+    /// Number of bytes read, `0` means the peer closed the stream.
+    ///
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> io::Result<usize> {
+        let mut chunk = [0u8; Self::READ_CHUNK];
+        let len = reader.read(&mut chunk)?;
+        self.feed(&chunk[..len]);
+        Ok(len)
+    }
+
+    /// Pop the next complete frame, if one has been fully received.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `FrameError` on a malformed header. The stream can no longer be
+    /// resynchronized after that and the connection should be closed.
+    ///
+    pub fn next_frame(&mut self) -> Result<Option<Frame>, FrameError> {
+        if self.buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+        let mut raw_header = [0u8; FRAME_HEADER_LEN];
+        raw_header.copy_from_slice(&self.buf[..FRAME_HEADER_LEN]);
+        let header = FrameHeader::from_bytes(&raw_header)?;
+
+        let frame_len = FRAME_HEADER_LEN + header.payload_len as usize;
+        if self.buf.len() < frame_len {
+            return Ok(None);
+        }
+        let payload = self.buf[FRAME_HEADER_LEN..frame_len].to_vec();
+        self.buf.drain(..frame_len);
+        Ok(Some(Frame { header, payload }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(frames: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut encoder = FrameEncoder::new();
+        for (opcode, payload) in frames {
+            encoder.push(*opcode, payload);
+        }
+        let mut wire = Vec::new();
+        encoder.flush_to(&mut wire).unwrap();
+        wire
+    }
+
+    fn header(magic: [u8; 2], version: u8, payload_len: u32) -> Vec<u8> {
+        let mut raw = vec![magic[0], magic[1], version, MsgOpcode::Data as u8];
+        raw.extend_from_slice(&payload_len.to_be_bytes());
+        raw
+    }
+
+    #[test]
+    fn decodes_frame_split_across_reads() {
+        let wire = encode(&[(MsgOpcode::Data as u8, b"hello frame")]);
+        let mut decoder = FrameDecoder::new();
+        // Every split point, including inside the header.
+        for split in 0..wire.len() {
+            decoder.feed(&wire[..split]);
+            assert!(decoder.next_frame().unwrap().is_none(), "split {}", split);
+            decoder.feed(&wire[split..]);
+            let frame = decoder.next_frame().unwrap().unwrap();
+            assert_eq!(frame.opcode(), MsgOpcode::Data as u8);
+            assert_eq!(frame.header.version, PROTOCOL_VERSION);
+            assert_eq!(frame.payload, b"hello frame");
+            assert!(decoder.next_frame().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let wire = encode(&[(MsgOpcode::Complete as u8, b"ab")]);
+        let mut decoder = FrameDecoder::new();
+        let mut frames = Vec::new();
+        for byte in &wire {
+            decoder.feed(std::slice::from_ref(byte));
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].payload, b"ab");
+    }
+
+    #[test]
+    fn decodes_several_frames_from_one_read() {
+        let wire = encode(&[
+            (MsgOpcode::Data as u8, b"one"),
+            (MsgOpcode::Data as u8, b""),
+            (MsgOpcode::Complete as u8, b"three"),
+        ]);
+        let mut decoder = FrameDecoder::new();
+        assert_eq!(decoder.read_from(&mut wire.as_slice()).unwrap(), wire.len());
+        let payloads: Vec<Vec<u8>> = std::iter::from_fn(|| decoder.next_frame().unwrap())
+            .map(|frame| frame.payload)
+            .collect();
+        assert_eq!(payloads, [&b"one"[..], b"", b"three"]);
+    }
+
+    #[test]
+    fn keeps_partial_frame_after_complete_ones() {
+        let wire = encode(&[
+            (MsgOpcode::Data as u8, b"first"),
+            (MsgOpcode::Data as u8, b"second"),
+        ]);
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&wire[..wire.len() - 2]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, b"first");
+        assert!(decoder.next_frame().unwrap().is_none());
+        decoder.feed(&wire[wire.len() - 2..]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, b"second");
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&header([b'G', b'E'], PROTOCOL_VERSION, 0));
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::BadMagic([b'G', b'E']))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        for version in [0, PROTOCOL_VERSION + 1, u8::MAX] {
+            let mut decoder = FrameDecoder::new();
+            decoder.feed(&header(FRAME_MAGIC, version, 0));
+            assert!(
+                matches!(
+                    decoder.next_frame(),
+                    Err(FrameError::UnsupportedVersion(v)) if v == version
+                ),
+                "version {}",
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_every_supported_version() {
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            let mut encoder = FrameEncoder::new();
+            encoder.set_version(version);
+            encoder.push(MsgOpcode::Data as u8, b"x");
+            let mut wire = Vec::new();
+            encoder.flush_to(&mut wire).unwrap();
+            let mut decoder = FrameDecoder::new();
+            decoder.feed(&wire);
+            assert_eq!(
+                decoder.next_frame().unwrap().unwrap().header.version,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_length_before_payload_arrives() {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&header(FRAME_MAGIC, PROTOCOL_VERSION, MAX_PAYLOAD_LEN + 1));
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::PayloadTooLarge(len)) if len == MAX_PAYLOAD_LEN + 1
+        ));
+
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&header(FRAME_MAGIC, PROTOCOL_VERSION, u32::MAX));
+        assert!(matches!(
+            decoder.next_frame(),
+            Err(FrameError::PayloadTooLarge(u32::MAX))
+        ));
+    }
+
+    #[test]
+    fn accepts_max_payload_len_header() {
+        let mut decoder = FrameDecoder::new();
+        decoder.feed(&header(FRAME_MAGIC, PROTOCOL_VERSION, MAX_PAYLOAD_LEN));
+        assert!(decoder.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let header = FrameHeader::new(MsgOpcode::Put as u8, 1234);
+        assert_eq!(FrameHeader::from_bytes(&header.to_bytes()).unwrap(), header);
+    }
+
+    #[test]
+    fn io_error_kinds_map_to_error_codes() {
+        for (kind, code) in [
+            (io::ErrorKind::NotFound, ErrorCode::NotFound),
+            (io::ErrorKind::PermissionDenied, ErrorCode::PermissionDenied),
+            (io::ErrorKind::AlreadyExists, ErrorCode::AlreadyExists),
+            (io::ErrorKind::NotADirectory, ErrorCode::NotADirectory),
+            (io::ErrorKind::IsADirectory, ErrorCode::IsADirectory),
+            (
+                io::ErrorKind::DirectoryNotEmpty,
+                ErrorCode::DirectoryNotEmpty,
+            ),
+            (io::ErrorKind::InvalidInput, ErrorCode::InvalidInput),
+            (io::ErrorKind::InvalidData, ErrorCode::InvalidData),
+            (io::ErrorKind::UnexpectedEof, ErrorCode::InvalidData),
+            (io::ErrorKind::StorageFull, ErrorCode::StorageFull),
+            (io::ErrorKind::QuotaExceeded, ErrorCode::StorageFull),
+            (io::ErrorKind::Interrupted, ErrorCode::IoError),
+            (io::ErrorKind::Other, ErrorCode::IoError),
+        ] {
+            assert_eq!(ErrorCode::from(kind), code, "{:?}", kind);
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// Upper bound for a registry message payload, protects against bogus length fields.
+pub const MAX_MESSAGE_LEN: u32 = 1024 * 1024;
+
+/// First byte of a registry message: opcode(1) + payload length(4, big-endian) + JSON payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistryOpcode {
+    /// A `DeviceSpec` sent by `connect::send_device_spec`, not answered.
+    Report = 0,
+    /// Device query with a `null` body, answered with a `List` message
+    /// carrying every known `DeviceSpec`.
+    List = 1,
+    /// A `Heartbeat` of a device that reported before, not answered.
+    Heartbeat = 2,
+}
+
+impl TryFrom<u8> for RegistryOpcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        match value {
+            0 => Ok(RegistryOpcode::Report),
+            1 => Ok(RegistryOpcode::List),
+            2 => Ok(RegistryOpcode::Heartbeat),
+            unknown => Err(unknown),
+        }
+    }
+}
+
+/// Body of a `Heartbeat` message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Heartbeat {
+    pub id: String,
+}
+
+/// Write one message with a JSON encoded body.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the write.
+///
+pub fn write_message<W: Write, T: Serialize>(
+    writer: &mut W,
+    opcode: RegistryOpcode,
+    body: &T,
+) -> io::Result<()> {
+    let payload = serde_json::to_vec(body)?;
+    let mut message = Vec::with_capacity(5 + payload.len());
+    message.push(opcode as u8);
+    message.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    message.extend_from_slice(&payload);
+    writer.write_all(&message)?;
+    writer.flush()
+}
+
+/// Block until the next message arrives.
+///
+/// # Returns
+/// The opcode and raw payload, `None` if the peer closed the stream
+/// between two messages.
+///
+/// # Errors
+///
+/// Returns `InvalidData` for an unknown opcode or an oversized payload,
+/// `UnexpectedEof` if the stream ends inside a message.
+///
+pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Option<(RegistryOpcode, Vec<u8>)>> {
+    let mut opcode = [0u8; 1];
+    loop {
+        match reader.read(&mut opcode) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            // `read_exact` below retries on its own.
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    let opcode = RegistryOpcode::try_from(opcode[0]).map_err(|unknown| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown registry opcode: {}", unknown),
+        )
+    })?;
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("payload too large: {}B (max {}B)", len, MAX_MESSAGE_LEN),
+        ));
+    }
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(Some((opcode, payload)))
+}
+
+/// Decode the JSON body of a message.
+pub fn body<T: DeserializeOwned>(payload: &[u8]) -> io::Result<T> {
+    Ok(serde_json::from_slice(payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::spec::DeviceSpec;
+    use std::io::Cursor;
+
+    fn spec() -> DeviceSpec {
+        serde_json::from_value(serde_json::json!({
+            "id": "4b0a6c1e-6f0a-4c1e-9a54-0d3f1a2b3c4d",
+            "os": "Linux",
+            "os_version": "6",
+            "core_num": 4,
+            "ip_addr": "10.0.0.1",
+            "port": 7000,
+            "status": "Active",
+            "updated_at": "",
+        }))
+        .unwrap()
+    }
+
+    /// Fails every other read with `Interrupted`, like a read hit by a signal.
+    struct Interrupting<R> {
+        inner: R,
+        interrupt: bool,
+    }
+
+    impl<R: Read> Read for Interrupting<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            self.inner.read(buf)
+        }
+    }
+
+    #[test]
+    fn report_keeps_the_send_device_spec_wire_format() {
+        let device = spec();
+        // What `send_device_spec` wrote before the registry existed.
+        let serialized_data = serde_json::to_vec(&device).unwrap();
+        let mut original = vec![0u8];
+        original.extend_from_slice(&(serialized_data.len() as u32).to_be_bytes());
+        original.extend_from_slice(&serialized_data);
+
+        let mut wire = Vec::new();
+        write_message(&mut wire, RegistryOpcode::Report, &device).unwrap();
+        assert_eq!(wire, original);
+
+        let (opcode, payload) = read_message(&mut Cursor::new(original)).unwrap().unwrap();
+        assert_eq!(opcode, RegistryOpcode::Report);
+        assert_eq!(body::<DeviceSpec>(&payload).unwrap().id, device.id);
+    }
+
+    #[test]
+    fn read_message_retries_interrupted_reads() {
+        let mut wire = Vec::new();
+        let heartbeat = Heartbeat {
+            id: "a".to_string(),
+        };
+        write_message(&mut wire, RegistryOpcode::Heartbeat, &heartbeat).unwrap();
+        write_message(&mut wire, RegistryOpcode::List, &()).unwrap();
+        let mut reader = Interrupting {
+            inner: Cursor::new(wire),
+            interrupt: false,
+        };
+        for (expected, payload) in [
+            (RegistryOpcode::Heartbeat, &br#"{"id":"a"}"#[..]),
+            (RegistryOpcode::List, b"null"),
+        ] {
+            let (opcode, body) = read_message(&mut reader).unwrap().unwrap();
+            assert_eq!((opcode, &body[..]), (expected, payload));
+        }
+        assert!(read_message(&mut reader).unwrap().is_none());
+    }
+
+    #[test]
+    fn read_message_rejects_broken_messages() {
+        let read = |wire: &[u8]| read_message(&mut Cursor::new(wire.to_vec()));
+        assert!(read(&[]).unwrap().is_none());
+        assert_eq!(
+            read(&[9, 0, 0, 0, 0]).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let too_large = (MAX_MESSAGE_LEN + 1).to_be_bytes();
+        assert_eq!(
+            read(&[0, too_large[0], too_large[1], too_large[2], too_large[3]])
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(
+            read(&[0, 0, 0]).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        assert_eq!(
+            read(&[0, 0, 0, 0, 4, b'n']).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
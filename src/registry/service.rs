@@ -0,0 +1,219 @@
+use super::message::{body, read_message, write_message, Heartbeat, RegistryOpcode};
+use super::store::{DeviceStore, Liveness};
+use crate::device::identity;
+use crate::device::spec::DeviceSpec;
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Longest time between two liveness checks.
+const MAX_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accepts device connections for the `DeviceStore`. Every connection
+/// gets its own thread, devices keep theirs open to send heartbeats.
+pub struct RegistryServer {
+    listener: TcpListener,
+    store: Arc<DeviceStore>,
+    liveness: Liveness,
+}
+
+impl RegistryServer {
+    pub fn new(listener: TcpListener, store: Arc<DeviceStore>, liveness: Liveness) -> Self {
+        RegistryServer {
+            listener,
+            store,
+            liveness,
+        }
+    }
+
+    /// Accept connections until the listener fails. A second thread
+    /// demotes devices whose heartbeats stopped.
+    pub fn run(self) {
+        let store = Arc::clone(&self.store);
+        let liveness = self.liveness;
+        let interval = (liveness.suspect_after / 2).min(MAX_CHECK_INTERVAL);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            store.check(&liveness).unwrap_or_else(|e| {
+                eprintln!(
+                    "Failed to save device registry {}: {}",
+                    store.path().display(),
+                    e
+                )
+            });
+        });
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Registry failed to accept: {}", e);
+                    continue;
+                }
+            };
+            let peer = stream
+                .peer_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            let store = Arc::clone(&self.store);
+            let spawned = thread::Builder::new()
+                .name(format!("registry {}", peer))
+                .spawn(move || {
+                    if let Err(e) = handle_connection(stream, &store) {
+                        eprintln!("Registry connection {} failed: {}", peer, e);
+                    }
+                });
+            if let Err(e) = spawned {
+                eprintln!("Failed to spawn registry connection thread: {}", e);
+            }
+        }
+    }
+}
+
+/// Serve one device until it disconnects.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` for broken streams and malformed messages,
+/// the connection is closed after either.
+///
+fn handle_connection(mut stream: TcpStream, store: &DeviceStore) -> io::Result<()> {
+    while let Some((opcode, payload)) = read_message(&mut stream)? {
+        match opcode {
+            RegistryOpcode::Report => {
+                let device = body::<DeviceSpec>(&payload)?;
+                if let Some(public_key) = &device.public_key {
+                    if !identity::is_bound(&device.id, public_key) {
+                        eprintln!(
+                            "Registry: report of {} ignored, its id is not bound to its public key",
+                            device.id
+                        );
+                        continue;
+                    }
+                }
+                match store.report(device) {
+                    Ok(device) => println!(
+                        "Registry: {} ({} {}) at {}:{}",
+                        device.id, device.os, device.os_version, device.ip_addr, device.port
+                    ),
+                    Err(e) => eprintln!(
+                        "Failed to save device registry {}: {}",
+                        store.path().display(),
+                        e
+                    ),
+                }
+            }
+            RegistryOpcode::List => {
+                write_message(&mut stream, RegistryOpcode::List, &store.list())?
+            }
+            RegistryOpcode::Heartbeat => {
+                let heartbeat = body::<Heartbeat>(&payload)?;
+                match store.heartbeat(&heartbeat.id) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        eprintln!("Registry: heartbeat of unknown device {}", heartbeat.id)
+                    }
+                    Err(e) => eprintln!(
+                        "Failed to save device registry {}: {}",
+                        store.path().display(),
+                        e
+                    ),
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::identity::NodeIdentity;
+    use std::fs;
+    use std::net::Shutdown;
+    use std::path::PathBuf;
+
+    /// A store and a state directory in a fresh temporary directory,
+    /// removed on drop.
+    struct Fixture {
+        dir: PathBuf,
+        store: DeviceStore,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = std::env::temp_dir().join(format!("xfs-service-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let store = DeviceStore::open(&dir.join("registry.json")).unwrap();
+            Fixture { dir, store }
+        }
+
+        fn identity(&self, name: &str) -> NodeIdentity {
+            NodeIdentity::load_or_create(&self.dir.join(name), true).unwrap()
+        }
+
+        /// Send `reports` over one connection and serve it to the end.
+        fn serve(&self, reports: &[DeviceSpec]) {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            for device in reports {
+                write_message(&mut client, RegistryOpcode::Report, device).unwrap();
+            }
+            client.shutdown(Shutdown::Write).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            handle_connection(stream, &self.store).unwrap();
+        }
+
+        fn ids(&self) -> Vec<String> {
+            self.store
+                .list()
+                .into_iter()
+                .map(|device| device.id)
+                .collect()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn device(id: &str, public_key: Option<&str>) -> DeviceSpec {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "os": "Linux",
+            "os_version": "6",
+            "core_num": 4,
+            "ip_addr": "10.0.0.1",
+            "port": 7000,
+            "status": "",
+            "updated_at": "",
+            "public_key": public_key,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn report_with_an_unbound_public_key_is_ignored() {
+        let fx = Fixture::new();
+        let node = fx.identity("node");
+        let other = fx.identity("other");
+        let public_key = node.public_key.as_deref().unwrap();
+
+        fx.serve(&[
+            device(&other.id, Some(public_key)),
+            device("chosen-id", Some(public_key)),
+            device(&node.id, Some("not hex")),
+        ]);
+        assert!(fx.ids().is_empty());
+
+        fx.serve(&[device(&node.id, Some(public_key)), device("keyless", None)]);
+        let mut ids = fx.ids();
+        ids.sort();
+        let mut expected = vec![node.id.clone(), "keyless".to_string()];
+        expected.sort();
+        assert_eq!(ids, expected);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::device::spec::{self, DeviceSpec};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Liveness of a device, kept in `DeviceSpec::status`. Ordered from
+/// alive to gone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DeviceStatus {
+    /// Reported or sent a heartbeat within the suspect timeout.
+    Active,
+    /// Missed heartbeats for longer than the suspect timeout.
+    Suspect,
+    /// Missed heartbeats for longer than the offline timeout.
+    Offline,
+}
+
+impl DeviceStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceStatus::Active => "Active",
+            DeviceStatus::Suspect => "Suspect",
+            DeviceStatus::Offline => "Offline",
+        }
+    }
+}
+
+impl fmt::Display for DeviceStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for DeviceStatus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "Active" => Ok(DeviceStatus::Active),
+            "Suspect" => Ok(DeviceStatus::Suspect),
+            "Offline" => Ok(DeviceStatus::Offline),
+            other => Err(format!("unknown device status: {}", other)),
+        }
+    }
+}
+
+/// A status transition of one device, delivered to every `subscribe`r.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceEvent {
+    pub id: String,
+    /// `None` for a device reporting for the first time.
+    pub from: Option<DeviceStatus>,
+    pub to: DeviceStatus,
+    /// `DeviceSpec::updated_at` format.
+    pub at: String,
+}
+
+impl fmt::Display for DeviceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.from {
+            Some(from) => write!(f, "device {}: {} -> {}", self.id, from, self.to),
+            None => write!(f, "device {}: registered, {}", self.id, self.to),
+        }
+    }
+}
+
+/// Heartbeat timeouts of the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Liveness {
+    pub suspect_after: Duration,
+    pub offline_after: Duration,
+}
+
+impl Liveness {
+    /// Status of a device last heard from `silence` ago.
+    fn status(&self, silence: Duration) -> DeviceStatus {
+        if silence >= self.offline_after {
+            DeviceStatus::Offline
+        } else if silence >= self.suspect_after {
+            DeviceStatus::Suspect
+        } else {
+            DeviceStatus::Active
+        }
+    }
+}
+
+struct Entry {
+    device: DeviceSpec,
+    status: DeviceStatus,
+    /// Last report or heartbeat, the load time for devices read from disk.
+    last_seen: Instant,
+}
+
+/// Known devices keyed by `DeviceSpec::id`, saved to a JSON file after
+/// every report and status change so the registry survives restarts.
+/// Heartbeats only refresh the in-memory state.
+pub struct DeviceStore {
+    path: PathBuf,
+    devices: Mutex<BTreeMap<String, Entry>>,
+    subscribers: Mutex<Vec<Sender<DeviceEvent>>>,
+}
+
+impl DeviceStore {
+    /// Load the devices saved at `path`, or start empty if it does not exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - JSON file holding the list of devices.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if the file exists but cannot be read
+    /// or parsed.
+    ///
+    pub fn open(path: &Path) -> io::Result<DeviceStore> {
+        let loaded = Instant::now();
+        let devices = match fs::read(path) {
+            Ok(data) => serde_json::from_slice::<Vec<DeviceSpec>>(&data)?
+                .into_iter()
+                .map(|device| {
+                    let status = device.status.parse().unwrap_or(DeviceStatus::Suspect);
+                    let entry = Entry {
+                        device,
+                        status,
+                        last_seen: loaded,
+                    };
+                    (entry.device.id.clone(), entry)
+                })
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(DeviceStore {
+            path: path.to_path_buf(),
+            devices: Mutex::new(devices),
+            subscribers: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Receive every status transition from now on. The subscription ends
+    /// when the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Record a report, replacing the previous spec of the same id.
+    /// `status` and `updated_at` are set by the registry, not the device.
+    ///
+    /// # Returns
+    /// The stored spec.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if the devices could not be saved, the
+    /// report is kept in memory regardless.
+    ///
+    pub fn report(&self, mut device: DeviceSpec) -> io::Result<DeviceSpec> {
+        device.status = DeviceStatus::Active.to_string();
+        device.updated_at = spec::timestamp();
+        let mut devices = self.devices.lock().unwrap();
+        let from = devices.get(&device.id).map(|entry| entry.status);
+        devices.insert(
+            device.id.clone(),
+            Entry {
+                device: device.clone(),
+                status: DeviceStatus::Active,
+                last_seen: Instant::now(),
+            },
+        );
+        if from != Some(DeviceStatus::Active) {
+            self.publish(&device.id, from, DeviceStatus::Active, &device.updated_at);
+        }
+        self.save(&devices)?;
+        Ok(device)
+    }
+
+    /// Record a heartbeat of a reported device.
+    ///
+    /// # Returns
+    /// `false` if the id is unknown, the device has to report first.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if a status change could not be saved.
+    ///
+    pub fn heartbeat(&self, id: &str) -> io::Result<bool> {
+        let mut devices = self.devices.lock().unwrap();
+        let Some(entry) = devices.get_mut(id) else {
+            return Ok(false);
+        };
+        entry.last_seen = Instant::now();
+        entry.device.updated_at = spec::timestamp();
+        if entry.status == DeviceStatus::Active {
+            return Ok(true);
+        }
+        let from = entry.status;
+        entry.status = DeviceStatus::Active;
+        entry.device.status = DeviceStatus::Active.to_string();
+        let at = entry.device.updated_at.clone();
+        self.publish(id, Some(from), DeviceStatus::Active, &at);
+        self.save(&devices)?;
+        Ok(true)
+    }
+
+    /// Move devices that stopped sending heartbeats to `Suspect` or
+    /// `Offline`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if a status change could not be saved.
+    ///
+    pub fn check(&self, liveness: &Liveness) -> io::Result<()> {
+        let now = Instant::now();
+        let mut devices = self.devices.lock().unwrap();
+        let mut changed = false;
+        for entry in devices.values_mut() {
+            let status = liveness.status(now.duration_since(entry.last_seen));
+            // Silence only demotes, recovering takes a heartbeat.
+            if status <= entry.status {
+                continue;
+            }
+            let from = entry.status;
+            entry.status = status;
+            entry.device.status = status.to_string();
+            self.publish(&entry.device.id, Some(from), status, &spec::timestamp());
+            changed = true;
+        }
+        if changed {
+            self.save(&devices)?;
+        }
+        Ok(())
+    }
+
+    /// Every known device, ordered by id.
+    pub fn list(&self) -> Vec<DeviceSpec> {
+        self.devices
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.device.clone())
+            .collect()
+    }
+
+    fn publish(&self, id: &str, from: Option<DeviceStatus>, to: DeviceStatus, at: &str) {
+        let event = DeviceEvent {
+            id: id.to_string(),
+            from,
+            to,
+            at: at.to_string(),
+        };
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Write to a temporary file first, so a crash never leaves a torn file.
+    fn save(&self, devices: &BTreeMap<String, Entry>) -> io::Result<()> {
+        let list: Vec<&DeviceSpec> = devices.values().map(|entry| &entry.device).collect();
+        let data = serde_json::to_vec_pretty(&list)?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::Receiver;
+    use std::thread;
+
+    const LIVENESS: Liveness = Liveness {
+        suspect_after: Duration::from_millis(40),
+        offline_after: Duration::from_millis(120),
+    };
+
+    /// A store in a fresh temporary directory, removed on drop.
+    struct Fixture {
+        dir: PathBuf,
+        store: DeviceStore,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let dir = std::env::temp_dir().join(format!("xfs-registry-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let store = DeviceStore::open(&dir.join("registry.json")).unwrap();
+            Fixture { dir, store }
+        }
+
+        fn reopen(&self) -> DeviceStore {
+            DeviceStore::open(self.store.path()).unwrap()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// A schema version 1 spec, as sent by the oldest devices.
+    fn device(id: &str) -> DeviceSpec {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "os": "Linux",
+            "os_version": "6",
+            "core_num": 4,
+            "ip_addr": "10.0.0.1",
+            "port": 7000,
+            "status": "",
+            "updated_at": "",
+        }))
+        .unwrap()
+    }
+
+    fn transitions(
+        events: &Receiver<DeviceEvent>,
+    ) -> Vec<(String, Option<DeviceStatus>, DeviceStatus)> {
+        events
+            .try_iter()
+            .map(|event| (event.id, event.from, event.to))
+            .collect()
+    }
+
+    fn status_of(store: &DeviceStore, id: &str) -> DeviceStatus {
+        let device = store
+            .list()
+            .into_iter()
+            .find(|device| device.id == id)
+            .unwrap();
+        device.status.parse().unwrap()
+    }
+
+    #[test]
+    fn report_registers_device_once() {
+        let fx = Fixture::new();
+        let events = fx.store.subscribe();
+        let stored = fx.store.report(device("a")).unwrap();
+        assert_eq!(stored.status, "Active");
+        assert!(!stored.updated_at.is_empty());
+        fx.store.report(device("a")).unwrap();
+        assert_eq!(
+            transitions(&events),
+            [("a".to_string(), None, DeviceStatus::Active)]
+        );
+    }
+
+    #[test]
+    fn silence_demotes_to_suspect_then_offline() {
+        let fx = Fixture::new();
+        fx.store.report(device("a")).unwrap();
+        let events = fx.store.subscribe();
+
+        fx.store.check(&LIVENESS).unwrap();
+        assert_eq!(status_of(&fx.store, "a"), DeviceStatus::Active);
+        assert!(transitions(&events).is_empty());
+
+        thread::sleep(LIVENESS.suspect_after);
+        fx.store.check(&LIVENESS).unwrap();
+        assert_eq!(status_of(&fx.store, "a"), DeviceStatus::Suspect);
+
+        thread::sleep(LIVENESS.offline_after - LIVENESS.suspect_after);
+        fx.store.check(&LIVENESS).unwrap();
+        fx.store.check(&LIVENESS).unwrap();
+        assert_eq!(status_of(&fx.store, "a"), DeviceStatus::Offline);
+        assert_eq!(
+            transitions(&events),
+            [
+                (
+                    "a".to_string(),
+                    Some(DeviceStatus::Active),
+                    DeviceStatus::Suspect
+                ),
+                (
+                    "a".to_string(),
+                    Some(DeviceStatus::Suspect),
+                    DeviceStatus::Offline
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn long_silence_goes_straight_to_offline() {
+        let fx = Fixture::new();
+        fx.store.report(device("a")).unwrap();
+        let events = fx.store.subscribe();
+        thread::sleep(LIVENESS.offline_after);
+        fx.store.check(&LIVENESS).unwrap();
+        assert_eq!(
+            transitions(&events),
+            [(
+                "a".to_string(),
+                Some(DeviceStatus::Active),
+                DeviceStatus::Offline
+            )]
+        );
+    }
+
+    #[test]
+    fn heartbeats_keep_device_active_and_revive_it() {
+        let fx = Fixture::new();
+        fx.store.report(device("a")).unwrap();
+        let events = fx.store.subscribe();
+
+        // Beats more often than the suspect timeout, for longer than it.
+        for _ in 0..4 {
+            thread::sleep(LIVENESS.suspect_after / 4);
+            assert!(fx.store.heartbeat("a").unwrap());
+            fx.store.check(&LIVENESS).unwrap();
+        }
+        assert!(transitions(&events).is_empty());
+
+        thread::sleep(LIVENESS.offline_after);
+        fx.store.check(&LIVENESS).unwrap();
+        assert!(fx.store.heartbeat("a").unwrap());
+        assert_eq!(status_of(&fx.store, "a"), DeviceStatus::Active);
+        assert_eq!(
+            transitions(&events),
+            [
+                (
+                    "a".to_string(),
+                    Some(DeviceStatus::Active),
+                    DeviceStatus::Offline
+                ),
+                (
+                    "a".to_string(),
+                    Some(DeviceStatus::Offline),
+                    DeviceStatus::Active
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn heartbeat_of_unknown_device_is_refused() {
+        let fx = Fixture::new();
+        let events = fx.store.subscribe();
+        assert!(!fx.store.heartbeat("ghost").unwrap());
+        assert!(fx.store.list().is_empty());
+        assert!(transitions(&events).is_empty());
+    }
+
+    #[test]
+    fn statuses_survive_a_restart() {
+        let fx = Fixture::new();
+        fx.store.report(device("a")).unwrap();
+        fx.store.report(device("b")).unwrap();
+        thread::sleep(LIVENESS.offline_after);
+        fx.store.heartbeat("b").unwrap();
+        fx.store.check(&LIVENESS).unwrap();
+
+        let store = fx.reopen();
+        let events = store.subscribe();
+        assert_eq!(status_of(&store, "a"), DeviceStatus::Offline);
+        assert_eq!(status_of(&store, "b"), DeviceStatus::Active);
+        // The load time counts as last seen, an offline device is not
+        // promoted by a check before its timeout.
+        store.check(&LIVENESS).unwrap();
+        assert_eq!(status_of(&store, "a"), DeviceStatus::Offline);
+        assert!(transitions(&events).is_empty());
+    }
+
+    #[test]
+    fn dropped_subscriber_is_forgotten() {
+        let fx = Fixture::new();
+        drop(fx.store.subscribe());
+        let events = fx.store.subscribe();
+        fx.store.report(device("a")).unwrap();
+        assert_eq!(transitions(&events).len(), 1);
+        assert_eq!(fx.store.subscribers.lock().unwrap().len(), 1);
+    }
+}
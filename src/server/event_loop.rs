@@ -0,0 +1,560 @@
+use super::sandbox::Sandbox;
+use super::session::{Session, SessionControl};
+use super::transfer::RangedUploads;
+use crate::packet::{Frame, FrameDecoder, MsgOpcode, FRAME_HEADER_LEN};
+use crate::threadpool::{Priority, QueueFull, ShutdownMode, ThreadPool};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, ErrorKind, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+/// `Data` chunks queued by one download job.
+const STREAMING_BATCH: usize = 16;
+/// Received but unhandled bytes after which a connection stops reading.
+const MAX_PENDING_BYTES: usize = 4 * 1024 * 1024;
+/// Unsent reply bytes after which a connection stops reading, handling
+/// requests and pumping its download until the peer catches up.
+const OUTPUT_HIGH_WATER: usize = 1024 * 1024;
+/// How long shutdown waits for running session jobs.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wakes the event loop from another thread and asks it to stop.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+        self.waker
+            .wake()
+            .unwrap_or_else(|e| eprintln!("Failed to wake event loop: {}", e));
+    }
+}
+
+/// A session handed back by a pool job, with the replies it queued.
+struct Completion {
+    token: Token,
+    /// `None` if the job panicked and the session was lost with it.
+    session: Option<Session>,
+    output: Vec<u8>,
+    /// Frames left for a later job because the output reached
+    /// `OUTPUT_HIGH_WATER`.
+    unhandled: Vec<Frame>,
+    control: SessionControl,
+}
+
+/// Work for the pool: frames to handle, then optionally a download batch.
+struct Job {
+    frames: Vec<Frame>,
+    pump: bool,
+}
+
+impl Job {
+    /// Upload chunks and download batches are bulk work, everything else
+    /// is control work that should not wait behind them.
+    fn priority(&self) -> Priority {
+        let data = MsgOpcode::Data as u8;
+        if self.pump || self.frames.iter().any(|frame| frame.opcode() == data) {
+            Priority::Low
+        } else {
+            Priority::High
+        }
+    }
+}
+
+/// A dispatched job, boxed for the backlog.
+type Task = Box<dyn FnOnce() + Send + 'static>;
+
+struct Connection {
+    stream: TcpStream,
+    peer: String,
+    /// `None` while a pool job owns the session.
+    session: Option<Session>,
+    decoder: FrameDecoder,
+    pending: VecDeque<Frame>,
+    pending_bytes: usize,
+    output: Vec<u8>,
+    written: usize,
+    writable: bool,
+    read_paused: bool,
+    /// No more input is accepted, close once the queued work is done.
+    closing: bool,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: String, session: Session) -> Self {
+        Connection {
+            stream,
+            peer,
+            session: Some(session),
+            decoder: FrameDecoder::new(),
+            pending: VecDeque::new(),
+            pending_bytes: 0,
+            output: Vec::new(),
+            written: 0,
+            writable: false,
+            read_paused: false,
+            closing: false,
+        }
+    }
+
+    fn unsent(&self) -> usize {
+        self.output.len() - self.written
+    }
+
+    /// A peer that does not read its replies must not make them pile up.
+    fn output_blocked(&self) -> bool {
+        self.unsent() >= OUTPUT_HIGH_WATER
+    }
+
+    fn input_blocked(&self) -> bool {
+        self.pending_bytes >= MAX_PENDING_BYTES || self.output_blocked()
+    }
+
+    /// Read until the socket would block, queueing complete frames.
+    ///
+    /// # Errors
+    ///
+    /// Returns the socket error, the connection should be dropped.
+    ///
+    fn read_input(&mut self) -> io::Result<()> {
+        loop {
+            if self.closing {
+                return Ok(());
+            }
+            if self.input_blocked() {
+                self.read_paused = true;
+                return Ok(());
+            }
+            match self.decoder.read_from(&mut self.stream) {
+                Ok(0) => {
+                    println!("#{:>5}: connection closed by peer", self.peer);
+                    self.closing = true;
+                }
+                Ok(_) => self.queue_frames(),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn queue_frames(&mut self) {
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    self.pending_bytes += FRAME_HEADER_LEN + frame.payload.len();
+                    self.pending.push_back(frame);
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    eprintln!("#{:>5}: dropping connection: {}", self.peer, e);
+                    self.closing = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Write queued replies until the socket would block.
+    fn write_output(&mut self) -> io::Result<()> {
+        while self.written < self.output.len() {
+            match self.stream.write(&self.output[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => self.written += len,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        self.output.clear();
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Take the next job, if the session is idle and has anything to do.
+    fn next_job(&mut self) -> Option<(Session, Job)> {
+        let session = self.session.as_ref()?;
+        if self.output_blocked() {
+            return None;
+        }
+        let pump = !self.closing && session.is_streaming();
+        if self.pending.is_empty() && !pump {
+            return None;
+        }
+        let frames = self.pending.drain(..).collect();
+        self.pending_bytes = 0;
+        Some((self.session.take().unwrap(), Job { frames, pump }))
+    }
+
+    fn is_done(&self) -> bool {
+        self.closing && self.session.is_some() && self.pending.is_empty() && self.unsent() == 0
+    }
+
+    fn update_interest(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let writable = self.unsent() > 0;
+        if writable == self.writable {
+            return Ok(());
+        }
+        self.writable = writable;
+        let interest = if writable {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        registry.reregister(&mut self.stream, token, interest)
+    }
+}
+
+/// Readiness based server loop. Idle connections only cost a registration,
+/// sessions are handed to the `ThreadPool` while they have work to do.
+pub struct EventLoop {
+    poll: Poll,
+    listener: TcpListener,
+    sandbox: Sandbox,
+    /// Ranged uploads, shared by the sessions of a parallel transfer.
+    ranged: RangedUploads,
+    pool: ThreadPool,
+    connections: HashMap<Token, Connection>,
+    /// Jobs waiting for room in the pool's queue, highest priority first.
+    backlog: BTreeMap<Priority, VecDeque<Task>>,
+    next_token: usize,
+    done_tx: Sender<Completion>,
+    done_rx: Receiver<Completion>,
+    waker: Arc<Waker>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl EventLoop {
+    /// Register the listener for readiness events.
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - Bound listener, switched to non-blocking mode.
+    /// * `sandbox` - Export root all client paths are resolved in.
+    /// * `pool` - Pool request work is dispatched to.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` indicating the success or failure of the operation.
+    ///
+    pub fn new(
+        listener: std::net::TcpListener,
+        sandbox: Sandbox,
+        pool: ThreadPool,
+    ) -> io::Result<EventLoop> {
+        let poll = Poll::new()?;
+        listener.set_nonblocking(true)?;
+        let mut listener = TcpListener::from_std(listener);
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (done_tx, done_rx) = mpsc::channel();
+
+        Ok(EventLoop {
+            poll,
+            listener,
+            sandbox,
+            ranged: RangedUploads::default(),
+            pool,
+            connections: HashMap::new(),
+            backlog: BTreeMap::new(),
+            next_token: FIRST_CONNECTION,
+            done_tx,
+            done_rx,
+            waker,
+            shutdown: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            flag: Arc::clone(&self.shutdown),
+            waker: Arc::clone(&self.waker),
+        }
+    }
+
+    /// Serve until `ShutdownHandle::shutdown` is called, then tell every
+    /// client the session ends and wait for the running jobs.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if polling fails.
+    ///
+    pub fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(1024);
+        while !self.shutdown.load(Ordering::SeqCst) {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => (),
+                    token => {
+                        if event.is_readable() {
+                            self.read_ready(token);
+                        }
+                        self.advance(token);
+                    }
+                }
+            }
+            while let Ok(completion) = self.done_rx.try_recv() {
+                self.complete(completion);
+            }
+            self.submit_backlog();
+        }
+        self.terminate_all();
+        Ok(())
+    }
+
+    fn accept(&mut self) {
+        loop {
+            let (mut stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    eprintln!("Connection failed: {}", e);
+                    return;
+                }
+            };
+            println!("Source Address: {}", peer);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)
+            {
+                eprintln!("Failed to register {}: {}", peer, e);
+                continue;
+            }
+            let peer = peer.port().to_string();
+            let session = Session::new(
+                &peer,
+                self.sandbox.clone(),
+                self.ranged.clone(),
+                self.pool.stats_handle(),
+            );
+            self.connections
+                .insert(token, Connection::new(stream, peer, session));
+        }
+    }
+
+    fn read_ready(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if let Err(e) = conn.read_input() {
+            eprintln!("#{:>5}: read failed: {}", conn.peer, e);
+            self.close(token);
+        }
+    }
+
+    fn complete(&mut self, completion: Completion) {
+        let Some(conn) = self.connections.get_mut(&completion.token) else {
+            // Closed while the job was running, dropping the session
+            // aborts its upload.
+            return;
+        };
+        let Some(session) = completion.session else {
+            eprintln!("#{:>5}: session lost to a panic, closing", conn.peer);
+            self.close(completion.token);
+            return;
+        };
+        conn.session = Some(session);
+        conn.output.extend_from_slice(&completion.output);
+        for frame in completion.unhandled.into_iter().rev() {
+            conn.pending_bytes += FRAME_HEADER_LEN + frame.payload.len();
+            conn.pending.push_front(frame);
+        }
+        if completion.control == SessionControl::Close {
+            conn.closing = true;
+            conn.pending.clear();
+            conn.pending_bytes = 0;
+        }
+        self.advance(completion.token);
+    }
+
+    /// Flush replies, dispatch pending work and close finished connections.
+    fn advance(&mut self, token: Token) {
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if let Err(e) = conn.write_output() {
+            eprintln!("#{:>5}: write failed: {}", conn.peer, e);
+            self.close(token);
+            return;
+        }
+        // Input stays in the socket while paused, no new readiness event
+        // announces it.
+        if conn.read_paused && conn.session.is_some() && !conn.input_blocked() {
+            conn.read_paused = false;
+            self.read_ready(token);
+        }
+        let Some(conn) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if let Some((session, job)) = conn.next_job() {
+            self.dispatch(token, session, job);
+        }
+        let conn = self.connections.get_mut(&token).unwrap();
+        if conn.is_done() {
+            self.close(token);
+            return;
+        }
+        if let Err(e) = conn.update_interest(self.poll.registry(), token) {
+            eprintln!("#{:>5}: failed to update interest: {}", conn.peer, e);
+            self.close(token);
+        }
+    }
+
+    fn dispatch(&mut self, token: Token, mut session: Session, job: Job) {
+        let done_tx = self.done_tx.clone();
+        let waker = Arc::clone(&self.waker);
+        let priority = job.priority();
+        self.submit(
+            priority,
+            Box::new(move || {
+                let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let mut control = SessionControl::Continue;
+                    let mut frames = job.frames.into_iter();
+                    for frame in frames.by_ref() {
+                        control = session.handle_frame(frame);
+                        if control == SessionControl::Close
+                            || session.buffered() >= OUTPUT_HIGH_WATER
+                        {
+                            break;
+                        }
+                    }
+                    let unhandled = match control {
+                        SessionControl::Continue => frames.collect(),
+                        SessionControl::Close => Vec::new(),
+                    };
+                    if control == SessionControl::Continue
+                        && job.pump
+                        && session.buffered() < OUTPUT_HIGH_WATER
+                    {
+                        session.pump(STREAMING_BATCH);
+                    }
+                    let mut output = Vec::new();
+                    session
+                        .flush_to(&mut output)
+                        .expect("writing to a Vec cannot fail");
+                    Completion {
+                        token,
+                        session: Some(session),
+                        output,
+                        unhandled,
+                        control,
+                    }
+                }));
+                let (completion, panic) = match result {
+                    Ok(completion) => (completion, None),
+                    Err(payload) => {
+                        let lost = Completion {
+                            token,
+                            session: None,
+                            output: Vec::new(),
+                            unhandled: Vec::new(),
+                            control: SessionControl::Close,
+                        };
+                        (lost, Some(payload))
+                    }
+                };
+                if done_tx.send(completion).is_ok() {
+                    waker
+                        .wake()
+                        .unwrap_or_else(|e| eprintln!("Failed to wake event loop: {}", e));
+                }
+                // Hand the panic on to the pool's panic hook.
+                if let Some(payload) = panic {
+                    panic::resume_unwind(payload);
+                }
+            }),
+        );
+    }
+
+    /// Queue `task` behind the backlog of its priority, the loop must not
+    /// block on a full pool.
+    fn submit(&mut self, priority: Priority, task: Task) {
+        self.backlog.entry(priority).or_default().push_back(task);
+        self.submit_backlog();
+    }
+
+    /// Move backlogged jobs to the pool until its queue is full.
+    fn submit_backlog(&mut self) {
+        for (&priority, backlog) in self.backlog.iter_mut() {
+            while let Some(task) = backlog.pop_front() {
+                if let Err(QueueFull(task)) = self.pool.try_execute(priority, task) {
+                    backlog.push_front(task);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut conn) = self.connections.remove(&token) {
+            self.poll
+                .registry()
+                .deregister(&mut conn.stream)
+                .unwrap_or_else(|e| eprintln!("Failed to deregister: {}", e));
+            conn.stream
+                .shutdown(std::net::Shutdown::Both)
+                .unwrap_or_else(|_| eprintln!("Failed to shutdown stream."));
+        }
+    }
+
+    /// Send `Terminate` to every client, including those whose session is
+    /// still owned by a job, once the pool has finished it. Sessions of jobs
+    /// that outlive `SHUTDOWN_TIMEOUT` are only disconnected.
+    fn terminate_all(mut self) {
+        println!("Shutting down event loop.");
+        // Backlogged jobs own their sessions, let the drain finish them.
+        for (priority, backlog) in std::mem::take(&mut self.backlog) {
+            for task in backlog {
+                self.pool.execute_with(priority, task);
+            }
+        }
+        let report = self
+            .pool
+            .shutdown(ShutdownMode::Drain, Some(SHUTDOWN_TIMEOUT));
+        println!("Threadpool shut down: {}", report);
+        println!("Threadpool stats:\n{}", self.pool.stats());
+        while let Ok(completion) = self.done_rx.try_recv() {
+            if let Some(conn) = self.connections.get_mut(&completion.token) {
+                conn.output.extend_from_slice(&completion.output);
+                conn.session = completion.session;
+            }
+        }
+        for (_, mut conn) in self.connections.drain() {
+            if let Some(session) = conn.session.as_mut() {
+                session.terminate();
+                session
+                    .flush_to(&mut conn.output)
+                    .expect("writing to a Vec cannot fail");
+            }
+            conn.write_output()
+                .unwrap_or_else(|_| eprintln!("Failed to send terminate."));
+            conn.stream
+                .shutdown(std::net::Shutdown::Both)
+                .unwrap_or_else(|_| eprintln!("Failed to shutdown stream."));
+        }
+    }
+}
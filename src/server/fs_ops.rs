@@ -0,0 +1,380 @@
+use super::sandbox::Sandbox;
+use crate::packet::{FileStat, FileType, ListReply};
+use std::fs::{self, Metadata, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+fn file_stat(name: String, metadata: &Metadata) -> FileStat {
+    let file_type = metadata.file_type();
+    let file_type = if file_type.is_symlink() {
+        FileType::Symlink
+    } else if file_type.is_dir() {
+        FileType::Dir
+    } else if file_type.is_file() {
+        FileType::File
+    } else {
+        FileType::Other
+    };
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since_the_epoch| since_the_epoch.as_secs())
+        .unwrap_or(0);
+
+    FileStat {
+        name,
+        size: metadata.len(),
+        file_type,
+        permissions: permission_bits(metadata),
+        mtime,
+    }
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+/// Metadata of a single path, symlinks are not followed.
+///
+/// # Arguments
+///
+/// * `sandbox` - The server storage root.
+/// * `path` - Path relative to the root.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn stat(sandbox: &Sandbox, path: &str) -> io::Result<FileStat> {
+    let target = sandbox.resolve(path)?;
+    let metadata = fs::symlink_metadata(&target)?;
+    let name = target
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(file_stat(name, &metadata))
+}
+
+/// Entries of a directory, sorted by name.
+///
+/// # Arguments
+///
+/// * `sandbox` - The server storage root.
+/// * `path` - Directory relative to the root, empty for the root itself.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn list_dir(sandbox: &Sandbox, path: &str) -> io::Result<ListReply> {
+    let dir = sandbox.resolve(path)?;
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        entries.push(file_stat(
+            entry.file_name().to_string_lossy().to_string(),
+            &metadata,
+        ));
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(ListReply {
+        path: path.to_string(),
+        entries,
+    })
+}
+
+/// Like `Sandbox::resolve`, but refuses the storage root itself.
+fn resolve_entry(sandbox: &Sandbox, path: &str) -> io::Result<PathBuf> {
+    let target = sandbox.resolve(path)?;
+    if target == sandbox.root() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "operation not allowed on the storage root",
+        ));
+    }
+    Ok(target)
+}
+
+/// Remove a file. Directories are removed with `rmdir`.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn delete(sandbox: &Sandbox, path: &str) -> io::Result<()> {
+    let target = resolve_entry(sandbox, path)?;
+    if fs::symlink_metadata(&target)?.is_dir() {
+        return Err(io::ErrorKind::IsADirectory.into());
+    }
+    fs::remove_file(target)
+}
+
+/// Rename a file or directory, replacing an existing destination file.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn rename(sandbox: &Sandbox, from: &str, to: &str) -> io::Result<()> {
+    let from = resolve_entry(sandbox, from)?;
+    let to = resolve_entry(sandbox, to)?;
+    fs::rename(from, to)
+}
+
+/// Create a directory, and its missing parents if `parents` is set.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn mkdir(sandbox: &Sandbox, path: &str, parents: bool) -> io::Result<()> {
+    let target = resolve_entry(sandbox, path)?;
+    if parents {
+        fs::create_dir_all(target)
+    } else {
+        fs::create_dir(target)
+    }
+}
+
+/// Remove a directory, which must be empty unless `recursive` is set.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn rmdir(sandbox: &Sandbox, path: &str, recursive: bool) -> io::Result<()> {
+    let target = resolve_entry(sandbox, path)?;
+    if recursive {
+        fs::remove_dir_all(target)
+    } else {
+        fs::remove_dir(target)
+    }
+}
+
+/// Shrink or extend an existing file to `size` bytes.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the operation.
+///
+pub fn truncate(sandbox: &Sandbox, path: &str, size: u64) -> io::Result<()> {
+    let target = resolve_entry(sandbox, path)?;
+    OpenOptions::new().write(true).open(target)?.set_len(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    /// Export root in a fresh temporary directory, removed on drop.
+    struct Fixture {
+        sandbox: Sandbox,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let root = std::env::temp_dir().join(format!("xfs-fs-ops-{}", uuid::Uuid::new_v4()));
+            Fixture {
+                sandbox: Sandbox::new(&root).unwrap(),
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.sandbox.root().join(path)
+        }
+
+        fn write(&self, path: &str, data: &[u8]) {
+            fs::write(self.path(path), data).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(self.sandbox.root());
+        }
+    }
+
+    fn kind<T>(result: io::Result<T>) -> io::ErrorKind {
+        result.err().expect("operation should fail").kind()
+    }
+
+    #[test]
+    fn stat_does_not_follow_symlinks() {
+        let fx = Fixture::new();
+        fx.write("f", b"hello");
+        fs::set_permissions(fx.path("f"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::create_dir(fx.path("d")).unwrap();
+        symlink(fx.path("f"), fx.path("l")).unwrap();
+
+        let file = stat(&fx.sandbox, "f").unwrap();
+        assert_eq!(file.name, "f");
+        assert_eq!(file.size, 5);
+        assert_eq!(file.file_type, FileType::File);
+        assert_eq!(file.permissions, 0o640);
+        assert!(file.mtime > 0);
+        assert_eq!(stat(&fx.sandbox, "d").unwrap().file_type, FileType::Dir);
+        assert_eq!(stat(&fx.sandbox, "l").unwrap().file_type, FileType::Symlink);
+        assert_eq!(kind(stat(&fx.sandbox, "missing")), io::ErrorKind::NotFound);
+        assert_eq!(
+            kind(stat(&fx.sandbox, "../f")),
+            io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn list_dir_sorts_entries_by_name() {
+        let fx = Fixture::new();
+        fx.write("b", b"bb");
+        fx.write("a", b"a");
+        fs::create_dir(fx.path("c")).unwrap();
+
+        let reply = list_dir(&fx.sandbox, "").unwrap();
+        assert_eq!(reply.path, "");
+        let entries: Vec<_> = reply
+            .entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.size, entry.file_type))
+            .collect();
+        assert_eq!(
+            entries[..2],
+            [("a", 1, FileType::File), ("b", 2, FileType::File)]
+        );
+        assert_eq!((entries[2].0, entries[2].2), ("c", FileType::Dir));
+        assert!(list_dir(&fx.sandbox, "c").unwrap().entries.is_empty());
+        assert_eq!(
+            kind(list_dir(&fx.sandbox, "a")),
+            io::ErrorKind::NotADirectory
+        );
+    }
+
+    #[test]
+    fn delete_removes_files_only() {
+        let fx = Fixture::new();
+        fx.write("f", b"");
+        fs::create_dir(fx.path("d")).unwrap();
+
+        delete(&fx.sandbox, "f").unwrap();
+        assert!(!fx.path("f").exists());
+        assert_eq!(kind(delete(&fx.sandbox, "d")), io::ErrorKind::IsADirectory);
+        assert!(fx.path("d").is_dir());
+        assert_eq!(kind(delete(&fx.sandbox, "f")), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rename_replaces_an_existing_file() {
+        let fx = Fixture::new();
+        fx.write("a", b"new");
+        fx.write("b", b"old");
+        fs::create_dir(fx.path("d")).unwrap();
+
+        rename(&fx.sandbox, "a", "b").unwrap();
+        assert!(!fx.path("a").exists());
+        assert_eq!(fs::read(fx.path("b")).unwrap(), b"new");
+        rename(&fx.sandbox, "d", "e").unwrap();
+        assert!(fx.path("e").is_dir());
+        assert_eq!(kind(rename(&fx.sandbox, "a", "c")), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn mkdir_creates_parents_only_when_asked() {
+        let fx = Fixture::new();
+        assert_eq!(
+            kind(mkdir(&fx.sandbox, "a/b", false)),
+            io::ErrorKind::NotFound
+        );
+        mkdir(&fx.sandbox, "a/b", true).unwrap();
+        assert!(fx.path("a/b").is_dir());
+        assert_eq!(
+            kind(mkdir(&fx.sandbox, "a", false)),
+            io::ErrorKind::AlreadyExists
+        );
+        mkdir(&fx.sandbox, "a", true).unwrap();
+    }
+
+    #[test]
+    fn rmdir_removes_contents_only_when_recursive() {
+        let fx = Fixture::new();
+        fs::create_dir_all(fx.path("a/b")).unwrap();
+        fx.write("a/b/f", b"data");
+        fs::create_dir(fx.path("empty")).unwrap();
+
+        rmdir(&fx.sandbox, "empty", false).unwrap();
+        assert!(!fx.path("empty").exists());
+        assert_eq!(
+            kind(rmdir(&fx.sandbox, "a", false)),
+            io::ErrorKind::DirectoryNotEmpty
+        );
+        rmdir(&fx.sandbox, "a", true).unwrap();
+        assert!(!fx.path("a").exists());
+    }
+
+    #[test]
+    fn truncate_shrinks_and_extends_existing_files() {
+        let fx = Fixture::new();
+        fx.write("f", b"0123456789");
+
+        truncate(&fx.sandbox, "f", 4).unwrap();
+        assert_eq!(fs::read(fx.path("f")).unwrap(), b"0123");
+        truncate(&fx.sandbox, "f", 6).unwrap();
+        assert_eq!(fs::read(fx.path("f")).unwrap(), b"0123\0\0");
+        assert_eq!(
+            kind(truncate(&fx.sandbox, "missing", 0)),
+            io::ErrorKind::NotFound
+        );
+        assert!(!fx.path("missing").exists());
+    }
+
+    #[test]
+    fn modifications_refuse_the_root() {
+        let fx = Fixture::new();
+        fx.write("f", b"data");
+        for root in ["", ".", "./"] {
+            assert_eq!(
+                kind(resolve_entry(&fx.sandbox, root)),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(delete(&fx.sandbox, root)),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(rename(&fx.sandbox, root, "moved")),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(rename(&fx.sandbox, "f", root)),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(mkdir(&fx.sandbox, root, true)),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(rmdir(&fx.sandbox, root, true)),
+                io::ErrorKind::PermissionDenied
+            );
+            assert_eq!(
+                kind(truncate(&fx.sandbox, root, 0)),
+                io::ErrorKind::PermissionDenied
+            );
+        }
+        assert_eq!(fs::read(fx.path("f")).unwrap(), b"data");
+        // The root can still be read.
+        assert_eq!(list_dir(&fx.sandbox, "").unwrap().entries.len(), 1);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod event_loop;
+pub mod fs_ops;
+pub mod sandbox;
+pub mod session;
+pub mod transfer;
@@ -0,0 +1,322 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+
+/// Why a client supplied path was rejected.
+#[derive(Debug)]
+pub enum SandboxError {
+    NulByte,
+    AbsolutePath,
+    ParentDir,
+    /// A symlink on the path, relative to the root, points outside of it or nowhere.
+    SymlinkEscape(PathBuf),
+    Io(io::Error),
+}
+
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SandboxError::NulByte => write!(f, "path contains a NUL byte"),
+            SandboxError::AbsolutePath => write!(f, "absolute paths are not allowed"),
+            SandboxError::ParentDir => write!(f, "\"..\" is not allowed"),
+            SandboxError::SymlinkEscape(link) => {
+                write!(f, "symlink escapes the export root: {}", link.display())
+            }
+            SandboxError::Io(e) => write!(f, "failed to resolve path: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SandboxError {}
+
+impl From<SandboxError> for io::Error {
+    fn from(e: SandboxError) -> Self {
+        match e {
+            SandboxError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::PermissionDenied, e),
+        }
+    }
+}
+
+/// Export root every client supplied path must resolve inside of.
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    /// Create the export root if needed and canonicalize it.
+    ///
+    /// # Arguments
+    ///
+    /// * `root` - The directory exported to clients.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` indicating the success or failure of the operation.
+    ///
+    pub fn new(root: &Path) -> io::Result<Sandbox> {
+        fs::create_dir_all(root)?;
+        Ok(Sandbox {
+            root: fs::canonicalize(root)?,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Path as the client sees it, so replies do not leak the server layout.
+    fn relative(&self, path: &Path) -> PathBuf {
+        path.strip_prefix(&self.root).unwrap_or(path).to_path_buf()
+    }
+
+    /// Resolve a client supplied path below the export root.
+    ///
+    /// Symlinks in the middle of the path are followed and must stay inside
+    /// the root. A symlink in the last position is checked the same way but
+    /// returned unresolved, so that it can be stat'ed or removed itself.
+    /// Components that do not exist yet are appended as they are.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path relative to the root, empty or `.` for the root itself.
+    ///
+    /// # Errors
+    ///
+    /// Returns a `SandboxError` for NUL bytes, absolute paths, `..`
+    /// components and symlinks leading outside of the root.
+    ///
+    pub fn resolve(&self, path: &str) -> Result<PathBuf, SandboxError> {
+        if path.contains('\0') {
+            return Err(SandboxError::NulByte);
+        }
+        let mut components = Vec::new();
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => components.push(name),
+                Component::CurDir => (),
+                Component::ParentDir => return Err(SandboxError::ParentDir),
+                Component::RootDir | Component::Prefix(_) => {
+                    return Err(SandboxError::AbsolutePath)
+                }
+            }
+        }
+
+        let mut resolved = self.root.clone();
+        let last = components.len().saturating_sub(1);
+        for (i, name) in components.iter().enumerate() {
+            resolved.push(name);
+            let metadata = match fs::symlink_metadata(&resolved) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    // Nothing below a missing entry can be a symlink.
+                    resolved.extend(&components[i + 1..]);
+                    break;
+                }
+                Err(e) => return Err(SandboxError::Io(e)),
+            };
+            if !metadata.file_type().is_symlink() {
+                continue;
+            }
+            let escape = || SandboxError::SymlinkEscape(self.relative(&resolved));
+            let target = fs::canonicalize(&resolved).map_err(|_| escape())?;
+            if !target.starts_with(&self.root) {
+                return Err(escape());
+            }
+            if i != last {
+                resolved = target;
+            }
+        }
+        Ok(resolved)
+    }
+}
+
+/// Create or truncate a file for writing without following a symlink in
+/// the last component, e.g. a temporary name next to a path returned by
+/// `Sandbox::resolve` that was itself never resolved.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation, an existing symlink fails with `ELOOP`.
+///
+pub fn create_no_follow(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+/// Open an existing file for writing without following a symlink in the
+/// last component, like `create_no_follow` but keeping its contents.
+///
+/// # Errors
+///
+/// Returns an `std::io::Result` indicating the success or failure of the
+/// operation, a symlink fails with `ELOOP`.
+///
+pub fn open_no_follow(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Export root `<tmp>/<id>/root` next to an `outside` directory.
+    struct Fixture {
+        base: PathBuf,
+        sandbox: Sandbox,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let base = std::env::temp_dir().join(format!("xfs-sandbox-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(base.join("outside")).unwrap();
+            fs::write(base.join("outside/secret"), b"secret").unwrap();
+            let sandbox = Sandbox::new(&base.join("root")).unwrap();
+            fs::create_dir_all(sandbox.root().join("dir")).unwrap();
+            fs::write(sandbox.root().join("dir/file"), b"data").unwrap();
+            Fixture {
+                base: fs::canonicalize(base).unwrap(),
+                sandbox,
+            }
+        }
+
+        fn link(&self, name: &str, target: &Path) {
+            symlink(target, self.sandbox.root().join(name)).unwrap();
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn resolves_plain_paths() {
+        let fx = Fixture::new();
+        let root = fx.sandbox.root();
+        assert_eq!(fx.sandbox.resolve("").unwrap(), root);
+        assert_eq!(fx.sandbox.resolve(".").unwrap(), root);
+        assert_eq!(
+            fx.sandbox.resolve("dir/file").unwrap(),
+            root.join("dir/file")
+        );
+        assert_eq!(
+            fx.sandbox.resolve("./dir/./file").unwrap(),
+            root.join("dir/file")
+        );
+        assert_eq!(fx.sandbox.resolve("new/a/b").unwrap(), root.join("new/a/b"));
+    }
+
+    #[test]
+    fn rejects_parent_dir() {
+        let fx = Fixture::new();
+        for path in [
+            "..",
+            "../outside/secret",
+            "dir/../../outside",
+            "dir/..",
+            "a/b/../../..",
+        ] {
+            assert!(
+                matches!(fx.sandbox.resolve(path), Err(SandboxError::ParentDir)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let fx = Fixture::new();
+        let secret = fx.base.join("outside/secret");
+        for path in ["/", "/etc/passwd", secret.to_str().unwrap(), "//dir/file"] {
+            assert!(
+                matches!(fx.sandbox.resolve(path), Err(SandboxError::AbsolutePath)),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_nul_bytes() {
+        let fx = Fixture::new();
+        for path in ["\0", "dir/file\0", "dir\0/../../etc/passwd"] {
+            assert!(
+                matches!(fx.sandbox.resolve(path), Err(SandboxError::NulByte)),
+                "{:?}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_symlink_escapes() {
+        let fx = Fixture::new();
+        fx.link("out_dir", &fx.base.join("outside"));
+        fx.link("out_file", &fx.base.join("outside/secret"));
+        fx.link("dangling", &fx.base.join("outside/missing"));
+        fx.link("relative", Path::new("../outside"));
+        for path in [
+            "out_dir",
+            "out_dir/secret",
+            "out_dir/new_file",
+            "out_file",
+            "dangling",
+            "relative/secret",
+        ] {
+            assert!(
+                matches!(
+                    fx.sandbox.resolve(path),
+                    Err(SandboxError::SymlinkEscape(_))
+                ),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_chained_symlink_escape() {
+        let fx = Fixture::new();
+        fx.link("hop", &fx.sandbox.root().join("dir"));
+        symlink(fx.base.join("outside"), fx.sandbox.root().join("dir/up")).unwrap();
+        assert!(matches!(
+            fx.sandbox.resolve("hop/up/secret"),
+            Err(SandboxError::SymlinkEscape(_))
+        ));
+    }
+
+    #[test]
+    fn follows_symlinks_inside_root() {
+        let fx = Fixture::new();
+        let root = fx.sandbox.root();
+        fx.link("alias", &root.join("dir"));
+        assert_eq!(
+            fx.sandbox.resolve("alias/file").unwrap(),
+            root.join("dir/file")
+        );
+        // The last component stays unresolved.
+        assert_eq!(fx.sandbox.resolve("alias").unwrap(), root.join("alias"));
+    }
+
+    #[test]
+    fn sandbox_errors_map_to_permission_denied() {
+        let e: io::Error = SandboxError::ParentDir.into();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(e.get_ref().unwrap().is::<SandboxError>());
+    }
+}
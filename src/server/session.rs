@@ -0,0 +1,796 @@
+use super::fs_ops;
+use super::sandbox::{Sandbox, SandboxError};
+use super::transfer::{Download, RangedUploads, Upload};
+use crate::device::identity;
+use crate::hash::HashAlgorithm;
+use crate::packet::{
+    data_chunk, ErrorCode, ErrorReply, Frame, FrameEncoder, GetRequest, HandshakeProof,
+    HandshakeReply, HandshakeRequest, MkdirRequest, MsgOpcode, MsgPacket, PathRequest, PutRequest,
+    RenameRequest, RmdirRequest, StatusReply, TruncateRequest, CHUNK_CHECKSUM_VERSION,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SERVER_ID,
+};
+use crate::threadpool::StatsHandle;
+use std::io::{self, Write};
+
+/// What the connection loop should do after a frame was handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionControl {
+    Continue,
+    Close,
+}
+
+/// Per-connection protocol state. Replies are queued and written by `flush_to`.
+pub struct Session {
+    peer: String,
+    client_id: Option<String>,
+    version: u8,
+    sandbox: Sandbox,
+    ranged: RangedUploads,
+    /// Load of the pool running the sessions, for `Status` replies.
+    stats: StatsHandle,
+    upload: Option<Upload>,
+    download: Option<Download>,
+    encoder: FrameEncoder,
+    /// Handshake waiting for the client to sign `Challenge::challenge`.
+    challenge: Option<Challenge>,
+}
+
+/// A keyed handshake between the challenge and the client's proof.
+struct Challenge {
+    request: HandshakeRequest,
+    version: u8,
+    challenge: String,
+}
+
+impl Session {
+    pub fn new(peer: &str, sandbox: Sandbox, ranged: RangedUploads, stats: StatsHandle) -> Self {
+        Session {
+            peer: peer.to_string(),
+            client_id: None,
+            version: PROTOCOL_VERSION,
+            sandbox,
+            ranged,
+            stats,
+            upload: None,
+            download: None,
+            encoder: FrameEncoder::new(),
+            challenge: None,
+        }
+    }
+
+    /// Negotiated client id, or the peer address before the handshake.
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or(&self.peer)
+    }
+
+    /// Dispatch a single frame received from the client.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - A complete frame from `FrameDecoder`.
+    ///
+    /// # Returns
+    /// Whether the connection should stay open.
+    ///
+    pub fn handle_frame(&mut self, frame: Frame) -> SessionControl {
+        let opcode = match MsgOpcode::try_from(frame.opcode()) {
+            Ok(opcode) => opcode,
+            Err(unknown) => {
+                return self.reply_error(
+                    ErrorCode::UnknownOpcode,
+                    format!("unknown opcode: {}", unknown),
+                );
+            }
+        };
+        if opcode == MsgOpcode::Data {
+            if self.client_id.is_none() {
+                return self.reply_error(
+                    ErrorCode::HandshakeRequired,
+                    "Data sent before handshake".to_string(),
+                );
+            }
+            return self.handle_data(&frame.payload);
+        }
+        let packet = match serde_json::from_slice::<MsgPacket>(&frame.payload) {
+            Ok(packet) if packet.opcode == opcode => packet,
+            Ok(packet) => {
+                return self.reply_error(
+                    ErrorCode::MalformedPacket,
+                    format!(
+                        "frame opcode {:?} does not match packet opcode {:?}",
+                        opcode, packet.opcode
+                    ),
+                );
+            }
+            Err(e) => {
+                return self.reply_error(ErrorCode::MalformedPacket, e.to_string());
+            }
+        };
+        if self.client_id.is_none()
+            && opcode != MsgOpcode::Handshake
+            && opcode != MsgOpcode::Terminate
+        {
+            return self.reply_error(
+                ErrorCode::HandshakeRequired,
+                format!("{:?} sent before handshake", opcode),
+            );
+        }
+
+        match opcode {
+            MsgOpcode::Handshake => self.handle_handshake(&packet),
+            MsgOpcode::PlainMsg => {
+                println!("#{:>5}(msg): {}", packet.id, packet.data);
+                self.encoder.push_packet(&packet);
+                SessionControl::Continue
+            }
+            MsgOpcode::Terminate => {
+                println!("#{:>5}: terminate requested", self.client_id());
+                self.terminate();
+                SessionControl::Close
+            }
+            MsgOpcode::Error => {
+                eprintln!(
+                    "#{:>5}: client reported error: {}",
+                    self.client_id(),
+                    packet.data
+                );
+                SessionControl::Continue
+            }
+            MsgOpcode::Put => self.handle_put(&packet),
+            MsgOpcode::Get => self.handle_get(&packet),
+            MsgOpcode::List => self.handle_list(&packet),
+            MsgOpcode::Stat => self.handle_stat(&packet),
+            MsgOpcode::Status => {
+                let reply = StatusReply {
+                    pool: self.stats.stats(),
+                };
+                self.encoder.push_packet(&MsgPacket::with_body(
+                    SERVER_ID,
+                    MsgOpcode::Status,
+                    &reply,
+                ));
+                SessionControl::Continue
+            }
+            MsgOpcode::Delete
+            | MsgOpcode::Rename
+            | MsgOpcode::Mkdir
+            | MsgOpcode::Rmdir
+            | MsgOpcode::Truncate => self.handle_mutation(&packet),
+            MsgOpcode::Data | MsgOpcode::Progress | MsgOpcode::Complete => self.reply_error(
+                ErrorCode::UnknownOpcode,
+                format!("{:?} is not a client request", opcode),
+            ),
+        }
+    }
+
+    /// Queue a `Terminate` packet, used both as the reply to a client
+    /// `Terminate` and for server initiated shutdown.
+    pub fn terminate(&mut self) {
+        self.encoder
+            .push_packet(&MsgPacket::with_opcode(SERVER_ID, MsgOpcode::Terminate, ""));
+    }
+
+    /// Queue a typed error reply for the client.
+    pub fn reply_error(&mut self, code: ErrorCode, message: String) -> SessionControl {
+        eprintln!("#{:>5}: {:?}: {}", self.client_id(), code, message);
+        self.encoder
+            .push_packet(&ErrorReply::new(code, message).to_packet());
+        SessionControl::Continue
+    }
+
+    /// Queue an error reply whose code is derived from `e.kind()`.
+    pub fn reply_io_error(&mut self, context: String, e: io::Error) -> SessionControl {
+        let code = match e.get_ref() {
+            Some(inner) if inner.is::<SandboxError>() => ErrorCode::PathRejected,
+            _ => ErrorCode::from(e.kind()),
+        };
+        self.reply_error(code, format!("{}: {}", context, e))
+    }
+
+    /// Algorithm of the digest in front of `Data` chunks, `None` for
+    /// clients older than `CHUNK_CHECKSUM_VERSION`.
+    fn chunk_checksum(&self, hash: HashAlgorithm) -> Option<HashAlgorithm> {
+        (self.version >= CHUNK_CHECKSUM_VERSION).then_some(hash)
+    }
+
+    /// Whether a download still has `Data` frames to send.
+    pub fn is_streaming(&self) -> bool {
+        self.download.is_some()
+    }
+
+    /// Queue up to `max_chunks` `Data` frames of the active download, and
+    /// its `Complete` acknowledgement once the whole range was queued.
+    pub fn pump(&mut self, max_chunks: usize) {
+        let Some(hash) = self.download.as_ref().map(Download::hash) else {
+            return;
+        };
+        let checksum = self.chunk_checksum(hash);
+        let download = self.download.as_mut().unwrap();
+        for _ in 0..max_chunks {
+            match download.next_chunk() {
+                Ok(Some(chunk)) => self.encoder.push_data(checksum, chunk),
+                Ok(None) => {
+                    let complete = match self.download.take().unwrap().finish() {
+                        Ok(complete) => complete,
+                        Err(e) => {
+                            self.reply_io_error("download failed".to_string(), e);
+                            return;
+                        }
+                    };
+                    println!(
+                        "#{:>5}: GET {} done ({}B, {} {})",
+                        self.client_id(),
+                        complete.path,
+                        complete.bytes,
+                        complete.hash,
+                        complete.checksum
+                    );
+                    self.encoder.push_packet(&MsgPacket::with_body(
+                        SERVER_ID,
+                        MsgOpcode::Complete,
+                        &complete,
+                    ));
+                    return;
+                }
+                Err(e) => {
+                    self.download = None;
+                    self.reply_io_error("download aborted".to_string(), e);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Reply bytes queued since the last `flush_to`.
+    pub fn buffered(&self) -> usize {
+        self.encoder.buffered()
+    }
+
+    /// Write every queued reply to the client.
+    pub fn flush_to<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        self.encoder.flush_to(writer)
+    }
+
+    fn handle_put(&mut self, packet: &MsgPacket) -> SessionControl {
+        if let Some(upload) = self.upload.take() {
+            upload.abort();
+        }
+        let request = match packet.body::<PutRequest>() {
+            Ok(request) => request,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+        match Upload::create(&self.sandbox, &self.ranged, &request) {
+            Ok(upload) => {
+                println!(
+                    "#{:>5}: PUT {} ({}B)",
+                    self.client_id(),
+                    request.path,
+                    request.size
+                );
+                self.upload = Some(upload);
+                // An empty file is complete before any `Data` frame arrives.
+                self.finish_upload_if_complete();
+                SessionControl::Continue
+            }
+            Err(e) => self.reply_io_error(format!("PUT {} failed", request.path), e),
+        }
+    }
+
+    fn handle_get(&mut self, packet: &MsgPacket) -> SessionControl {
+        let request = match packet.body::<GetRequest>() {
+            Ok(request) => request,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+        match Download::open(&self.sandbox, &request) {
+            Ok((download, reply)) => {
+                println!(
+                    "#{:>5}: GET {} ({}+{}B)",
+                    self.client_id(),
+                    reply.path,
+                    reply.offset,
+                    reply.length
+                );
+                self.encoder
+                    .push_packet(&MsgPacket::with_body(SERVER_ID, MsgOpcode::Get, &reply));
+                self.download = Some(download);
+                SessionControl::Continue
+            }
+            Err(e) => self.reply_io_error(format!("GET {} failed", request.path), e),
+        }
+    }
+
+    fn handle_list(&mut self, packet: &MsgPacket) -> SessionControl {
+        let request = match packet.body::<PathRequest>() {
+            Ok(request) => request,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+        match fs_ops::list_dir(&self.sandbox, &request.path) {
+            Ok(reply) => {
+                self.encoder
+                    .push_packet(&MsgPacket::with_body(SERVER_ID, MsgOpcode::List, &reply));
+                SessionControl::Continue
+            }
+            Err(e) => self.reply_io_error(format!("LIST {} failed", request.path), e),
+        }
+    }
+
+    fn handle_stat(&mut self, packet: &MsgPacket) -> SessionControl {
+        let request = match packet.body::<PathRequest>() {
+            Ok(request) => request,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+        match fs_ops::stat(&self.sandbox, &request.path) {
+            Ok(stat) => {
+                self.encoder
+                    .push_packet(&MsgPacket::with_body(SERVER_ID, MsgOpcode::Stat, &stat));
+                SessionControl::Continue
+            }
+            Err(e) => self.reply_io_error(format!("STAT {} failed", request.path), e),
+        }
+    }
+
+    fn handle_mutation(&mut self, packet: &MsgPacket) -> SessionControl {
+        let root = &self.sandbox;
+        let result = match packet.opcode {
+            MsgOpcode::Delete => packet
+                .body::<PathRequest>()
+                .map(|request| fs_ops::delete(root, &request.path)),
+            MsgOpcode::Rename => packet
+                .body::<RenameRequest>()
+                .map(|request| fs_ops::rename(root, &request.from, &request.to)),
+            MsgOpcode::Mkdir => packet
+                .body::<MkdirRequest>()
+                .map(|request| fs_ops::mkdir(root, &request.path, request.parents)),
+            MsgOpcode::Rmdir => packet
+                .body::<RmdirRequest>()
+                .map(|request| fs_ops::rmdir(root, &request.path, request.recursive)),
+            MsgOpcode::Truncate => packet
+                .body::<TruncateRequest>()
+                .map(|request| fs_ops::truncate(root, &request.path, request.size)),
+            opcode => unreachable!("{:?} is not a mutating operation", opcode),
+        };
+        match result {
+            Err(e) => self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+            Ok(Err(e)) => {
+                self.reply_io_error(format!("{:?} {} failed", packet.opcode, packet.data), e)
+            }
+            Ok(Ok(())) => {
+                println!(
+                    "#{:>5}: {:?} {}",
+                    self.client_id(),
+                    packet.opcode,
+                    packet.data
+                );
+                self.encoder.push_packet(&MsgPacket::with_opcode(
+                    SERVER_ID,
+                    packet.opcode,
+                    &packet.data,
+                ));
+                SessionControl::Continue
+            }
+        }
+    }
+
+    fn handle_data(&mut self, payload: &[u8]) -> SessionControl {
+        let Some(hash) = self.upload.as_ref().map(Upload::hash) else {
+            return self.reply_error(
+                ErrorCode::NoActiveTransfer,
+                "Data received without an active PUT".to_string(),
+            );
+        };
+        let checksum = self.chunk_checksum(hash);
+        let upload = self.upload.as_mut().unwrap();
+        if let Err(e) = data_chunk(checksum, payload).and_then(|chunk| upload.write_chunk(chunk)) {
+            if let Some(upload) = self.upload.take() {
+                upload.abort();
+            }
+            return self.reply_io_error("upload aborted".to_string(), e);
+        }
+        if let Some(progress) = upload.take_progress() {
+            self.encoder.push_packet(&MsgPacket::with_body(
+                SERVER_ID,
+                MsgOpcode::Progress,
+                &progress,
+            ));
+        }
+        self.finish_upload_if_complete();
+        SessionControl::Continue
+    }
+
+    fn finish_upload_if_complete(&mut self) {
+        if !self.upload.as_ref().is_some_and(Upload::is_complete) {
+            return;
+        }
+        match self.upload.take().unwrap().finish() {
+            Ok(complete) => {
+                println!(
+                    "#{:>5}: PUT {} done ({}B, {} {})",
+                    self.client_id(),
+                    complete.path,
+                    complete.bytes,
+                    complete.hash,
+                    complete.checksum
+                );
+                self.encoder.push_packet(&MsgPacket::with_body(
+                    SERVER_ID,
+                    MsgOpcode::Complete,
+                    &complete,
+                ));
+            }
+            Err(e) => {
+                self.reply_io_error("upload failed".to_string(), e);
+            }
+        }
+    }
+
+    fn handle_handshake(&mut self, packet: &MsgPacket) -> SessionControl {
+        if self.client_id.is_some() {
+            return self.reply_error(
+                ErrorCode::MalformedPacket,
+                "handshake already completed".to_string(),
+            );
+        }
+        if let Some(challenge) = self.challenge.take() {
+            return self.handle_proof(packet, challenge);
+        }
+        let request = match packet.body::<HandshakeRequest>() {
+            Ok(request) => request,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+
+        let version = request.max_version.min(PROTOCOL_VERSION);
+        if version < request.min_version || version < MIN_PROTOCOL_VERSION {
+            self.reply_error(
+                ErrorCode::VersionMismatch,
+                format!(
+                    "client speaks {}..={}, server speaks {}..={}",
+                    request.min_version,
+                    request.max_version,
+                    MIN_PROTOCOL_VERSION,
+                    PROTOCOL_VERSION
+                ),
+            );
+            self.terminate();
+            return SessionControl::Close;
+        }
+
+        if let Some(public_key) = &request.public_key {
+            if !identity::is_bound(&request.node_id, public_key) {
+                return self.reply_error(
+                    ErrorCode::InvalidInput,
+                    format!("node id {} does not match its public key", request.node_id),
+                );
+            }
+            // Anyone can send a public key, the node has to prove it holds
+            // the secret key before the id is accepted.
+            let challenge = match identity::new_challenge() {
+                Ok(challenge) => challenge,
+                Err(e) => return self.reply_io_error("handshake failed".to_string(), e),
+            };
+            self.encoder.push_packet(&MsgPacket::with_body(
+                SERVER_ID,
+                MsgOpcode::Handshake,
+                &HandshakeReply {
+                    version,
+                    client_id: String::new(),
+                    challenge: Some(challenge.clone()),
+                },
+            ));
+            self.challenge = Some(Challenge {
+                request,
+                version,
+                challenge,
+            });
+            return SessionControl::Continue;
+        }
+        self.complete_handshake(request, version)
+    }
+
+    /// Check the signature of a pending challenge, close the connection if
+    /// it does not verify.
+    fn handle_proof(&mut self, packet: &MsgPacket, challenge: Challenge) -> SessionControl {
+        let proof = match packet.body::<HandshakeProof>() {
+            Ok(proof) => proof,
+            Err(e) => return self.reply_error(ErrorCode::MalformedPacket, e.to_string()),
+        };
+        let Challenge {
+            request,
+            version,
+            challenge,
+        } = challenge;
+        let public_key = request.public_key.as_deref().unwrap_or_default();
+        if !identity::verify_challenge(public_key, &challenge, &proof.signature) {
+            self.reply_error(
+                ErrorCode::PermissionDenied,
+                format!("node {} failed the key challenge", request.node_id),
+            );
+            self.terminate();
+            return SessionControl::Close;
+        }
+        self.complete_handshake(request, version)
+    }
+
+    fn complete_handshake(&mut self, request: HandshakeRequest, version: u8) -> SessionControl {
+        let client_id = if !request.client_id.is_empty() {
+            request.client_id
+        } else if !request.node_id.is_empty() {
+            request.node_id.clone()
+        } else {
+            uuid::Uuid::new_v4().to_string()
+        };
+        if request.node_id.is_empty() {
+            println!(
+                "#{:>5}: handshake as {} (protocol v{})",
+                self.peer, client_id, version
+            );
+        } else {
+            println!(
+                "#{:>5}: handshake as {} from node {} (protocol v{})",
+                self.peer, client_id, request.node_id, version
+            );
+        }
+        self.version = version;
+        self.encoder.set_version(version);
+        self.client_id = Some(client_id.clone());
+        self.encoder.push_packet(&MsgPacket::with_body(
+            SERVER_ID,
+            MsgOpcode::Handshake,
+            &HandshakeReply {
+                version,
+                client_id,
+                challenge: None,
+            },
+        ));
+        SessionControl::Continue
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        if let Some(upload) = self.upload.take() {
+            upload.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{FrameDecoder, GetReply, TransferComplete};
+    use crate::threadpool::ThreadPool;
+    use std::fs;
+
+    /// A session on a fresh temporary export root, fed frames as they would
+    /// arrive from the connection. Removes the root on drop.
+    struct Harness {
+        session: Session,
+        decoder: FrameDecoder,
+        root: std::path::PathBuf,
+        _pool: ThreadPool,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let root = std::env::temp_dir().join(format!("xfs-session-{}", uuid::Uuid::new_v4()));
+            let pool = ThreadPool::new(1);
+            let session = Session::new(
+                "127.0.0.1:1",
+                Sandbox::new(&root).unwrap(),
+                RangedUploads::default(),
+                pool.stats_handle(),
+            );
+            Harness {
+                session,
+                decoder: FrameDecoder::new(),
+                root,
+                _pool: pool,
+            }
+        }
+
+        /// Pass `encoder`'s queued frame to the session.
+        fn handle(&mut self, mut encoder: FrameEncoder) -> SessionControl {
+            let mut wire = Vec::new();
+            encoder.flush_to(&mut wire).unwrap();
+            let mut decoder = FrameDecoder::new();
+            decoder.feed(&wire);
+            let frame = decoder.next_frame().unwrap().unwrap();
+            self.session.handle_frame(frame)
+        }
+
+        fn send<T: serde::Serialize>(&mut self, opcode: MsgOpcode, body: &T) -> SessionControl {
+            let mut encoder = FrameEncoder::new();
+            encoder.push_packet(&MsgPacket::with_body("client", opcode, body));
+            self.handle(encoder)
+        }
+
+        fn send_data(&mut self, checksum: Option<HashAlgorithm>, chunk: &[u8]) -> SessionControl {
+            let mut encoder = FrameEncoder::new();
+            encoder.push_data(checksum, chunk);
+            self.handle(encoder)
+        }
+
+        fn handshake(&mut self, min_version: u8, max_version: u8) -> SessionControl {
+            self.send(
+                MsgOpcode::Handshake,
+                &HandshakeRequest {
+                    min_version,
+                    max_version,
+                    client_id: "tester".to_string(),
+                    node_id: String::new(),
+                    public_key: None,
+                },
+            )
+        }
+
+        /// Every frame queued since the last call.
+        fn replies(&mut self) -> Vec<Frame> {
+            let mut wire = Vec::new();
+            self.session.flush_to(&mut wire).unwrap();
+            self.decoder.feed(&wire);
+            let mut frames = Vec::new();
+            while let Some(frame) = self.decoder.next_frame().unwrap() {
+                frames.push(frame);
+            }
+            frames
+        }
+
+        /// The queued packets, `Data` frames must not be among them.
+        fn packets(&mut self) -> Vec<MsgPacket> {
+            self.replies()
+                .iter()
+                .map(|frame| serde_json::from_slice(&frame.payload).unwrap())
+                .collect()
+        }
+    }
+
+    impl Drop for Harness {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn error_code(packet: &MsgPacket) -> ErrorCode {
+        assert_eq!(packet.opcode, MsgOpcode::Error, "{:?}", packet);
+        packet.body::<ErrorReply>().unwrap().code
+    }
+
+    #[test]
+    fn handshake_picks_the_highest_common_version() {
+        for (min, max, expected) in [
+            (MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 3, PROTOCOL_VERSION),
+            (
+                MIN_PROTOCOL_VERSION,
+                MIN_PROTOCOL_VERSION,
+                MIN_PROTOCOL_VERSION,
+            ),
+            (0, PROTOCOL_VERSION, PROTOCOL_VERSION),
+        ] {
+            let mut harness = Harness::new();
+            assert_eq!(harness.handshake(min, max), SessionControl::Continue);
+            let replies = harness.replies();
+            assert_eq!(replies.len(), 1);
+            // The reply already goes out in the negotiated version.
+            assert_eq!(replies[0].header.version, expected);
+            let packet: MsgPacket = serde_json::from_slice(&replies[0].payload).unwrap();
+            let reply = packet.body::<HandshakeReply>().unwrap();
+            assert_eq!(
+                (reply.version, reply.client_id.as_str()),
+                (expected, "tester")
+            );
+            assert_eq!(reply.challenge, None);
+            assert_eq!(harness.session.client_id(), "tester");
+
+            assert_eq!(harness.handshake(min, max), SessionControl::Continue);
+            assert_eq!(
+                error_code(&harness.packets()[0]),
+                ErrorCode::MalformedPacket
+            );
+        }
+    }
+
+    #[test]
+    fn handshake_without_a_common_version_closes_the_session() {
+        for (min, max) in [
+            (PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 3),
+            (0, MIN_PROTOCOL_VERSION - 1),
+            (PROTOCOL_VERSION, MIN_PROTOCOL_VERSION),
+        ] {
+            let mut harness = Harness::new();
+            assert_eq!(harness.handshake(min, max), SessionControl::Close);
+            let packets = harness.packets();
+            assert_eq!(error_code(&packets[0]), ErrorCode::VersionMismatch);
+            assert_eq!(packets[1].opcode, MsgOpcode::Terminate);
+            assert_eq!(harness.session.client_id(), "127.0.0.1:1");
+        }
+    }
+
+    #[test]
+    fn requests_before_the_handshake_are_refused() {
+        let mut harness = Harness::new();
+        fs::write(harness.root.join("f"), b"data").unwrap();
+        let stat = PathRequest {
+            path: "f".to_string(),
+        };
+        assert_eq!(
+            harness.send(MsgOpcode::Stat, &stat),
+            SessionControl::Continue
+        );
+        assert_eq!(harness.send_data(None, b"data"), SessionControl::Continue);
+        let packets = harness.packets();
+        assert_eq!(packets.len(), 2);
+        assert_eq!(error_code(&packets[0]), ErrorCode::HandshakeRequired);
+        assert_eq!(error_code(&packets[1]), ErrorCode::HandshakeRequired);
+
+        harness.handshake(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION);
+        harness.replies();
+        harness.send(MsgOpcode::Stat, &stat);
+        assert_eq!(harness.packets()[0].opcode, MsgOpcode::Stat);
+    }
+
+    #[test]
+    fn terminate_is_answered_before_the_handshake() {
+        let mut harness = Harness::new();
+        assert_eq!(
+            harness.send(MsgOpcode::Terminate, &""),
+            SessionControl::Close
+        );
+        assert_eq!(harness.packets()[0].opcode, MsgOpcode::Terminate);
+    }
+
+    #[test]
+    fn put_then_get_round_trips_through_the_session() {
+        let hash = HashAlgorithm::default();
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        for version in MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION {
+            let mut harness = Harness::new();
+            harness.handshake(version, version);
+            harness.replies();
+            let checksum = (version >= CHUNK_CHECKSUM_VERSION).then_some(hash);
+
+            let put = PutRequest {
+                path: "f".to_string(),
+                size: data.len() as u64,
+                offset: 0,
+                file_size: None,
+                hash,
+            };
+            harness.send(MsgOpcode::Put, &put);
+            for chunk in data.chunks(30_000) {
+                assert_eq!(harness.send_data(checksum, chunk), SessionControl::Continue);
+            }
+            let packets = harness.packets();
+            let complete = packets.last().unwrap();
+            assert_eq!(complete.opcode, MsgOpcode::Complete, "{:?}", packets);
+            let complete = complete.body::<TransferComplete>().unwrap();
+            assert_eq!(complete.bytes, data.len() as u64);
+            assert_eq!(complete.checksum, hash.digest(&data).to_string());
+            assert_eq!(fs::read(harness.root.join("f")).unwrap(), data);
+
+            let get = GetRequest {
+                path: "f".to_string(),
+                offset: 0,
+                length: None,
+                hash,
+                file_checksum: false,
+            };
+            harness.send(MsgOpcode::Get, &get);
+            while harness.session.is_streaming() {
+                harness.session.pump(4);
+            }
+            let mut received = Vec::new();
+            let mut packets = Vec::new();
+            for frame in harness.replies() {
+                if frame.opcode() == MsgOpcode::Data as u8 {
+                    received.extend_from_slice(data_chunk(checksum, &frame.payload).unwrap());
+                } else {
+                    packets.push(serde_json::from_slice::<MsgPacket>(&frame.payload).unwrap());
+                }
+            }
+            assert_eq!(received, data);
+            let reply = packets[0].body::<GetReply>().unwrap();
+            assert_eq!((reply.offset, reply.length), (0, data.len() as u64));
+            let complete = packets[1].body::<TransferComplete>().unwrap();
+            assert_eq!(complete.checksum, hash.digest(&data).to_string());
+        }
+    }
+}
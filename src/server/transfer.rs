@@ -0,0 +1,575 @@
+use super::sandbox::{self, Sandbox};
+use crate::file::file_io::{hash_range, read_part};
+use crate::hash::{HashAlgorithm, Hasher};
+use crate::packet::{
+    GetReply, GetRequest, PutRequest, TransferComplete, TransferProgress, DATA_CHUNK_LEN,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Bytes between two `Progress` reports of one transfer.
+pub const PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Ranged uploads in progress, by destination, shared by every session.
+///
+/// The ranges of one destination are written to the same `.part` file,
+/// which replaces the destination once every byte has arrived. Until
+/// then the destination is left as it was.
+#[derive(Clone, Default)]
+pub struct RangedUploads {
+    parts: Arc<Mutex<HashMap<PathBuf, SharedPart>>>,
+}
+
+/// The `.part` file of a ranged upload.
+struct SharedPart {
+    file_size: u64,
+    /// Ranges written completely, as `(offset, end)`.
+    done: Vec<(u64, u64)>,
+    /// Uploads writing to the file right now.
+    writers: usize,
+}
+
+impl SharedPart {
+    fn is_complete(&self) -> bool {
+        let mut done = self.done.clone();
+        done.sort_unstable();
+        let mut covered = 0;
+        for (offset, end) in done {
+            if offset > covered {
+                return false;
+            }
+            covered = covered.max(end);
+        }
+        covered >= self.file_size
+    }
+}
+
+impl RangedUploads {
+    fn parts(&self) -> MutexGuard<'_, HashMap<PathBuf, SharedPart>> {
+        self.parts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Open the `.part` file of `dest` for one more range, creating it
+    /// with `file_size` bytes for the first one.
+    fn join(&self, dest: &Path, part: &Path, file_size: u64) -> io::Result<File> {
+        let mut parts = self.parts();
+        match parts.get_mut(dest) {
+            Some(shared) if shared.file_size == file_size => {
+                let file = sandbox::open_no_follow(part)?;
+                shared.writers += 1;
+                return Ok(file);
+            }
+            Some(shared) if shared.writers > 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("upload of {}B in progress", shared.file_size),
+                ));
+            }
+            // Left over by a transfer whose ranges never all arrived.
+            Some(_) => (),
+            None => (),
+        }
+        let file = sandbox::create_no_follow(part)?;
+        file.set_len(file_size)?;
+        parts.insert(
+            dest.to_path_buf(),
+            SharedPart {
+                file_size,
+                done: Vec::new(),
+                writers: 1,
+            },
+        );
+        Ok(file)
+    }
+
+    /// Record a written range, and move the `.part` file onto `dest` if
+    /// it was the last one missing.
+    fn complete(&self, dest: &Path, part: &Path, range: (u64, u64)) -> io::Result<()> {
+        let mut parts = self.parts();
+        let Some(shared) = parts.get_mut(dest) else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "ranged upload was discarded",
+            ));
+        };
+        shared.writers -= 1;
+        shared.done.push(range);
+        if shared.is_complete() {
+            parts.remove(dest);
+            fs::rename(part, dest)?;
+        }
+        Ok(())
+    }
+
+    /// Drop an unfinished range. The `.part` file is removed once no other
+    /// range is being written, the transfer has failed.
+    fn abort(&self, dest: &Path, part: &Path) {
+        let mut parts = self.parts();
+        let Some(shared) = parts.get_mut(dest) else {
+            return;
+        };
+        shared.writers -= 1;
+        if shared.writers == 0 {
+            parts.remove(dest);
+            fs::remove_file(part)
+                .unwrap_or_else(|e| eprintln!("Failed to remove partial upload: {}", e));
+        }
+    }
+}
+
+/// An upload in progress. Bytes go to a `.part` file that is renamed
+/// onto the destination once the declared size has been received.
+///
+/// A ranged upload (`PutRequest::file_size` set) is one of several
+/// parallel connections and writes its range into a `.part` file shared
+/// through `RangedUploads`.
+pub struct Upload {
+    path: String,
+    dest: PathBuf,
+    part: PathBuf,
+    /// Set for a ranged upload, with the range's offset.
+    ranged: Option<(RangedUploads, u64)>,
+    file: File,
+    size: u64,
+    received: u64,
+    hash: HashAlgorithm,
+    hasher: Hasher,
+    next_report: u64,
+}
+
+impl Upload {
+    /// Create the destination directories and the temporary file.
+    ///
+    /// # Arguments
+    ///
+    /// * `sandbox` - The server storage root.
+    /// * `ranged` - Ranged uploads of every session.
+    /// * `request` - The client's `Put` request.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` indicating the success or failure of the operation.
+    ///
+    pub fn create(
+        sandbox: &Sandbox,
+        ranged: &RangedUploads,
+        request: &PutRequest,
+    ) -> io::Result<Upload> {
+        let dest = sandbox.resolve(&request.path)?;
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut part = dest.clone().into_os_string();
+        part.push(".part");
+        let part = PathBuf::from(part);
+        // Only `dest` was resolved, the `.part` name may be a symlink.
+        let (file, ranged) = match request.file_size {
+            None if request.offset != 0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "offset requires file_size",
+                ));
+            }
+            None => (sandbox::create_no_follow(&part)?, None),
+            Some(file_size) => {
+                // Both values come from the client, the sum may overflow.
+                let end = request.offset.checked_add(request.size);
+                if end.is_none_or(|end| end > file_size) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "range {}+{} outside of {}B",
+                            request.offset, request.size, file_size
+                        ),
+                    ));
+                }
+                let mut file = ranged.join(&dest, &part, file_size)?;
+                if let Err(e) = file.seek(SeekFrom::Start(request.offset)) {
+                    ranged.abort(&dest, &part);
+                    return Err(e);
+                }
+                (file, Some((ranged.clone(), request.offset)))
+            }
+        };
+
+        Ok(Upload {
+            path: request.path.clone(),
+            dest,
+            part,
+            ranged,
+            file,
+            size: request.size,
+            received: 0,
+            hash: request.hash,
+            hasher: request.hash.hasher(),
+            next_report: PROGRESS_INTERVAL,
+        })
+    }
+
+    /// Append one `Data` chunk.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidData` if the chunk exceeds the declared size.
+    ///
+    pub fn write_chunk(&mut self, chunk: &[u8]) -> io::Result<()> {
+        let received = self.received.checked_add(chunk.len() as u64);
+        if received.is_none_or(|received| received > self.size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("upload exceeds declared size of {}B", self.size),
+            ));
+        }
+        self.file.write_all(chunk)?;
+        self.hasher.update(chunk);
+        self.received += chunk.len() as u64;
+        Ok(())
+    }
+
+    /// Algorithm of the chunk and transfer checksums.
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received == self.size
+    }
+
+    /// Progress report, returned once every `PROGRESS_INTERVAL` bytes.
+    /// Ranged uploads get none, their clients only read the final reply.
+    pub fn take_progress(&mut self) -> Option<TransferProgress> {
+        if self.ranged.is_some() || self.received < self.next_report || self.is_complete() {
+            return None;
+        }
+        self.next_report = self.received + PROGRESS_INTERVAL;
+        Some(TransferProgress {
+            path: self.path.clone(),
+            bytes: self.received,
+            total: self.size,
+        })
+    }
+
+    /// Flush the file to disk and move it into place, for a ranged upload
+    /// once every range has been written.
+    pub fn finish(self) -> io::Result<TransferComplete> {
+        if let Err(e) = self.file.sync_all() {
+            self.abort();
+            return Err(e);
+        }
+        match &self.ranged {
+            Some((ranged, offset)) => {
+                ranged.complete(&self.dest, &self.part, (*offset, offset + self.size))?
+            }
+            None => fs::rename(&self.part, &self.dest)?,
+        }
+        Ok(TransferComplete {
+            path: self.path,
+            bytes: self.received,
+            checksum: self.hasher.finish().to_string(),
+            hash: self.hash,
+            file_checksum: None,
+        })
+    }
+
+    /// Drop an unfinished upload and its temporary file.
+    pub fn abort(self) {
+        let Upload {
+            dest,
+            part,
+            ranged,
+            file,
+            ..
+        } = self;
+        drop(file);
+        match ranged {
+            Some((ranged, _)) => ranged.abort(&dest, &part),
+            None => fs::remove_file(part)
+                .unwrap_or_else(|e| eprintln!("Failed to remove partial upload: {}", e)),
+        }
+    }
+}
+
+/// A download in progress, read in `DATA_CHUNK_LEN` pieces with `read_part`.
+pub struct Download {
+    path: String,
+    file: File,
+    position: u64,
+    end: u64,
+    sent: u64,
+    /// Size of the source to hash for `TransferComplete::file_checksum`.
+    file_checksum: Option<u64>,
+    hash: HashAlgorithm,
+    hasher: Hasher,
+    buffer: Vec<u8>,
+}
+
+impl Download {
+    /// Open the source file and validate the requested range.
+    ///
+    /// # Arguments
+    ///
+    /// * `sandbox` - The server storage root.
+    /// * `request` - The client's `Get` request.
+    ///
+    /// # Errors
+    ///
+    /// Returns `InvalidInput` if the range lies outside the file.
+    ///
+    pub fn open(sandbox: &Sandbox, request: &GetRequest) -> io::Result<(Download, GetReply)> {
+        let src = sandbox.resolve(&request.path)?;
+        let file = File::open(&src)?;
+        let file_size = file.metadata()?.len();
+        let length = request
+            .length
+            .unwrap_or_else(|| file_size.saturating_sub(request.offset));
+        let end = match request.offset.checked_add(length) {
+            Some(end) if end <= file_size => end,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "range {}+{} outside of {} ({}B)",
+                        request.offset, length, request.path, file_size
+                    ),
+                ));
+            }
+        };
+
+        let reply = GetReply {
+            path: request.path.clone(),
+            offset: request.offset,
+            length,
+            file_size,
+            hash: request.hash,
+        };
+        let download = Download {
+            path: request.path.clone(),
+            file,
+            position: request.offset,
+            end,
+            sent: 0,
+            file_checksum: request.file_checksum.then_some(file_size),
+            hash: request.hash,
+            hasher: request.hash.hasher(),
+            buffer: vec![0; DATA_CHUNK_LEN],
+        };
+        Ok((download, reply))
+    }
+
+    /// Read the next chunk of the range, `None` once everything was read.
+    pub fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        let len = (self.end - self.position).min(DATA_CHUNK_LEN as u64) as usize;
+        if len == 0 {
+            return Ok(None);
+        }
+        read_part(&mut self.file, self.position, &mut self.buffer[..len])?;
+        self.position += len as u64;
+        self.sent += len as u64;
+        self.hasher.update(&self.buffer[..len]);
+        Ok(Some(&self.buffer[..len]))
+    }
+
+    /// Algorithm of the chunk and transfer checksums.
+    pub fn hash(&self) -> HashAlgorithm {
+        self.hash
+    }
+
+    /// Acknowledgement of the sent range.
+    ///
+    /// # Errors
+    ///
+    /// Returns an `std::io::Result` if the whole file checksum was asked
+    /// for and the source could not be read.
+    ///
+    pub fn finish(self) -> io::Result<TransferComplete> {
+        let file_checksum = match self.file_checksum {
+            Some(file_size) => Some(hash_range(&self.file, 0, file_size, self.hash)?.to_string()),
+            None => None,
+        };
+        Ok(TransferComplete {
+            path: self.path,
+            bytes: self.sent,
+            checksum: self.hasher.finish().to_string(),
+            hash: self.hash,
+            file_checksum,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// Export root `<tmp>/<id>/root` next to an `outside` directory,
+    /// removed on drop.
+    struct Fixture {
+        base: PathBuf,
+        sandbox: Sandbox,
+        ranged: RangedUploads,
+    }
+
+    impl Fixture {
+        fn new() -> Fixture {
+            let base = std::env::temp_dir().join(format!("xfs-transfer-{}", uuid::Uuid::new_v4()));
+            fs::create_dir_all(base.join("outside")).unwrap();
+            Fixture {
+                sandbox: Sandbox::new(&base.join("root")).unwrap(),
+                base,
+                ranged: RangedUploads::default(),
+            }
+        }
+
+        fn path(&self, path: &str) -> PathBuf {
+            self.sandbox.root().join(path)
+        }
+
+        /// Start the upload of `data[offset..end]` as a range of `data`.
+        fn range(&self, path: &str, data: &[u8], offset: usize, end: usize) -> Upload {
+            let request = PutRequest {
+                path: path.to_string(),
+                size: (end - offset) as u64,
+                offset: offset as u64,
+                file_size: Some(data.len() as u64),
+                hash: HashAlgorithm::default(),
+            };
+            let mut upload = Upload::create(&self.sandbox, &self.ranged, &request).unwrap();
+            upload.write_chunk(&data[offset..end]).unwrap();
+            upload
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.base);
+        }
+    }
+
+    #[test]
+    fn ranges_replace_the_destination_once_all_arrived() {
+        let fx = Fixture::new();
+        fs::write(fx.path("f"), b"old contents").unwrap();
+        let data = b"0123456789abcdef";
+        let first = fx.range("f", data, 0, 6);
+        let last = fx.range("f", data, 6, 16);
+
+        // Completion order is up to the connections.
+        assert!(last.is_complete());
+        last.finish().unwrap();
+        assert_eq!(fs::read(fx.path("f")).unwrap(), b"old contents");
+        assert!(fx.path("f.part").exists());
+
+        let complete = first.finish().unwrap();
+        assert_eq!(complete.bytes, 6);
+        assert_eq!(fs::read(fx.path("f")).unwrap(), data);
+        assert!(!fx.path("f.part").exists());
+        assert!(fx.ranged.parts().is_empty());
+    }
+
+    #[test]
+    fn aborted_range_leaves_the_destination_alone() {
+        let fx = Fixture::new();
+        fs::write(fx.path("f"), b"old contents").unwrap();
+        let data = b"0123456789abcdef";
+        let done = fx.range("f", data, 0, 8);
+        let broken = fx.range("f", data, 8, 12);
+        done.finish().unwrap();
+        broken.abort();
+
+        assert_eq!(fs::read(fx.path("f")).unwrap(), b"old contents");
+        assert!(!fx.path("f.part").exists());
+        assert!(fx.ranged.parts().is_empty());
+
+        // A retry starts from an empty `.part` file.
+        fx.range("f", data, 0, 16).finish().unwrap();
+        assert_eq!(fs::read(fx.path("f")).unwrap(), data);
+    }
+
+    #[test]
+    fn abort_keeps_the_part_file_while_other_ranges_write() {
+        let fx = Fixture::new();
+        let data = b"0123456789";
+        let broken = fx.range("f", data, 0, 5);
+        let running = fx.range("f", data, 5, 10);
+        broken.abort();
+        assert!(fx.path("f.part").exists());
+        running.abort();
+        assert!(!fx.path("f.part").exists());
+        assert!(!fx.path("f").exists());
+    }
+
+    #[test]
+    fn ranges_of_another_size_are_refused_while_writing() {
+        let fx = Fixture::new();
+        let _running = fx.range("f", b"0123456789", 0, 5);
+        let request = PutRequest {
+            path: "f".to_string(),
+            size: 2,
+            offset: 0,
+            file_size: Some(4),
+            hash: HashAlgorithm::default(),
+        };
+        let e = Upload::create(&fx.sandbox, &fx.ranged, &request)
+            .err()
+            .unwrap();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn upload_part_file_does_not_follow_symlinks() {
+        let fx = Fixture::new();
+        let secret = fx.base.join("outside/secret");
+        fs::write(&secret, b"secret").unwrap();
+        fs::create_dir(fx.path("dir")).unwrap();
+        fs::write(fx.path("dir/file"), b"data").unwrap();
+        symlink(&secret, fx.path("x.part")).unwrap();
+        symlink(fx.path("dir/file"), fx.path("dir/y.part")).unwrap();
+        for path in ["x", "dir/y"] {
+            let request = PutRequest {
+                path: path.to_string(),
+                size: 3,
+                offset: 0,
+                file_size: None,
+                hash: HashAlgorithm::default(),
+            };
+            let err = Upload::create(&fx.sandbox, &fx.ranged, &request)
+                .err()
+                .unwrap_or_else(|| panic!("{}: upload followed the .part symlink", path));
+            assert_eq!(err.raw_os_error(), Some(libc::ELOOP), "{}", path);
+        }
+        assert_eq!(fs::read(&secret).unwrap(), b"secret");
+        assert_eq!(fs::read(fx.path("dir/file")).unwrap(), b"data");
+    }
+
+    /// Send `path` from `offset` and return its acknowledgement.
+    fn download(fx: &Fixture, path: &str, offset: u64, file_checksum: bool) -> TransferComplete {
+        let request = GetRequest {
+            path: path.to_string(),
+            offset,
+            length: None,
+            hash: HashAlgorithm::default(),
+            file_checksum,
+        };
+        let (mut download, _) = Download::open(&fx.sandbox, &request).unwrap();
+        while download.next_chunk().unwrap().is_some() {}
+        download.finish().unwrap()
+    }
+
+    #[test]
+    fn resumed_download_can_ask_for_the_whole_file_checksum() {
+        let fx = Fixture::new();
+        let data = b"0123456789abcdef";
+        fs::write(fx.path("f"), data).unwrap();
+        let hash = HashAlgorithm::default();
+
+        let complete = download(&fx, "f", 10, true);
+        assert_eq!(complete.bytes, 6);
+        assert_eq!(complete.checksum, hash.digest(&data[10..]).to_string());
+        assert_eq!(complete.file_checksum, Some(hash.digest(data).to_string()));
+
+        assert_eq!(download(&fx, "f", 10, false).file_checksum, None);
+    }
+}
@@ -1,29 +1,553 @@
 use core::marker::Send;
 use core::ops::FnOnce;
-use std::sync::{mpsc, Arc, Mutex};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker as Deque};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cell::Cell;
+use std::fmt;
+use std::iter;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// Work-stealing pool. Jobs are pushed to a global lock-free queue, each
+/// worker takes them in batches into its own deque and steals from the
+/// other workers' deques when both run dry.
+///
+/// The number of workers moves between a minimum and a maximum: workers are
+/// spawned when jobs are submitted while none is idle, and exit after
+/// `PoolOptions::idle_timeout` without work.
+///
+/// A panicking job never unwinds its worker: the panic is caught around the
+/// job, passed to the panic hook and the worker goes on with the next job.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
+    shared: Arc<Shared>,
+    /// Set by the first `shutdown`, returned again by later calls.
+    report: Option<ShutdownReport>,
 }
 
 trait FnBox {
-    fn call_box(self: Box<Self>);
+    /// Run the job, or only resolve its handle if it was cancelled.
+    fn call_box(self: Box<Self>, cancelled: bool);
 }
 
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<F>) {
-        (*self)()
+impl<F: FnOnce(bool)> FnBox for F {
+    fn call_box(self: Box<F>, cancelled: bool) {
+        (*self)(cancelled)
     }
 }
 
-pub enum Message {
-    NewJob(Job),
-    Terminate,
+/// A job's function, `'static` unless it was boxed for a `Scope`.
+type Task<'a> = Box<dyn FnBox + Send + 'a>;
+
+struct Job {
+    id: JobId,
+    task: Task<'static>,
+    submitted: Instant,
+}
+
+/// Identifies a job in a `ShutdownReport`.
+pub type JobId = u64;
+
+/// `Shared::running` value of a worker without a job.
+const IDLE: JobId = JobId::MAX;
+
+/// Times an idle worker yields before it parks.
+const IDLE_SPINS: u32 = 16;
+
+/// Queue a job is submitted to. Workers take `High` jobs before any other
+/// queued job, then `Normal` ones before `Low` ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// Control work, such as handshakes and metadata requests.
+    High,
+    #[default]
+    Normal,
+    /// Bulk data copies.
+    Low,
+}
+
+/// Settings of a `ThreadPool` besides its size.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolOptions {
+    /// Most jobs waiting to run, `None` for no limit. Submitting to a full
+    /// pool blocks, fails or times out, depending on the method used.
+    pub capacity: Option<usize>,
+    /// Workers kept alive when idle, `None` for the pool size.
+    pub min_workers: Option<usize>,
+    /// How long a worker above the minimum waits for work before it
+    /// exits, `None` to keep it until `resize`.
+    pub idle_timeout: Option<Duration>,
+}
+
+/// A job rejected because the queue stayed full, with its function.
+pub struct QueueFull<F>(pub F);
+
+impl<F> fmt::Debug for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("QueueFull(..)")
+    }
+}
+
+impl<F> fmt::Display for QueueFull<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job queue is full")
+    }
+}
+
+impl<F> std::error::Error for QueueFull<F> {}
+
+/// What happens to queued jobs on `ThreadPool::shutdown`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Run every queued job, then stop.
+    Drain,
+    /// Finish running jobs, resolve queued ones as `JobError::Cancelled`.
+    Cancel,
+}
+
+/// Outcome of the jobs that were queued or running when `shutdown` began.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub finished: Vec<JobId>,
+    pub cancelled: Vec<JobId>,
+    /// Still queued or running at the deadline, their workers were detached.
+    pub timed_out: Vec<JobId>,
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} finished, {} cancelled, {} timed out",
+            self.finished.len(),
+            self.cancelled.len(),
+            self.timed_out.len()
+        )
+    }
+}
+
+/// A job's panic caught by a worker, passed to the pool's panic hook.
+#[derive(Debug, Clone)]
+pub struct PanicReport {
+    pub worker_id: usize,
+    pub message: String,
+}
+
+type PanicHook = Box<dyn Fn(&PanicReport) + Send + Sync + 'static>;
+
+/// Buckets of a `Histogram`, the last one ends at about 36 minutes.
+const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Durations counted in power of two microsecond buckets.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// `buckets[i]` counts durations below `2^i` µs, the last one also
+    /// counts everything longer.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_us: u64,
+    pub max_us: u64,
+}
+
+impl Histogram {
+    pub fn mean_us(&self) -> u64 {
+        self.sum_us.checked_div(self.count).unwrap_or(0)
+    }
+
+    /// Upper bound of the bucket holding the `quantile` (0.0 to 1.0).
+    pub fn percentile_us(&self, quantile: f64) -> u64 {
+        let rank = (self.count as f64 * quantile).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return (1u64 << i).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "n={} mean={}us p50<={}us p99<={}us max={}us",
+            self.count,
+            self.mean_us(),
+            self.percentile_us(0.5),
+            self.percentile_us(0.99),
+            self.max_us
+        )
+    }
+}
+
+/// Lock free `Histogram` the workers record into.
+struct Recorder {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+impl Recorder {
+    fn new() -> Recorder {
+        Recorder {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, duration: Duration) {
+        let us = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        // Bucket i holds [2^(i-1), 2^i).
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            count: self.count.load(Ordering::Relaxed),
+            sum_us: self.sum_us.load(Ordering::Relaxed),
+            max_us: self.max_us.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of a `ThreadPool`'s load, from `ThreadPool::stats`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub workers: usize,
+    pub idle_workers: usize,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// Jobs submitted but not started.
+    pub queued: usize,
+    pub running: usize,
+    /// Jobs that returned without a panic.
+    pub completed: u64,
+    pub panicked: u64,
+    pub cancelled: u64,
+    /// From submission until a worker started the job.
+    pub wait: Histogram,
+    /// Time the job ran, cancelled jobs excluded.
+    pub run: Histogram,
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "workers: {} ({} idle, {}..={})",
+            self.workers, self.idle_workers, self.min_workers, self.max_workers
+        )?;
+        writeln!(
+            f,
+            "   jobs: {} queued, {} running",
+            self.queued, self.running
+        )?;
+        writeln!(
+            f,
+            "   done: {} completed, {} panicked, {} cancelled",
+            self.completed, self.panicked, self.cancelled
+        )?;
+        writeln!(f, "   wait: {}", self.wait)?;
+        write!(f, "    run: {}", self.run)
+    }
+}
+
+/// Counters behind `PoolStats`.
+struct Metrics {
+    running: AtomicUsize,
+    completed: AtomicU64,
+    panicked: AtomicU64,
+    cancelled: AtomicU64,
+    wait: Recorder,
+    run: Recorder,
+}
+
+/// Shutdown bookkeeping, guarded by `Shared::outcome`.
+#[derive(Default)]
+struct Outcome {
+    finished: Vec<JobId>,
+    cancelled: Vec<JobId>,
+}
+
+/// State shared by the pool and its worker threads.
+struct Shared {
+    /// Global queues, by `Priority`.
+    injectors: [Injector<Job>; 3],
+    workers: RwLock<Vec<Arc<Worker>>>,
+    next_worker_id: AtomicUsize,
+    min_workers: AtomicUsize,
+    max_workers: AtomicUsize,
+    idle_timeout: Option<Duration>,
+    /// Workers started and not exited, changed under `outcome`'s lock
+    /// when they exit.
+    live: AtomicUsize,
+    /// Workers looking for a job.
+    idle: AtomicUsize,
+    /// `PoolOptions::capacity`, `usize::MAX` if unbounded.
+    capacity: usize,
+    /// Jobs submitted but not taken by a worker yet.
+    queued: AtomicUsize,
+    /// Callers blocked on a full queue wait on `space`, `submitters`
+    /// counts them.
+    submitters: AtomicUsize,
+    submit: Mutex<()>,
+    space: Condvar,
+    panic_hook: RwLock<Option<PanicHook>>,
+    metrics: Metrics,
+    next_id: AtomicU64,
+    /// Set by `shutdown`: workers exit once they find no more jobs.
+    stopping: AtomicBool,
+    /// Set by `shutdown`: queued jobs are cancelled instead of run.
+    cancel: AtomicBool,
+    /// Set by `shutdown`: job outcomes are recorded in `outcome`.
+    recording: AtomicBool,
+    /// Idle workers wait on `wake`, `sleepers` counts them.
+    sleepers: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    outcome: Mutex<Outcome>,
+    /// Notified whenever a job is recorded or a worker exits.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn outcome(&self) -> MutexGuard<'_, Outcome> {
+        self.outcome.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn injector(&self, priority: Priority) -> &Injector<Job> {
+        &self.injectors[priority as usize]
+    }
+
+    fn workers(&self) -> Vec<Arc<Worker>> {
+        self.workers
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Steal a job from any other worker's deque.
+    fn steal_any(&self) -> Steal<Job> {
+        let workers = self.workers.read().unwrap_or_else(PoisonError::into_inner);
+        workers
+            .iter()
+            .map(|worker| worker.stealer.steal())
+            .collect()
+    }
+
+    /// Count a worker out if more than `floor` are left.
+    fn retire(&self, floor: usize) -> bool {
+        if self.live.load(Ordering::SeqCst) <= floor {
+            return false;
+        }
+        let _outcome = self.outcome();
+        let retired = self
+            .live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live > floor).then(|| live - 1)
+            })
+            .is_ok();
+        if retired {
+            self.changed.notify_all();
+        }
+        retired
+    }
+
+    /// Next job for a worker: a `High` job, its own deque, a batch from the
+    /// `Normal` queue, a job stolen from another worker, and last a `Low`
+    /// job. Only `Normal` jobs are taken in batches, so the deques never
+    /// hold a `High` or `Low` job that others would wait behind or overtake.
+    fn find_job(&self, local: &Deque<Job>) -> Option<Job> {
+        let job = steal(|| self.injector(Priority::High).steal())
+            .or_else(|| local.pop())
+            .or_else(|| steal(|| self.injector(Priority::Normal).steal_batch_and_pop(local)))
+            .or_else(|| steal(|| self.steal_any()))
+            .or_else(|| steal(|| self.injector(Priority::Low).steal()))?;
+        self.release(1);
+        Some(job)
+    }
+
+    /// Take every queued job off the global queues and the worker deques.
+    fn drain_jobs(&self) -> Vec<Job> {
+        let mut jobs = Vec::new();
+        let mut drain = |steal: &dyn Fn() -> Steal<Job>| loop {
+            match steal() {
+                Steal::Success(job) => jobs.push(job),
+                Steal::Retry => (),
+                Steal::Empty => break,
+            }
+        };
+        for injector in self.injectors.iter() {
+            drain(&|| injector.steal());
+        }
+        for worker in self.workers() {
+            drain(&|| worker.stealer.steal());
+        }
+        self.release(jobs.len());
+        jobs
+    }
+
+    /// Take a queue slot. If `block`, wait for one until `deadline`, or
+    /// for good if there is none.
+    fn reserve(&self, block: bool, deadline: Option<Instant>) -> bool {
+        let try_reserve = || {
+            self.queued
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                    (queued < self.capacity).then_some(queued + 1)
+                })
+                .is_ok()
+        };
+        loop {
+            if try_reserve() {
+                return true;
+            }
+            if !block {
+                return false;
+            }
+            let guard = self.submit.lock().unwrap_or_else(PoisonError::into_inner);
+            self.submitters.fetch_add(1, Ordering::SeqCst);
+            // Same handshake as `sleep_until_work`, against `release`.
+            let mut timed_out = false;
+            if self.queued.load(Ordering::SeqCst) >= self.capacity {
+                match deadline {
+                    None => drop(
+                        self.space
+                            .wait(guard)
+                            .unwrap_or_else(PoisonError::into_inner),
+                    ),
+                    Some(deadline) => {
+                        let now = Instant::now();
+                        timed_out = now >= deadline;
+                        if !timed_out {
+                            drop(
+                                self.space
+                                    .wait_timeout(guard, deadline - now)
+                                    .unwrap_or_else(PoisonError::into_inner),
+                            );
+                        }
+                    }
+                }
+            }
+            self.submitters.fetch_sub(1, Ordering::SeqCst);
+            if timed_out {
+                return try_reserve();
+            }
+        }
+    }
+
+    /// Free `count` queue slots taken by `reserve`.
+    fn release(&self, count: usize) {
+        if count == 0 {
+            return;
+        }
+        self.queued.fetch_sub(count, Ordering::SeqCst);
+        if self.submitters.load(Ordering::SeqCst) > 0 {
+            let _guard = self.submit.lock().unwrap_or_else(PoisonError::into_inner);
+            self.space.notify_all();
+        }
+    }
+
+    /// Block until a job may be available or the pool is stopping.
+    ///
+    /// # Returns
+    /// `false` if the idle timeout passed without a wakeup.
+    fn sleep_until_work(&self) -> bool {
+        let guard = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        self.sleepers.fetch_add(1, Ordering::SeqCst);
+        let mut woken = true;
+        // Checked after announcing the sleeper, so a concurrent `wake_one`
+        // either sees it or its job is seen here.
+        if self.injectors.iter().all(Injector::is_empty) && !self.stopping.load(Ordering::SeqCst) {
+            match self.idle_timeout {
+                None => drop(
+                    self.wake
+                        .wait(guard)
+                        .unwrap_or_else(PoisonError::into_inner),
+                ),
+                Some(timeout) => {
+                    let (guard, result) = self
+                        .wake
+                        .wait_timeout(guard, timeout)
+                        .unwrap_or_else(PoisonError::into_inner);
+                    drop(guard);
+                    woken = !result.timed_out();
+                }
+            }
+        }
+        self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        woken
+    }
+
+    fn wake_one(&self) {
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            let _guard = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+            self.wake.notify_one();
+        }
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.sleep.lock().unwrap_or_else(PoisonError::into_inner);
+        self.wake.notify_all();
+    }
+
+    fn stats(&self) -> PoolStats {
+        let metrics = &self.metrics;
+        PoolStats {
+            workers: self.live.load(Ordering::SeqCst),
+            idle_workers: self.idle.load(Ordering::SeqCst),
+            min_workers: self.min_workers.load(Ordering::SeqCst),
+            max_workers: self.max_workers.load(Ordering::SeqCst),
+            queued: self.queued.load(Ordering::SeqCst),
+            running: metrics.running.load(Ordering::SeqCst),
+            completed: metrics.completed.load(Ordering::Relaxed),
+            panicked: metrics.panicked.load(Ordering::Relaxed),
+            cancelled: metrics.cancelled.load(Ordering::Relaxed),
+            wait: metrics.wait.snapshot(),
+            run: metrics.run.snapshot(),
+        }
+    }
+
+    /// Pass a panic to the hook, or print it if there is none.
+    /// A panicking hook falls back to printing as well.
+    fn report(&self, report: PanicReport) {
+        let hook = self
+            .panic_hook
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let reported = match hook.as_ref() {
+            Some(hook) => panic::catch_unwind(AssertUnwindSafe(|| hook(&report))).is_ok(),
+            None => false,
+        };
+        if !reported {
+            eprintln!("Worker {} panicked: {}", report.worker_id, report.message);
+        }
+    }
 }
 
-type Job = Box<dyn FnBox + Send + 'static>;
+/// Retry `attempt` until it does not ask for a retry.
+fn steal(attempt: impl FnMut() -> Steal<Job>) -> Option<Job> {
+    iter::repeat_with(attempt)
+        .find(|steal| !steal.is_retry())
+        .and_then(Steal::success)
+}
 
 impl ThreadPool {
     /// Create a new ThreadPool.
@@ -36,99 +560,1392 @@ impl ThreadPool {
     ///
     /// The `new` function will panic if the size is zero.
     pub fn new(size: usize) -> ThreadPool {
+        ThreadPool::with_options(size, PoolOptions::default())
+    }
+
+    /// Create a new ThreadPool with the given options.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - The most threads in the pool.
+    /// * `options` - Queue capacity, scaling and other settings.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size or the capacity is zero, or if `min_workers`
+    /// is larger than the size.
+    pub fn with_options(size: usize, options: PoolOptions) -> ThreadPool {
         assert!(size > 0);
+        assert!(options.capacity != Some(0), "capacity must not be zero");
+        let min_workers = options.min_workers.unwrap_or(size);
+        assert!(min_workers <= size, "min_workers must not exceed the size");
 
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(size);
+        let shared = Arc::new(Shared {
+            injectors: [Injector::new(), Injector::new(), Injector::new()],
+            workers: RwLock::new(Vec::with_capacity(size)),
+            next_worker_id: AtomicUsize::new(0),
+            min_workers: AtomicUsize::new(min_workers),
+            max_workers: AtomicUsize::new(size),
+            idle_timeout: options.idle_timeout,
+            live: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            capacity: options.capacity.unwrap_or(usize::MAX),
+            queued: AtomicUsize::new(0),
+            submitters: AtomicUsize::new(0),
+            submit: Mutex::new(()),
+            space: Condvar::new(),
+            panic_hook: RwLock::new(None),
+            metrics: Metrics {
+                running: AtomicUsize::new(0),
+                completed: AtomicU64::new(0),
+                panicked: AtomicU64::new(0),
+                cancelled: AtomicU64::new(0),
+                wait: Recorder::new(),
+                run: Recorder::new(),
+            },
+            next_id: AtomicU64::new(0),
+            stopping: AtomicBool::new(false),
+            cancel: AtomicBool::new(false),
+            recording: AtomicBool::new(false),
+            sleepers: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            outcome: Mutex::new(Outcome::default()),
+            changed: Condvar::new(),
+        });
+        ResizeHandle {
+            shared: Arc::clone(&shared),
+        }
+        .resize(min_workers, size);
 
-        for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+        ThreadPool {
+            shared,
+            report: None,
         }
+    }
 
-        ThreadPool { workers, sender }
+    /// Execute a function on the thread pool, at `Priority::Normal`.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The function to execute.
+    ///
+    /// # Returns
+    /// `JobHandle` for the return value of `f`. Dropping it detaches the job.
+    /// After `shutdown` the job is not run and the handle resolves to
+    /// `JobError::Cancelled`.
+    ///
+    /// Blocks while the queue is full, see `execute_with`.
+    pub fn execute<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.execute_with(Priority::Normal, f)
     }
 
-    /// Execute a function on the thread pool.
+    /// Execute a function on the thread pool, waiting for room in the
+    /// queue if it is full.
+    ///
+    /// A job that submits to its own full pool this way can block every
+    /// worker for good, jobs should use `try_execute` instead.
     ///
     /// # Arguments
     ///
+    /// * `priority` - Queue to submit to.
     /// * `f` - The function to execute.
     ///
-    pub fn execute<F>(&self, f: F)
+    /// # Returns
+    /// `JobHandle` for the return value of `f`, as for `execute`.
+    pub fn execute_with<F, T>(&self, priority: Priority, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        match self.submit(priority, f, true, None) {
+            Ok(handle) => handle,
+            Err(_) => unreachable!("blocking submit without deadline"),
+        }
+    }
+
+    /// Execute a function on the thread pool if the queue has room.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - Queue to submit to.
+    /// * `f` - The function to execute.
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueFull` with `f` if the queue is full.
+    ///
+    pub fn try_execute<F, T>(&self, priority: Priority, f: F) -> Result<JobHandle<T>, QueueFull<F>>
     where
-        F: FnOnce() + Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
     {
-        let job = Box::new(f);
+        self.submit(priority, f, false, None)
+    }
 
-        self.sender.send(Message::NewJob(job)).unwrap();
+    /// Execute a function on the thread pool, waiting up to `timeout` for
+    /// room in the queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `priority` - Queue to submit to.
+    /// * `f` - The function to execute.
+    /// * `timeout` - How long to wait for a free slot.
+    ///
+    /// # Errors
+    ///
+    /// Returns `QueueFull` with `f` if the queue stayed full.
+    ///
+    pub fn execute_timeout<F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+        timeout: Duration,
+    ) -> Result<JobHandle<T>, QueueFull<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit(priority, f, true, Some(Instant::now() + timeout))
     }
 
-    /// WIP :
-    /// Wait for all threads to finish.
+    fn submit<F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+        block: bool,
+        deadline: Option<Instant>,
+    ) -> Result<JobHandle<T>, QueueFull<F>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.submit_task(priority, f, block, deadline, |task| task)
+    }
+
+    /// Queue `f`, its boxed task passed through `seal` first. `seal` is
+    /// only called, and only dropped after, once the job was queued.
+    fn submit_task<'a, F, T>(
+        &self,
+        priority: Priority,
+        f: F,
+        block: bool,
+        deadline: Option<Instant>,
+        seal: impl FnOnce(Task<'a>) -> Task<'static>,
+    ) -> Result<JobHandle<T>, QueueFull<F>>
+    where
+        F: FnOnce() -> T + Send + 'a,
+        T: Send + 'a,
+    {
+        let (result_tx, result_rx) = mpsc::channel();
+        if self.report.is_some() {
+            let _ = result_tx.send(Err(JobError::Cancelled));
+            return Ok(JobHandle {
+                id: self.shared.next_id.fetch_add(1, Ordering::SeqCst),
+                receiver: result_rx,
+                taken: Cell::new(false),
+            });
+        }
+        if !self.shared.reserve(block, deadline) {
+            return Err(QueueFull(f));
+        }
+
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let task: Task<'a> = Box::new(move |cancelled: bool| {
+            if cancelled {
+                let _ = result_tx.send(Err(JobError::Cancelled));
+                return;
+            }
+            match panic::catch_unwind(AssertUnwindSafe(f)) {
+                // The receiver is gone if the handle was dropped.
+                Ok(value) => {
+                    let _ = result_tx.send(Ok(value));
+                }
+                Err(payload) => {
+                    let _ = result_tx.send(Err(JobError::Panicked(panic_message(&*payload))));
+                    // Let the worker report it to the panic hook.
+                    panic::resume_unwind(payload);
+                }
+            }
+        });
+
+        self.shared.injector(priority).push(Job {
+            id,
+            task: seal(task),
+            submitted: Instant::now(),
+        });
+        self.shared.wake_one();
+        // More jobs are waiting than idle workers can take.
+        if self.shared.queued.load(Ordering::SeqCst) > self.shared.idle.load(Ordering::SeqCst) {
+            Worker::grow(&self.shared);
+        }
+        Ok(JobHandle {
+            id,
+            receiver: result_rx,
+            taken: Cell::new(false),
+        })
+    }
+
+    /// Run `f` with a `Scope` whose jobs may borrow from the caller's
+    /// stack, like `std::thread::scope` but on the pool's workers. Returns
+    /// once `f` and every job it submitted have finished.
+    ///
+    /// Calling it from a job of the same pool can deadlock when every
+    /// worker waits for a scope.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - Gets the `Scope` to submit jobs with.
+    ///
+    /// # Returns
+    /// The return value of `f`. If `f` panics, the panic is resumed after
+    /// the jobs have finished.
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: self,
+            pending: Arc::new((Mutex::new(0), Condvar::new())),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        scope.wait();
+        match result {
+            Ok(value) => value,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+
+    /// Jobs submitted but not started yet.
+    pub fn queued(&self) -> usize {
+        self.shared.queued.load(Ordering::SeqCst)
+    }
+
+    /// `PoolOptions::capacity` the pool was created with.
+    pub fn capacity(&self) -> Option<usize> {
+        (self.shared.capacity != usize::MAX).then_some(self.shared.capacity)
+    }
+
+    /// Set the function called with every panic caught by a worker,
+    /// instead of printing it.
+    ///
+    /// # Arguments
+    ///
+    /// * `hook` - Called on the worker thread that caught the panic.
+    ///
+    pub fn set_panic_hook<F>(&self, hook: F)
+    where
+        F: Fn(&PanicReport) + Send + Sync + 'static,
+    {
+        *self
+            .shared
+            .panic_hook
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+    }
+
+    /// Number of running workers.
+    pub fn size(&self) -> usize {
+        self.shared.live.load(Ordering::SeqCst)
+    }
+
+    /// Current `(min, max)` worker bounds.
+    pub fn bounds(&self) -> (usize, usize) {
+        (
+            self.shared.min_workers.load(Ordering::SeqCst),
+            self.shared.max_workers.load(Ordering::SeqCst),
+        )
+    }
+
+    /// Change the worker bounds, see `ResizeHandle::resize`.
+    pub fn resize(&self, min: usize, max: usize) {
+        self.resize_handle().resize(min, max);
+    }
+
+    /// Snapshot of the pool's load and job latencies.
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+
+    /// Handle to read `stats` from another thread.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Handle to resize the pool from another thread.
+    pub fn resize_handle(&self) -> ResizeHandle {
+        ResizeHandle {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Wait for all queued jobs to finish, then stop the workers.
     pub fn join(&mut self) {
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        self.shutdown(ShutdownMode::Drain, None);
+    }
+
+    /// Stop the workers, draining or cancelling the queued jobs.
+    ///
+    /// Only the first call does the work, later calls return the same report.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - Whether queued jobs are run or cancelled.
+    /// * `timeout` - How long to wait for the workers, `None` waits forever.
+    ///   Workers still busy at the deadline are detached.
+    ///
+    /// # Returns
+    /// `ShutdownReport` of every job queued or running when it was called.
+    pub fn shutdown(&mut self, mode: ShutdownMode, timeout: Option<Duration>) -> ShutdownReport {
+        if let Some(report) = &self.report {
+            return report.clone();
         }
-        for worker in &mut self.workers {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let shared = &self.shared;
+        shared.recording.store(true, Ordering::SeqCst);
+        shared
+            .cancel
+            .store(mode == ShutdownMode::Cancel, Ordering::SeqCst);
+        // Workers keep taking jobs until every queue is empty, then exit.
+        shared.stopping.store(true, Ordering::SeqCst);
+        shared.wake_all();
+
+        let mut outcome = shared.outcome();
+        while shared.live.load(Ordering::SeqCst) > 0 {
+            outcome = match deadline {
+                None => shared
+                    .changed
+                    .wait(outcome)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    shared
+                        .changed
+                        .wait_timeout(outcome, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+        let all_exited = shared.live.load(Ordering::SeqCst) == 0;
+        let mut report = ShutdownReport {
+            finished: std::mem::take(&mut outcome.finished),
+            cancelled: std::mem::take(&mut outcome.cancelled),
+            timed_out: Vec::new(),
+        };
+        shared.recording.store(false, Ordering::SeqCst);
+        drop(outcome);
+        if all_exited {
+            // Workers exit once they find no job, left over jobs can only
+            // be there if no worker was running to take them.
+            for job in shared.drain_jobs() {
+                shared.metrics.cancelled.fetch_add(1, Ordering::Relaxed);
+                report.cancelled.push(job.id);
+                job.task.call_box(true);
+            }
+        } else {
+            shared.cancel.store(true, Ordering::SeqCst);
+            for job in shared.drain_jobs() {
+                shared.metrics.cancelled.fetch_add(1, Ordering::Relaxed);
+                report.timed_out.push(job.id);
+                job.task.call_box(true);
+            }
+            for worker in shared.workers() {
+                match worker.running.load(Ordering::SeqCst) {
+                    IDLE => (),
+                    id => report.timed_out.push(id),
+                }
+            }
+            report.timed_out.sort_unstable();
+        }
+
+        for worker in shared.workers() {
             println!("Shutting down worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            if all_exited {
+                worker.join();
+            } else {
+                worker.detach();
             }
         }
+        self.report = Some(report.clone());
+        report
     }
 }
 
-impl Drop for ThreadPool {
+/// Jobs of a `Scope` that have not finished, and the condvar notified
+/// when that count drops to zero.
+type Pending = Arc<(Mutex<usize>, Condvar)>;
+
+/// Submits jobs that borrow from the stack of a `ThreadPool::scope` call.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    pending: Pending,
+    /// Invariant lifetimes, as in `std::thread::Scope`.
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Counts a scoped job as finished when dropped, whether it ran,
+/// panicked or was never queued.
+struct ScopeGuard(Pending);
+
+impl Drop for ScopeGuard {
     fn drop(&mut self) {
-        // println!("Sending terminate message to all workers.");
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
+        let (count, done) = &*self.0;
+        let mut count = count.lock().unwrap_or_else(PoisonError::into_inner);
+        *count -= 1;
+        if *count == 0 {
+            done.notify_all();
         }
-        println!("Waiting for all workers to shut down...");
-        for worker in &mut self.workers {
-            println!("Shutting down worker {}", worker.id);
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Execute a borrowing function on the pool, at `Priority::Normal`.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - The function to execute.
+    ///
+    /// # Returns
+    /// `JobHandle` for the return value of `f`, as for `ThreadPool::execute`.
+    pub fn execute<F, T>(&'scope self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        self.execute_with(Priority::Normal, f)
+    }
+
+    /// Like `execute`, on the queue of `priority`. Blocks while the queue
+    /// is full.
+    pub fn execute_with<F, T>(&'scope self, priority: Priority, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        *self
+            .pending
+            .0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) += 1;
+        let guard = ScopeGuard(Arc::clone(&self.pending));
+        let seal = move |task: Task<'scope>| {
+            // The guard is dropped after the task and everything it
+            // captured, the result sender included.
+            let task: Task<'scope> = Box::new(move |cancelled: bool| {
+                task.call_box(cancelled);
+                drop(guard);
+            });
+            // SAFETY: `ThreadPool::scope` does not return before every
+            // guard was dropped, so nothing borrowed for 'scope is used
+            // after it ends. The pool cannot be shut down meanwhile, it is
+            // borrowed for 'env.
+            unsafe { std::mem::transmute::<Task<'scope>, Task<'static>>(task) }
+        };
+        match self.pool.submit_task(priority, f, true, None, seal) {
+            Ok(handle) => handle,
+            Err(_) => unreachable!("blocking submit without deadline"),
+        }
+    }
+
+    /// Block until every job of the scope has finished.
+    fn wait(&self) {
+        let (count, done) = &*self.pending;
+        let mut count = count.lock().unwrap_or_else(PoisonError::into_inner);
+        while *count > 0 {
+            count = done.wait(count).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+/// Reads the `PoolStats` of a `ThreadPool` from any thread.
+#[derive(Clone)]
+pub struct StatsHandle {
+    shared: Arc<Shared>,
+}
+
+impl StatsHandle {
+    pub fn stats(&self) -> PoolStats {
+        self.shared.stats()
+    }
+}
+
+/// Changes the worker bounds of a `ThreadPool` from any thread.
+#[derive(Clone)]
+pub struct ResizeHandle {
+    shared: Arc<Shared>,
+}
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+impl ResizeHandle {
+    /// Set the worker bounds. Workers are started right away up to `min`,
+    /// workers above `max` exit once they ran the jobs they already took.
+    ///
+    /// # Arguments
+    ///
+    /// * `min` - Workers kept alive when idle.
+    /// * `max` - Most workers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or smaller than `min`.
+    pub fn resize(&self, min: usize, max: usize) {
+        assert!(
+            max > 0 && min <= max,
+            "invalid pool bounds {}..={}",
+            min,
+            max
+        );
+        let shared = &self.shared;
+        shared.max_workers.store(max, Ordering::SeqCst);
+        shared.min_workers.store(min, Ordering::SeqCst);
+        while shared.live.load(Ordering::SeqCst) < min && Worker::grow(shared) {}
+        // Let surplus idle workers notice.
+        shared.wake_all();
+    }
+}
+
+/// Why a job has no result.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobError {
+    /// The job panicked, with the panic message.
+    Panicked(String),
+    /// The job was cancelled by `ThreadPool::shutdown` before it ran.
+    Cancelled,
+    /// The job was dropped before it finished, or its result was already taken.
+    Lost,
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::Panicked(message) => write!(f, "job panicked: {}", message),
+            JobError::Cancelled => write!(f, "job cancelled"),
+            JobError::Lost => write!(f, "job result lost"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".to_string()
+    }
+}
+
+/// Result of a job submitted with `ThreadPool::execute`.
+///
+/// The result can be taken once. After that, every call returns `JobError::Lost`.
+pub struct JobHandle<T> {
+    id: JobId,
+    receiver: mpsc::Receiver<Result<T, JobError>>,
+    /// Set once the result was returned, the job may still hold its sender.
+    taken: Cell<bool>,
+}
+
+impl<T> JobHandle<T> {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Wait for the job to finish.
+    ///
+    /// # Errors
+    ///
+    /// Returns `JobError::Panicked` if the job panicked, `JobError::Cancelled`
+    /// if it was cancelled and `JobError::Lost` if it never reported back.
+    ///
+    pub fn join(self) -> Result<T, JobError> {
+        if self.taken.get() {
+            return Err(JobError::Lost);
+        }
+        self.receiver.recv().unwrap_or(Err(JobError::Lost))
+    }
+
+    /// Take the result if the job has finished, without blocking.
+    ///
+    /// # Returns
+    /// `None` while the job is queued or running.
+    pub fn try_join(&self) -> Option<Result<T, JobError>> {
+        if self.taken.get() {
+            return Some(Err(JobError::Lost));
+        }
+        match self.receiver.try_recv() {
+            Ok(result) => {
+                self.taken.set(true);
+                Some(result)
+            }
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(JobError::Lost)),
+        }
+    }
+
+    /// Wait at most `timeout` for the job to finish.
+    ///
+    /// # Returns
+    /// `None` if the job is still queued or running, the handle stays valid.
+    pub fn join_timeout(&self, timeout: Duration) -> Option<Result<T, JobError>> {
+        if self.taken.get() {
+            return Some(Err(JobError::Lost));
+        }
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => {
+                self.taken.set(true);
+                Some(result)
             }
+            Err(mpsc::RecvTimeoutError::Timeout) => None,
+            Err(mpsc::RecvTimeoutError::Disconnected) => Some(Err(JobError::Lost)),
         }
     }
+
+    /// Like `join_timeout`, waiting until `deadline`.
+    pub fn join_deadline(&self, deadline: Instant) -> Option<Result<T, JobError>> {
+        self.join_timeout(deadline.saturating_duration_since(Instant::now()))
+    }
 }
 
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if self.report.is_none() {
+            println!("Waiting for all workers to shut down...");
+            self.join();
+        }
+    }
+}
+
+/// A worker as seen by the pool and the other workers.
 struct Worker {
     id: usize,
-    thread: Option<thread::JoinHandle<()>>,
+    stealer: Stealer<Job>,
+    /// Job the worker is running, `IDLE` if none.
+    running: AtomicU64,
+    /// Thread of the worker, taken when it is joined or detached.
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
 }
 
 impl Worker {
-    /// Create a new Worker.
+    /// Start one more worker, unless there are `max_workers` already or
+    /// the pool is stopping.
     ///
     /// # Arguments
     ///
-    /// * `id` - The id of the worker.
-    /// * `receiver` - The receiver end of the channel, message from ThreadPool.
+    /// * `shared` - State shared with the pool, including the job queues.
     ///
     /// # Returns
-    /// new Worker.
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let msg = receiver.lock().unwrap().recv().unwrap();
-
-            match msg {
-                Message::NewJob(job) => {
-                    // println!("Worker {} got a job; executing...", id);
-                    job.call_box();
+    /// `true` if a worker was started.
+    fn grow(shared: &Arc<Shared>) -> bool {
+        if shared.stopping.load(Ordering::SeqCst) {
+            return false;
+        }
+        let max = shared.max_workers.load(Ordering::SeqCst);
+        let started = shared
+            .live
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| {
+                (live < max).then_some(live + 1)
+            })
+            .is_ok();
+        if !started {
+            return false;
+        }
+        // Counted as idle until it takes a job, so that concurrent
+        // submissions do not start more workers for the same work.
+        shared.idle.fetch_add(1, Ordering::SeqCst);
+        let deque = Deque::new_fifo();
+        let worker = Arc::new(Worker {
+            id: shared.next_worker_id.fetch_add(1, Ordering::SeqCst),
+            stealer: deque.stealer(),
+            running: AtomicU64::new(IDLE),
+            thread: Mutex::new(None),
+        });
+        shared
+            .workers
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::clone(&worker));
+        Worker::spawn(worker, Arc::clone(shared), deque);
+        true
+    }
+
+    /// Start a thread for `worker` and store its handle.
+    fn spawn(worker: Arc<Worker>, shared: Arc<Shared>, deque: Deque<Job>) {
+        // Hold the slot until the handle is stored, a retiring thread
+        // detaches itself through it.
+        let mut current = worker.thread.lock().unwrap_or_else(PoisonError::into_inner);
+        let thread_worker = Arc::clone(&worker);
+        *current = Some(thread::spawn(move || {
+            Worker::run(&thread_worker, &shared, &deque);
+        }));
+    }
+
+    fn run(worker: &Arc<Worker>, shared: &Arc<Shared>, deque: &Deque<Job>) {
+        // Counted in `Shared::idle` by `grow`.
+        let mut idle = true;
+        let mut spins = 0;
+        let retired = loop {
+            // Only between batches, the jobs in the deque would be lost.
+            if deque.is_empty() && shared.retire(shared.max_workers.load(Ordering::SeqCst)) {
+                break true;
+            }
+            match shared.find_job(deque) {
+                Some(job) => {
+                    spins = 0;
+                    if idle {
+                        idle = false;
+                        shared.idle.fetch_sub(1, Ordering::SeqCst);
+                    }
+                    // Let an idle worker steal what this one took in excess.
+                    if !deque.is_empty() {
+                        shared.wake_one();
+                    }
+                    Worker::run_job(worker, shared, job);
+                    continue;
                 }
-                Message::Terminate => {
-                    // println!("Worker {} was told to terminate.", id);
-                    break;
+                None if !idle => {
+                    idle = true;
+                    shared.idle.fetch_add(1, Ordering::SeqCst);
                 }
+                None => (),
+            }
+            if shared.stopping.load(Ordering::SeqCst) {
+                // println!("Worker {} was told to terminate.", id);
+                break false;
+            }
+            // Yield a few times first, parking costs a wakeup per job.
+            if spins < IDLE_SPINS {
+                spins += 1;
+                thread::yield_now();
+                continue;
+            }
+            spins = 0;
+            if !shared.sleep_until_work()
+                && shared.retire(shared.min_workers.load(Ordering::SeqCst))
+            {
+                break true;
+            }
+        };
+        if idle {
+            shared.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+        if retired {
+            // Nobody joins a retired worker, its deque is empty.
+            shared
+                .workers
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .retain(|other| !Arc::ptr_eq(other, worker));
+            worker.detach();
+            // A job submitted while this worker still counted as idle did
+            // not start another one, which may have been the last.
+            if shared.queued.load(Ordering::SeqCst) > shared.idle.load(Ordering::SeqCst) {
+                Worker::grow(shared);
+            }
+        } else {
+            let _outcome = shared.outcome();
+            shared.live.fetch_sub(1, Ordering::SeqCst);
+            shared.changed.notify_all();
+        }
+    }
+
+    fn run_job(worker: &Worker, shared: &Shared, job: Job) {
+        let cancelled = shared.cancel.load(Ordering::SeqCst);
+        let metrics = &shared.metrics;
+        let started = Instant::now();
+        if !cancelled {
+            metrics
+                .wait
+                .record(started.saturating_duration_since(job.submitted));
+            metrics.running.fetch_add(1, Ordering::SeqCst);
+            worker.running.store(job.id, Ordering::SeqCst);
+        }
+        // println!("Worker {} got a job; executing...", id);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job.task.call_box(cancelled)));
+        if cancelled {
+            metrics.cancelled.fetch_add(1, Ordering::Relaxed);
+        } else {
+            worker.running.store(IDLE, Ordering::SeqCst);
+            metrics.running.fetch_sub(1, Ordering::SeqCst);
+            metrics.run.record(started.elapsed());
+            let counter = match result {
+                Ok(()) => &metrics.completed,
+                Err(_) => &metrics.panicked,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        if shared.recording.load(Ordering::SeqCst) {
+            let mut outcome = shared.outcome();
+            if cancelled {
+                outcome.cancelled.push(job.id);
+            } else {
+                outcome.finished.push(job.id);
             }
+            drop(outcome);
+            shared.changed.notify_all();
+        }
+        if let Err(payload) = result {
+            shared.report(PanicReport {
+                worker_id: worker.id,
+                message: panic_message(&*payload),
+            });
+        }
+    }
+
+    /// Wait for the worker's thread.
+    fn join(&self) {
+        let thread = self
+            .thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(thread) = thread {
+            let _ = thread.join();
+        }
+    }
+
+    /// Let a busy worker run on unattended.
+    fn detach(&self) {
+        self.thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn min_zero_pool_never_strands_a_job() {
+        let pool = ThreadPool::with_options(
+            2,
+            PoolOptions {
+                min_workers: Some(0),
+                idle_timeout: Some(Duration::from_millis(1)),
+                ..PoolOptions::default()
+            },
+        );
+        // Submit around the moment the last idle worker times out.
+        for i in 0..2000u64 {
+            thread::sleep(Duration::from_micros(800 + i % 400));
+            let handle = pool.execute(move || i);
+            assert_eq!(
+                handle
+                    .join_timeout(Duration::from_secs(5))
+                    .map(Result::unwrap),
+                Some(i),
+                "job {} stranded: {}",
+                i,
+                pool.stats()
+            );
+        }
+    }
+
+    /// Spin until `done` holds, failing after a few seconds.
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "condition not reached");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// A job that blocks its worker until `release` is called.
+    fn gate(pool: &ThreadPool, priority: Priority) -> (mpsc::Receiver<()>, impl FnOnce()) {
+        let (started_tx, started) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        pool.execute_with(priority, move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+        });
+        (started, move || drop(release_tx))
+    }
+
+    #[test]
+    fn join_returns_the_value_or_the_panic_message() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_hook(|_| ());
+        let value = pool.execute(|| vec![1, 2, 3]);
+        let io = pool.execute(|| std::fs::read("/nonexistent/xfs"));
+        let literal = pool.execute(|| -> u32 { panic!("static message") });
+        let formatted = pool.execute(|| -> u32 { panic!("formatted {}", 42) });
+        let opaque = pool.execute(|| -> u32 { panic::panic_any(7u8) });
+        assert_ne!(value.id(), io.id());
+
+        assert_eq!(value.join(), Ok(vec![1, 2, 3]));
+        // Errors of the job are part of its value.
+        let e = io.join().unwrap().unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(
+            literal.join(),
+            Err(JobError::Panicked("static message".to_string()))
+        );
+        assert_eq!(
+            formatted.join(),
+            Err(JobError::Panicked("formatted 42".to_string()))
+        );
+        assert_eq!(
+            opaque.join(),
+            Err(JobError::Panicked("unknown panic payload".to_string()))
+        );
+    }
+
+    #[test]
+    fn try_join_and_join_timeout_leave_a_running_job_alone() {
+        let pool = ThreadPool::new(1);
+        let (started, release) = gate(&pool, Priority::Normal);
+        started.recv().unwrap();
+        let handle = pool.execute(|| "done");
+
+        assert_eq!(handle.try_join(), None);
+        let waited = Instant::now();
+        assert_eq!(handle.join_timeout(Duration::from_millis(30)), None);
+        assert!(waited.elapsed() >= Duration::from_millis(30));
+        assert_eq!(handle.join_deadline(Instant::now()), None);
+
+        release();
+        assert_eq!(
+            handle.join_timeout(Duration::from_secs(5)),
+            Some(Ok("done"))
+        );
+        // The result was taken.
+        assert_eq!(handle.try_join(), Some(Err(JobError::Lost)));
+        assert_eq!(
+            handle.join_timeout(Duration::from_millis(1)),
+            Some(Err(JobError::Lost))
+        );
+        assert_eq!(handle.join(), Err(JobError::Lost));
+
+        let handle = pool.execute(|| 5);
+        wait_until(|| pool.stats().completed == 3);
+        assert_eq!(handle.try_join(), Some(Ok(5)));
+    }
+
+    #[test]
+    fn dropped_handle_detaches_the_job() {
+        let pool = ThreadPool::new(1);
+        let ran = Arc::new(AtomicBool::new(false));
+        let job_ran = Arc::clone(&ran);
+        drop(pool.execute(move || job_ran.store(true, Ordering::SeqCst)));
+        wait_until(|| ran.load(Ordering::SeqCst));
+    }
+
+    /// A busy worker with three jobs queued behind it, and the handles of
+    /// all four. `release` lets the first job finish.
+    fn busy_pool() -> (ThreadPool, impl FnOnce() + Send, Vec<JobHandle<u64>>) {
+        let pool = ThreadPool::new(1);
+        let (started_tx, started) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let mut handles = vec![pool.execute(move || {
+            started_tx.send(()).unwrap();
+            let _ = release_rx.recv();
+            0
+        })];
+        started.recv().unwrap();
+        handles.extend((1..4).map(|i| pool.execute(move || i)));
+        (pool, move || drop(release_tx), handles)
+    }
+
+    /// Run `release` a little after `shutdown` has started waiting.
+    fn release_later(release: impl FnOnce() + Send + 'static) {
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            release();
+        });
+    }
+
+    fn ids(handles: &[JobHandle<u64>]) -> Vec<JobId> {
+        handles.iter().map(JobHandle::id).collect()
+    }
+
+    #[test]
+    fn drain_shutdown_runs_queued_jobs() {
+        let (mut pool, release, handles) = busy_pool();
+        release_later(release);
+        let mut report = pool.shutdown(ShutdownMode::Drain, None);
+        report.finished.sort_unstable();
+        assert_eq!(report.finished, ids(&handles));
+        assert!(report.cancelled.is_empty() && report.timed_out.is_empty());
+        let results: Vec<_> = handles.into_iter().map(JobHandle::join).collect();
+        assert_eq!(results, [Ok(0), Ok(1), Ok(2), Ok(3)]);
+        assert_eq!(pool.size(), 0);
+    }
+
+    #[test]
+    fn cancel_shutdown_finishes_running_jobs_only() {
+        let (mut pool, release, handles) = busy_pool();
+        release_later(release);
+        let mut report = pool.shutdown(ShutdownMode::Cancel, None);
+        report.cancelled.sort_unstable();
+        assert_eq!(report.finished, ids(&handles[..1]));
+        assert_eq!(report.cancelled, ids(&handles[1..]));
+        assert!(report.timed_out.is_empty());
+        assert_eq!(report.to_string(), "1 finished, 3 cancelled, 0 timed out");
+        let results: Vec<_> = handles.into_iter().map(JobHandle::join).collect();
+        assert_eq!(
+            results,
+            [
+                Ok(0),
+                Err(JobError::Cancelled),
+                Err(JobError::Cancelled),
+                Err(JobError::Cancelled)
+            ]
+        );
+        assert_eq!(pool.stats().cancelled, 3);
+    }
+
+    #[test]
+    fn shutdown_deadline_detaches_busy_workers() {
+        let (mut pool, release, handles) = busy_pool();
+        let started = Instant::now();
+        let report = pool.shutdown(ShutdownMode::Drain, Some(Duration::from_millis(50)));
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "{:?}", elapsed);
+        assert!(report.finished.is_empty() && report.cancelled.is_empty());
+        assert_eq!(report.timed_out, ids(&handles));
+        for handle in &handles[1..] {
+            assert_eq!(handle.try_join(), Some(Err(JobError::Cancelled)));
+        }
+        // The detached job still runs to its end.
+        release();
+        assert_eq!(handles[0].join_timeout(Duration::from_secs(5)), Some(Ok(0)));
+    }
+
+    #[test]
+    fn shutdown_twice_returns_the_first_report() {
+        let (mut pool, release, handles) = busy_pool();
+        release_later(release);
+        let first = pool.shutdown(ShutdownMode::Cancel, None);
+        let second = pool.shutdown(ShutdownMode::Drain, Some(Duration::ZERO));
+        assert_eq!(first, second);
+        drop(handles);
+        // Jobs submitted after shutdown are not run.
+        assert_eq!(pool.execute(|| 1).join(), Err(JobError::Cancelled));
+        let late = pool.try_execute(Priority::High, || 1).unwrap();
+        assert_eq!(late.join(), Err(JobError::Cancelled));
+        pool.join();
+        drop(pool);
+    }
+
+    /// One busy worker and a queue of two that is full.
+    fn full_pool() -> (ThreadPool, impl FnOnce() + Send) {
+        let pool = ThreadPool::with_options(
+            1,
+            PoolOptions {
+                capacity: Some(2),
+                ..PoolOptions::default()
+            },
+        );
+        let (started, release) = gate(&pool, Priority::Normal);
+        started.recv().unwrap();
+        pool.try_execute(Priority::Normal, || 1).unwrap();
+        pool.try_execute(Priority::Low, || 2).unwrap();
+        (pool, release)
+    }
+
+    #[test]
+    fn try_execute_returns_the_job_when_the_queue_is_full() {
+        let (pool, release) = full_pool();
+        assert_eq!(pool.capacity(), Some(2));
+        assert_eq!(pool.queued(), 2);
+        // Priorities share the capacity.
+        match pool.try_execute(Priority::High, || 3) {
+            Err(QueueFull(job)) => assert_eq!(job(), 3),
+            Ok(_) => panic!("submitted to a full queue"),
+        }
+        assert_eq!(pool.queued(), 2);
+
+        release();
+        wait_until(|| pool.queued() == 0);
+        assert_eq!(
+            pool.try_execute(Priority::High, || 4).unwrap().join(),
+            Ok(4)
+        );
+    }
+
+    #[test]
+    fn execute_timeout_waits_for_a_free_slot() {
+        let (pool, release) = full_pool();
+        let started = Instant::now();
+        let rejected = pool.execute_timeout(Priority::Normal, || 3, Duration::from_millis(30));
+        assert!(rejected.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        release_later(release);
+        let accepted = pool.execute_timeout(Priority::Normal, || 4, Duration::from_secs(5));
+        assert_eq!(accepted.unwrap().join(), Ok(4));
+    }
+
+    #[test]
+    fn execute_blocks_until_the_queue_has_room() {
+        let (pool, release) = full_pool();
+        let submitted = AtomicBool::new(false);
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                let handle = pool.execute(|| 3);
+                submitted.store(true, Ordering::SeqCst);
+                assert_eq!(handle.join(), Ok(3));
+            });
+            thread::sleep(Duration::from_millis(30));
+            assert!(!submitted.load(Ordering::SeqCst));
+            release();
+        });
+        assert!(submitted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn recorder_buckets_by_powers_of_two() {
+        let recorder = Recorder::new();
+        for us in [0, 1, 3, 4, 1000, 1000] {
+            recorder.record(Duration::from_micros(us));
+        }
+        recorder.record(Duration::from_secs(u64::MAX));
+        let histogram = recorder.snapshot();
+        assert_eq!(histogram.count, 7);
+        assert_eq!(histogram.max_us, u64::MAX);
+        let mut expected = vec![0; HISTOGRAM_BUCKETS];
+        expected[0] = 1; // 0
+        expected[1] = 1; // 1
+        expected[2] = 1; // 3
+        expected[3] = 1; // 4
+        expected[10] = 2; // 1000 < 1024
+        expected[HISTOGRAM_BUCKETS - 1] = 1;
+        assert_eq!(histogram.buckets, expected);
+    }
+
+    #[test]
+    fn histogram_summaries() {
+        let mut histogram = Histogram {
+            buckets: vec![0; HISTOGRAM_BUCKETS],
+            ..Histogram::default()
+        };
+        assert_eq!(histogram.mean_us(), 0);
+        assert_eq!(histogram.percentile_us(0.5), 0);
+        // 90 durations of 100us, 10 of 5000us.
+        histogram.buckets[7] = 90;
+        histogram.buckets[13] = 10;
+        histogram.count = 100;
+        histogram.sum_us = 90 * 100 + 10 * 5000;
+        histogram.max_us = 5000;
+        assert_eq!(histogram.mean_us(), 590);
+        assert_eq!(histogram.percentile_us(0.5), 128);
+        assert_eq!(histogram.percentile_us(0.9), 128);
+        // Bounded by the largest duration seen.
+        assert_eq!(histogram.percentile_us(0.99), 5000);
+        assert_eq!(
+            histogram.to_string(),
+            "n=100 mean=590us p50<=128us p99<=5000us max=5000us"
+        );
+    }
+
+    #[test]
+    fn stats_count_jobs_and_their_latencies() {
+        let pool = ThreadPool::with_options(
+            2,
+            PoolOptions {
+                min_workers: Some(1),
+                ..PoolOptions::default()
+            },
+        );
+        pool.set_panic_hook(|_| ());
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.workers, stats.min_workers, stats.max_workers),
+            (1, 1, 2)
+        );
+        assert_eq!((stats.queued, stats.running, stats.completed), (0, 0, 0));
+
+        let (started, release) = gate(&pool, Priority::Normal);
+        started.recv().unwrap();
+        let (second, release_second) = gate(&pool, Priority::Normal);
+        second.recv().unwrap();
+        let queued: Vec<_> = (0..3).map(|i| pool.execute(move || i)).collect();
+        let stats = pool.stats_handle().stats();
+        assert_eq!(stats.workers, 2);
+        assert_eq!(stats.idle_workers, 0);
+        assert_eq!(stats.running, 2);
+        assert_eq!(stats.queued, 3);
+
+        thread::sleep(Duration::from_millis(10));
+        release();
+        release_second();
+        for handle in queued {
+            handle.join().unwrap();
+        }
+        let _ = pool.execute(|| panic!("counted")).join();
+        // Counted once the handles resolved.
+        wait_until(|| pool.stats().completed + pool.stats().panicked == 6);
+        let stats = pool.stats();
+        assert_eq!(stats.completed, 5);
+        assert_eq!(stats.panicked, 1);
+        assert_eq!(stats.cancelled, 0);
+        assert_eq!(stats.wait.count, 6);
+        assert_eq!(stats.run.count, 6);
+        // The gates ran for at least 10ms, the queued jobs waited as long.
+        assert!(stats.run.max_us >= 10_000, "{}", stats.run);
+        assert!(stats.wait.max_us >= 10_000, "{}", stats.wait);
+        assert!(stats
+            .to_string()
+            .contains("5 completed, 1 panicked, 0 cancelled"));
+    }
+
+    #[test]
+    fn queued_normal_jobs_run_before_queued_low_jobs() {
+        let pool = ThreadPool::new(1);
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let record = |name: &'static str| {
+            let order = Arc::clone(&order);
+            move || order.lock().unwrap().push(name)
+        };
+
+        // Low jobs queued while the only worker is busy.
+        let (started, release) = gate(&pool, Priority::High);
+        started.recv().unwrap();
+        let (low_started, release_low) = gate(&pool, Priority::Low);
+        for name in ["low 1", "low 2", "low 3"] {
+            pool.execute_with(Priority::Low, record(name));
+        }
+        release();
+        // The worker runs the first Low job, then Normal jobs arrive.
+        low_started.recv().unwrap();
+        for name in ["normal 1", "normal 2"] {
+            pool.execute_with(Priority::Normal, record(name));
+        }
+        let high = pool.execute_with(Priority::High, record("high"));
+        release_low();
+        let last = pool.execute_with(Priority::Low, record("low 4"));
+        last.join().unwrap();
+        high.join().unwrap();
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["high", "normal 1", "normal 2", "low 1", "low 2", "low 3", "low 4"]
+        );
+    }
+
+    #[test]
+    fn job_panics_reach_the_hook_and_keep_every_worker() {
+        let pool = ThreadPool::new(2);
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let hook_reports = Arc::clone(&reports);
+        pool.set_panic_hook(move |report| hook_reports.lock().unwrap().push(report.clone()));
+
+        let failed: Vec<_> = (0..6)
+            .map(|i| pool.execute(move || panic!("job {} failed", i)))
+            .collect();
+        for handle in failed {
+            assert!(matches!(handle.join(), Err(JobError::Panicked(_))));
+        }
+        // Every worker still takes jobs.
+        let results: Vec<_> = (0..8).map(|i| pool.execute(move || i)).collect();
+        let results: Vec<i32> = results.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(results, (0..8).collect::<Vec<_>>());
+
+        assert_eq!(pool.size(), 2);
+        // Counters and hook follow after the handle resolved.
+        wait_until(|| {
+            let stats = pool.stats();
+            stats.panicked == 6 && stats.completed == 8 && reports.lock().unwrap().len() == 6
         });
-        Worker {
-            id: id,
-            thread: Some(thread),
+        let reports = reports.lock().unwrap();
+        let mut messages: Vec<_> = reports.iter().map(|r| r.message.clone()).collect();
+        messages.sort();
+        assert_eq!(
+            messages,
+            (0..6)
+                .map(|i| format!("job {} failed", i))
+                .collect::<Vec<_>>()
+        );
+        assert!(reports.iter().all(|report| report.worker_id < 2));
+    }
+
+    #[test]
+    fn panicking_hook_does_not_kill_the_worker() {
+        let pool = ThreadPool::new(1);
+        pool.set_panic_hook(|_| panic!("hook failed"));
+        let failed = pool.execute(|| panic!("job failed"));
+        assert_eq!(
+            failed.join(),
+            Err(JobError::Panicked("job failed".to_string()))
+        );
+        assert_eq!(pool.execute(|| 1).join(), Ok(1));
+        assert_eq!(pool.size(), 1);
+    }
+
+    /// Scoped jobs that sleep long enough to still run if `scope` did not
+    /// wait, each counting itself in `done` when it finishes.
+    fn slow_jobs<'scope>(
+        scope: &'scope Scope<'scope, '_>,
+        done: &'scope AtomicUsize,
+        count: usize,
+    ) {
+        for _ in 0..count {
+            scope.execute(move || {
+                thread::sleep(Duration::from_millis(20));
+                done.fetch_add(1, Ordering::SeqCst);
+            });
         }
     }
+
+    #[test]
+    fn scope_joins_jobs_before_returning() {
+        let pool = ThreadPool::new(2);
+        let done = AtomicUsize::new(0);
+        let mut results = vec![0u64; 8];
+        pool.scope(|scope| {
+            slow_jobs(scope, &done, 8);
+            for (i, result) in results.iter_mut().enumerate() {
+                scope.execute(move || *result = i as u64 * 2);
+            }
+        });
+        assert_eq!(done.load(Ordering::SeqCst), 8);
+        assert_eq!(results, [0, 2, 4, 6, 8, 10, 12, 14]);
+    }
+
+    #[test]
+    fn scope_joins_jobs_when_a_job_panics() {
+        let pool = ThreadPool::new(2);
+        pool.set_panic_hook(|_| ());
+        let done = AtomicUsize::new(0);
+        let failed = pool.scope(|scope| {
+            let failed = scope.execute(|| panic!("scoped job failed"));
+            slow_jobs(scope, &done, 6);
+            failed
+        });
+        assert_eq!(done.load(Ordering::SeqCst), 6);
+        assert!(matches!(failed.join(), Err(JobError::Panicked(_))));
+        // The pool keeps working after the panic.
+        assert_eq!(pool.execute(|| 7).join().unwrap(), 7);
+    }
+
+    #[test]
+    fn scope_joins_jobs_when_the_closure_panics() {
+        let pool = ThreadPool::new(2);
+        let done = AtomicUsize::new(0);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                slow_jobs(scope, &done, 6);
+                panic!("scope closure failed");
+            })
+        }));
+        // Resumed only after the borrowing jobs finished.
+        assert!(result.is_err());
+        assert_eq!(done.load(Ordering::SeqCst), 6);
+    }
 }
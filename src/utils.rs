@@ -1,4 +1,7 @@
-use signal_hook::{consts::SIGINT, iterator::Signals};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT},
+    iterator::Signals,
+};
 use std::thread;
 
 pub fn register_sig_handler<F>(f: F)
@@ -14,3 +17,18 @@ where
         }
     });
 }
+
+/// Call `f` on every SIGHUP, the usual request to reload the configuration.
+pub fn register_reload_handler<F>(f: F)
+where
+    F: Send + 'static + Fn(),
+{
+    let mut signals =
+        Signals::new([SIGHUP]).expect("Failed to register signal handler: SIGHUP register failed");
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            println!("Received signal {:?}", sig);
+            f();
+        }
+    });
+}